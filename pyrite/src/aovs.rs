@@ -0,0 +1,399 @@
+use std::{
+    error::Error,
+    io::{self, Read, Write},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use cgmath::{MetricSpace, Point2, Point3};
+
+use crate::{
+    film::{read_bytes, read_string, write_string, AspectRatio, Film, FilmViews, Sample},
+    pixel_filter::PixelFilter,
+    program::ExecutionContext,
+    tracer::{Bounce, BounceType, RenderContext},
+};
+
+/// The auxiliary buffers that can be rendered next to the main image.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AovKind {
+    Depth,
+    Normal,
+    TextureCoordinates,
+    Albedo,
+    ObjectId,
+    Direct,
+    Indirect,
+    Strategies,
+}
+
+/// A way of connecting a camera path and a light path in the bidirectional
+/// renderer, by how many vertices it takes from each of them. The camera
+/// and the light source are vertices too.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Strategy {
+    pub light_vertices: usize,
+    pub camera_vertices: usize,
+}
+
+/// Collects information about the first hit of each camera ray, and about
+/// the lighting. Each buffer is only allocated if it's requested.
+pub(crate) struct Aovs {
+    depth: Option<Film>,
+    normal: Option<Film>,
+    texture_coordinates: Option<Film>,
+    albedo: Option<Film>,
+    object_ids: Option<ObjectIds>,
+    direct: Option<Film>,
+    indirect: Option<Film>,
+    strategies: Vec<(Strategy, Film)>,
+}
+
+impl Aovs {
+    pub fn new(
+        kinds: &[AovKind],
+        strategies: &[Strategy],
+        width: usize,
+        height: usize,
+        spectrum_bins: usize,
+        spectrum_span: (f32, f32),
+        filter: PixelFilter,
+        views: FilmViews,
+    ) -> Self {
+        let data_film = |kind| {
+            kinds
+                .contains(&kind)
+                .then(|| Film::new(width, height, 3, (0.0, 3.0), filter, views))
+        };
+        let spectral_film = |kind| {
+            kinds
+                .contains(&kind)
+                .then(|| Film::new(width, height, spectrum_bins, spectrum_span, filter, views))
+        };
+
+        Aovs {
+            depth: data_film(AovKind::Depth),
+            normal: data_film(AovKind::Normal),
+            texture_coordinates: data_film(AovKind::TextureCoordinates),
+            albedo: spectral_film(AovKind::Albedo),
+            object_ids: kinds
+                .contains(&AovKind::ObjectId)
+                .then(|| ObjectIds::new(width, height)),
+            direct: spectral_film(AovKind::Direct),
+            indirect: spectral_film(AovKind::Indirect),
+            strategies: if kinds.contains(&AovKind::Strategies) {
+                strategies
+                    .iter()
+                    .map(|&strategy| {
+                        let film =
+                            Film::new(width, height, spectrum_bins, spectrum_span, filter, views);
+                        (strategy, film)
+                    })
+                    .collect()
+            } else {
+                Vec::new()
+            },
+        }
+    }
+
+    /// Returns a film with depth, normal or texture coordinates, where each
+    /// grain is a vector component.
+    pub fn data(&self, kind: AovKind) -> Option<&Film> {
+        match kind {
+            AovKind::Depth => self.depth.as_ref(),
+            AovKind::Normal => self.normal.as_ref(),
+            AovKind::TextureCoordinates => self.texture_coordinates.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Returns a film with the albedo, or the direct or indirect light.
+    pub fn spectral(&self, kind: AovKind) -> Option<&Film> {
+        match kind {
+            AovKind::Albedo => self.albedo.as_ref(),
+            AovKind::Direct => self.direct.as_ref(),
+            AovKind::Indirect => self.indirect.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Returns the films with the weighted light from each connection strategy.
+    /// They add up to the main image.
+    pub fn strategies(&self) -> &[(Strategy, Film)] {
+        &self.strategies
+    }
+
+    pub fn object_ids(&self) -> Option<&ObjectIds> {
+        self.object_ids.as_ref()
+    }
+
+    /// Writes the names of the buffers and their raw data, so they can be
+    /// restored together with the main film.
+    pub fn write_checkpoint(&self, mut writer: impl Write) -> io::Result<()> {
+        let names = self.buffer_names();
+        writer.write_all(&(names.len() as u64).to_le_bytes())?;
+        for name in &names {
+            write_string(&mut writer, name)?;
+        }
+
+        for (_, film) in self.named_films() {
+            film.write_grains(&mut writer)?;
+        }
+
+        if let Some(object_ids) = &self.object_ids {
+            for pixel in &object_ids.pixels {
+                writer.write_all(&pixel.load(Ordering::Relaxed).to_le_bytes())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the buffer data with the data from a checkpoint. The
+    /// checkpoint has to have the same buffers.
+    pub fn read_checkpoint(&self, mut reader: impl Read) -> Result<(), Box<dyn Error>> {
+        let length = u64::from_le_bytes(read_bytes(&mut reader)?);
+        let names = (0..length)
+            .map(|_| read_string(&mut reader))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let expected_names = self.buffer_names();
+        if names != expected_names {
+            return Err(format!(
+                "the checkpoint has the AOVs [{}], but the project has [{}]",
+                names.join(", "),
+                expected_names.join(", ")
+            )
+            .into());
+        }
+
+        for (_, film) in self.named_films() {
+            film.read_grains(&mut reader)?;
+        }
+
+        if let Some(object_ids) = &self.object_ids {
+            for pixel in &object_ids.pixels {
+                let value = u64::from_le_bytes(read_bytes(&mut reader)?);
+                pixel.store(value, Ordering::Relaxed);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The buffers that are stored as films, in checkpoint order.
+    fn named_films(&self) -> Vec<(String, &Film)> {
+        let films = [
+            ("depth", &self.depth),
+            ("normal", &self.normal),
+            ("texture_coordinates", &self.texture_coordinates),
+            ("albedo", &self.albedo),
+            ("direct", &self.direct),
+            ("indirect", &self.indirect),
+        ];
+
+        films
+            .into_iter()
+            .filter_map(|(name, film)| Some((name.to_owned(), film.as_ref()?)))
+            .chain(self.strategies.iter().map(|(strategy, film)| {
+                let name = format!(
+                    "strategy_s{}_t{}",
+                    strategy.light_vertices, strategy.camera_vertices
+                );
+                (name, film)
+            }))
+            .collect()
+    }
+
+    fn buffer_names(&self) -> Vec<String> {
+        let mut names: Vec<_> = self
+            .named_films()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        if self.object_ids.is_some() {
+            names.push("object_id".to_owned());
+        }
+
+        names
+    }
+
+    pub fn has_surface(&self) -> bool {
+        self.depth.is_some()
+            || self.normal.is_some()
+            || self.texture_coordinates.is_some()
+            || self.albedo.is_some()
+            || self.object_ids.is_some()
+    }
+
+    pub fn has_lighting(&self) -> bool {
+        self.direct.is_some() || self.indirect.is_some()
+    }
+
+    /// Records the first surface that a camera ray hit. Nothing is recorded
+    /// for the sky, so pixels that only see the sky stay at zero.
+    pub fn record_surface<'a>(
+        &self,
+        position: Point2<f32>,
+        ray_origin: Point3<f32>,
+        bounce: &Bounce<'a>,
+        wavelengths: impl IntoIterator<Item = f32>,
+        exe: &mut ExecutionContext<'a>,
+    ) {
+        if !self.has_surface() || bounce.object_id == 0 {
+            return;
+        }
+
+        let expose_data = |film: &Option<Film>, values: [f32; 3]| {
+            if let Some(film) = film {
+                for (grain, value) in values.into_iter().enumerate() {
+                    film.expose_grain(position, grain, value, 1.0);
+                }
+            }
+        };
+
+        let depth = ray_origin.distance(bounce.position);
+        expose_data(&self.depth, [depth, depth, depth]);
+        expose_data(&self.normal, bounce.normal.into());
+        expose_data(
+            &self.texture_coordinates,
+            [bounce.texture.x, bounce.texture.y, 0.0],
+        );
+
+        if let Some(object_ids) = &self.object_ids {
+            object_ids.record(position, bounce.object_id);
+        }
+
+        // Light sources don't have an albedo, since they don't reflect.
+        if let (Some(albedo), false) = (&self.albedo, bounce.ty.is_emission()) {
+            let mut wavelengths = wavelengths.into_iter();
+            let Some(first_wavelength) = wavelengths.next() else {
+                return;
+            };
+
+            let initial_input = RenderContext {
+                wavelength: first_wavelength,
+                incident: bounce.incident,
+                normal: bounce.normal,
+                texture: bounce.texture,
+            };
+            let mut exe = bounce.color.memoize(initial_input, exe);
+
+            albedo.expose(position, albedo_sample(first_wavelength, exe.run()));
+            for wavelength in wavelengths {
+                exe.update_input().set_wavelength(wavelength);
+                albedo.expose(position, albedo_sample(wavelength, exe.run()));
+            }
+        }
+    }
+
+    /// Splits a finished sample into its direct and indirect parts, where
+    /// `direct` is the brightness from light sources seen directly or after
+    /// the first bounce.
+    pub fn record_lighting(&self, position: Point2<f32>, sample: &Sample, direct: f32) {
+        let expose = |film: &Option<Film>, brightness: f32| {
+            if let Some(film) = film {
+                film.expose(
+                    position,
+                    Sample {
+                        brightness,
+                        ..sample.clone()
+                    },
+                );
+            }
+        };
+
+        expose(&self.direct, direct);
+        expose(&self.indirect, sample.brightness - direct);
+    }
+}
+
+fn albedo_sample(wavelength: f32, brightness: f32) -> Sample {
+    Sample {
+        brightness,
+        wavelength,
+        weight: 1.0,
+    }
+}
+
+/// Reads the three components of each pixel in a film from `Aovs::data`.
+pub(crate) fn developed_vectors(film: &Film) -> Vec<[f32; 3]> {
+    film.developed_pixels()
+        .map(|spectrum| {
+            let mut vector = [0.0; 3];
+            for (component, grain) in vector.iter_mut().zip(spectrum.grain_values()) {
+                *component = grain;
+            }
+            vector
+        })
+        .collect()
+}
+
+/// Returns the number of bounces in `path` that contribute direct light.
+/// That's the first bounce, and the second one if it's a light source that
+/// was hit by a reflected ray.
+pub(crate) fn direct_bounces(path: &[Bounce<'_>]) -> usize {
+    match path.get(1) {
+        Some(Bounce {
+            ty: BounceType::Emission,
+            ..
+        }) => 2,
+        _ => 1,
+    }
+}
+
+/// Gives each object ID a distinct color, with black for the background.
+pub(crate) fn object_id_color(id: u32) -> [f32; 3] {
+    if id == 0 {
+        return [0.0; 3];
+    }
+
+    let hash = id.wrapping_mul(0x9E37_79B9);
+    [hash >> 24, hash >> 16, hash >> 8].map(|component| (component & 0xFF) as f32 / 255.0)
+}
+
+/// The object ID that's closest to the center of each pixel. It's stored
+/// together with the distance, as `distance << 32 | id`, to be able to
+/// atomically keep the closest one.
+pub(crate) struct ObjectIds {
+    width: usize,
+    height: usize,
+    aspect_ratio: AspectRatio,
+    pixels: Vec<AtomicU64>,
+}
+
+impl ObjectIds {
+    fn new(width: usize, height: usize) -> Self {
+        ObjectIds {
+            width,
+            height,
+            aspect_ratio: AspectRatio::new(width, height),
+            pixels: std::iter::repeat_with(|| AtomicU64::new(u64::MAX))
+                .take(width * height)
+                .collect(),
+        }
+    }
+
+    fn record(&self, position: Point2<f32>, id: u32) {
+        let Some(film_position) = self.aspect_ratio.to_film(position) else {
+            return;
+        };
+
+        let x = (film_position.x as usize).min(self.width - 1);
+        let y = (film_position.y as usize).min(self.height - 1);
+        let center = Point2::new(x as f32 + 0.5, y as f32 + 0.5);
+
+        // Positive floats keep their order when compared as integers.
+        let distance = center.distance2(film_position).to_bits() as u64;
+        self.pixels[x + y * self.width].fetch_min(distance << 32 | id as u64, Ordering::Relaxed);
+    }
+
+    /// The object ID of each pixel, or 0 where no object was hit.
+    pub fn ids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.pixels
+            .iter()
+            .map(|pixel| match pixel.load(Ordering::Relaxed) {
+                u64::MAX => 0,
+                value => value as u32,
+            })
+    }
+}
@@ -0,0 +1,193 @@
+use std::{error::Error, f32::consts};
+
+use cgmath::{EuclideanSpace, InnerSpace, Point2, Vector2};
+use rand::Rng;
+
+use crate::project::textures::Textures;
+
+/// The opening of a thin lens, which decides the shape of the bokeh.
+pub(crate) struct Aperture {
+    radius: f32,
+    shape: ApertureShape,
+    vignetting: f32,
+}
+
+impl Aperture {
+    pub fn from_project(
+        size: f32,
+        shape: Option<crate::project::ApertureShape>,
+        vignetting: f32,
+        textures: &Textures,
+    ) -> Result<Self, Box<dyn Error>> {
+        let shape = match shape {
+            None => ApertureShape::Circle,
+            Some(crate::project::ApertureShape::Polygon {
+                blades,
+                rotation,
+                curvature,
+            }) => {
+                if blades < 3 {
+                    return Err(format!(
+                        "the aperture needs at least 3 blades, but it has {}",
+                        blades
+                    )
+                    .into());
+                }
+
+                ApertureShape::Polygon {
+                    blades,
+                    rotation: rotation.unwrap_or(0.0).to_radians(),
+                    curvature: curvature.unwrap_or(0.0).clamp(0.0, 1.0),
+                }
+            }
+            Some(crate::project::ApertureShape::Texture { texture }) => {
+                ApertureShape::Texture(ApertureTexture::new(textures.get_mono(texture))?)
+            }
+        };
+
+        Ok(Aperture {
+            // The aperture size is the squared radius of the lens.
+            radius: size.max(0.0).sqrt(),
+            shape,
+            vignetting: vignetting.max(0.0),
+        })
+    }
+
+    pub fn is_pinhole(&self) -> bool {
+        self.radius <= 0.0
+    }
+
+    /// Picks a position on the lens for a ray towards `view_position`, or
+    /// `None` if it's blocked by vignetting. The blocked samples darken the
+    /// edges of the image and give the bokeh a cat's eye shape, the same way
+    /// as `is_vignetted` does for connections to the camera.
+    pub fn sample(&self, view_position: Point2<f32>, rng: &mut impl Rng) -> Option<Point2<f32>> {
+        let lens_position = self.sample_shape(rng);
+        (!self.is_vignetted(lens_position, view_position)).then_some(lens_position)
+    }
+
+    /// Picks a position within the aperture shape, without vignetting.
+    pub fn sample_shape(&self, rng: &mut impl Rng) -> Point2<f32> {
+        let unit_position = match self.shape {
+            ApertureShape::Circle => sample_disk(rng),
+            ApertureShape::Polygon {
+                blades,
+                rotation,
+                curvature,
+            } => loop {
+                // The polygon is inscribed in the unit circle, so points in
+                // the circle are kept if they are inside the polygon.
+                let position = sample_disk(rng);
+                let radius = polygon_radius(position, blades, rotation, curvature);
+                if position.to_vec().magnitude2() <= radius * radius {
+                    break position;
+                }
+            },
+            ApertureShape::Texture(ref texture) => texture.sample(rng),
+        };
+
+        unit_position * self.radius
+    }
+
+    /// Checks if the lens position is outside of the lens barrel, as seen
+    /// from `view_position`. The barrel is modeled as a second opening that
+    /// moves sideways towards the edges of the image.
+    pub fn is_vignetted(&self, lens_position: Point2<f32>, view_position: Point2<f32>) -> bool {
+        if self.vignetting == 0.0 {
+            return false;
+        }
+
+        // The view's Y axis points down, while the lens' Y axis points up.
+        let offset = Vector2::new(view_position.x, -view_position.y) * self.vignetting;
+        let barrel_center = Point2::from_vec(offset * self.radius);
+
+        (lens_position - barrel_center).magnitude2() > self.radius * self.radius
+    }
+}
+
+enum ApertureShape {
+    Circle,
+    /// A regular polygon with `blades` corners. The blades are straight
+    /// with a curvature of 0 and form a circle with a curvature of 1.
+    Polygon {
+        blades: u32,
+        rotation: f32,
+        curvature: f32,
+    },
+    Texture(ApertureTexture),
+}
+
+/// The distance from the center to the edge of a polygon with rounded sides,
+/// in the direction of `position`.
+fn polygon_radius(position: Point2<f32>, blades: u32, rotation: f32, curvature: f32) -> f32 {
+    let blade_angle = consts::TAU / blades as f32;
+    let angle = (position.y.atan2(position.x) - rotation).rem_euclid(blade_angle);
+    let straight_radius = (blade_angle * 0.5).cos() / (angle - blade_angle * 0.5).cos();
+
+    straight_radius + (1.0 - straight_radius) * curvature
+}
+
+fn sample_disk(rng: &mut impl Rng) -> Point2<f32> {
+    let radius = rng.gen::<f32>().sqrt();
+    let angle = consts::TAU * rng.gen::<f32>();
+    Point2::new(radius * angle.cos(), radius * angle.sin())
+}
+
+/// A grayscale image of the aperture, where the brightness is how much light
+/// passes through. The longest side of the image covers the lens diameter.
+struct ApertureTexture {
+    width: usize,
+    height: usize,
+    /// The cumulative brightness of the pixels, row by row.
+    cumulative_brightness: Vec<f32>,
+}
+
+impl ApertureTexture {
+    fn new(texture: &crate::texture::Texture<palette::LinLuma>) -> Result<Self, Box<dyn Error>> {
+        let width = texture.width();
+        let height = texture.height();
+
+        let mut sum = 0.0;
+        let cumulative_brightness: Vec<f32> = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| {
+                sum += texture.color_at(x, y).luma.max(0.0);
+                sum
+            })
+            .collect();
+
+        if sum <= 0.0 {
+            return Err("the aperture texture is completely black".into());
+        }
+
+        Ok(ApertureTexture {
+            width,
+            height,
+            cumulative_brightness,
+        })
+    }
+
+    /// Picks a position in the unit square, with brighter pixels being more
+    /// likely to be picked.
+    fn sample(&self, rng: &mut impl Rng) -> Point2<f32> {
+        let total = *self
+            .cumulative_brightness
+            .last()
+            .expect("the aperture texture should have at least one pixel");
+        let target = rng.gen::<f32>() * total;
+        let index = self
+            .cumulative_brightness
+            .partition_point(|&brightness| brightness <= target)
+            .min(self.cumulative_brightness.len() - 1);
+
+        let x = (index % self.width) as f32 + rng.gen::<f32>();
+        let y = (index / self.width) as f32 + rng.gen::<f32>();
+        let half_size = self.width.max(self.height) as f32 * 0.5;
+
+        // The first row is the top of the image.
+        Point2::new(
+            (x - self.width as f32 * 0.5) / half_size,
+            (self.height as f32 * 0.5 - y) / half_size,
+        )
+    }
+}
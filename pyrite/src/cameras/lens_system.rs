@@ -0,0 +1,572 @@
+use std::{error::Error, path::Path};
+
+use cgmath::{EuclideanSpace, InnerSpace, Point2, Point3, Vector3};
+use collision::{Ray, Ray3};
+use rand::Rng;
+
+/// The lens data is in millimeters, while the scene is in meters.
+const LENS_UNIT: f32 = 0.001;
+
+/// The Fraunhofer d, F and C lines, in micrometers, which are used for the
+/// refractive index and Abbe number.
+const D_LINE: f32 = 0.587_56;
+const F_LINE: f32 = 0.486_13;
+const C_LINE: f32 = 0.656_27;
+
+/// The exit pupil bounds are precomputed for this many distances from the
+/// center of the sensor.
+const PUPIL_BINS: usize = 64;
+
+/// The rear element is tested with a grid of this many rays per side when
+/// finding the exit pupil.
+const PUPIL_GRID_SIZE: usize = 64;
+
+/// A sequence of spherical lens elements in front of the sensor.
+///
+/// The sensor is at `z = 0` and the elements are along the negative Z axis,
+/// with the first element being the one closest to the scene.
+pub(crate) struct LensSystem {
+    elements: Vec<LensElement>,
+    half_sensor_width: f32,
+    /// The parts of the rear element that lead out of the lens, for each
+    /// distance from the center of the sensor, along the X axis.
+    exit_pupil: Vec<PupilBounds>,
+    /// The light that reaches the center of the sensor, which is used for
+    /// making the center of the image as bright as it would be without the
+    /// lens.
+    center_light: f32,
+}
+
+impl LensSystem {
+    pub fn from_file(
+        path: &Path,
+        sensor_width: f32,
+        focus_distance: Option<f32>,
+        aperture: Option<f32>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|error| format!("could not read {}: {}", path.display(), error))?;
+
+        let mut elements = parse_lens_data(&content)
+            .map_err(|error| format!("invalid lens data in {}: {}", path.display(), error))?;
+
+        if sensor_width <= 0.0 {
+            return Err("the sensor width must be positive".into());
+        }
+
+        if let Some(aperture) = aperture {
+            let stop = elements
+                .iter_mut()
+                .find(|element| element.is_stop())
+                .ok_or("the lens has no aperture stop to adjust")?;
+            stop.aperture_radius = aperture.max(0.0) * 0.5 * LENS_UNIT;
+        }
+
+        let mut lens_system = LensSystem {
+            elements,
+            half_sensor_width: sensor_width * 0.5 * LENS_UNIT,
+            exit_pupil: Vec::new(),
+            center_light: 0.0,
+        };
+        lens_system.update_positions();
+        lens_system.focus(focus_distance)?;
+
+        lens_system.exit_pupil = (0..PUPIL_BINS)
+            .map(|bin| {
+                let max_radius = lens_system.half_sensor_width * std::f32::consts::SQRT_2;
+                let from = max_radius * bin as f32 / PUPIL_BINS as f32;
+                let to = max_radius * (bin + 1) as f32 / PUPIL_BINS as f32;
+                lens_system.find_pupil_bounds(from, to)
+            })
+            .collect();
+        lens_system.center_light = lens_system.find_center_light();
+
+        if lens_system.center_light <= 0.0 {
+            return Err(format!(
+                "no light reaches the center of the sensor through the lens in {}",
+                path.display()
+            )
+            .into());
+        }
+
+        Ok(lens_system)
+    }
+
+    /// Checks if the refraction depends on the wavelength.
+    pub fn is_dispersive(&self) -> bool {
+        self.elements
+            .iter()
+            .any(|element| element.glass.is_some_and(|glass| glass.dispersion != 0.0))
+    }
+
+    /// Traces a ray from a position in the view, through the lens, and
+    /// returns it together with its weight. The result is in camera space,
+    /// and is `None` if the ray is blocked inside the lens.
+    pub fn ray_towards(
+        &self,
+        target: Point2<f32>,
+        wavelength: f32,
+        rng: &mut impl Rng,
+    ) -> Option<(Ray3<f32>, f32)> {
+        // The image is upside down on the sensor.
+        let sensor_position = Point3::new(
+            -target.x * self.half_sensor_width,
+            target.y * self.half_sensor_width,
+            0.0,
+        );
+
+        let sensor_radius = sensor_position.to_vec().magnitude();
+        let max_radius = self.half_sensor_width * std::f32::consts::SQRT_2;
+        let bin = ((sensor_radius / max_radius * PUPIL_BINS as f32) as usize).min(PUPIL_BINS - 1);
+        let bounds = self.exit_pupil[bin].as_ref()?;
+
+        let pupil_x = bounds.min.x + (bounds.max.x - bounds.min.x) * rng.gen::<f32>();
+        let pupil_y = bounds.min.y + (bounds.max.y - bounds.min.y) * rng.gen::<f32>();
+
+        // The bounds are along the X axis, so they are rotated towards the
+        // sensor position.
+        let (sin, cos) = if sensor_radius > 0.0 {
+            (
+                sensor_position.y / sensor_radius,
+                sensor_position.x / sensor_radius,
+            )
+        } else {
+            (0.0, 1.0)
+        };
+        let pupil_position = Point3::new(
+            cos * pupil_x - sin * pupil_y,
+            sin * pupil_x + cos * pupil_y,
+            self.rear_z(),
+        );
+
+        let direction = (pupil_position - sensor_position).normalize();
+        let ray = self.trace(
+            Ray::new(sensor_position, direction),
+            wavelength * 0.001,
+            Direction::TowardsScene,
+        )?;
+
+        let cos_theta = -direction.z;
+        let weight = cos_theta.powi(4) * bounds.area() / self.center_light;
+
+        Some((ray, weight))
+    }
+
+    /// Moves the lens along the optical axis until objects at
+    /// `focus_distance` from the sensor, or infinitely far away, are in
+    /// focus, using a thick lens approximation.
+    fn focus(&mut self, focus_distance: Option<f32>) -> Result<(), Box<dyn Error>> {
+        let object_principal_z = self.principal_plane(Direction::TowardsScene)?.0;
+        let (image_principal_z, image_focal_z) = self.principal_plane(Direction::TowardsSensor)?;
+        let focal_length = image_focal_z - image_principal_z;
+        let image_distance = -image_principal_z;
+
+        let delta = if let Some(focus_distance) = focus_distance {
+            // Shifting the lens by `delta` towards the scene has to make
+            // `1 / object_distance + 1 / image_distance = 1 / focal_length`.
+            let object_distance = object_principal_z + focus_distance;
+            let total = object_distance + image_distance;
+            let discriminant = total * (total - 4.0 * focal_length);
+
+            if discriminant < 0.0 {
+                return Err(
+                    format!("the lens can't focus at a distance of {}", focus_distance).into(),
+                );
+            }
+
+            0.5 * (object_distance - image_distance - discriminant.sqrt())
+        } else {
+            focal_length - image_distance
+        };
+
+        let rear = self
+            .elements
+            .last_mut()
+            .expect("the lens should have at least one element");
+        rear.thickness += delta;
+
+        if rear.thickness <= 0.0 {
+            return Err("the lens would have to be moved through the sensor to focus".into());
+        }
+
+        self.update_positions();
+
+        Ok(())
+    }
+
+    /// Finds the principal plane and focal point on the side of the lens
+    /// that rays end up on when going in `direction`, by tracing a ray
+    /// parallel to the optical axis.
+    fn principal_plane(&self, direction: Direction) -> Result<(f32, f32), Box<dyn Error>> {
+        let height = self.half_sensor_width * 0.001;
+        let ray_in = match direction {
+            Direction::TowardsScene => Ray::new(Point3::new(height, 0.0, 1.0), -Vector3::unit_z()),
+            Direction::TowardsSensor => Ray::new(
+                Point3::new(height, 0.0, self.front_z() - 1.0),
+                Vector3::unit_z(),
+            ),
+        };
+
+        let ray_out = self
+            .trace(ray_in, D_LINE, direction)
+            .ok_or("the lens blocks rays along the optical axis")?;
+
+        if ray_out.direction.x == 0.0 {
+            return Err("the lens doesn't focus light".into());
+        }
+
+        let focal_t = -ray_out.origin.x / ray_out.direction.x;
+        let principal_t = (ray_in.origin.x - ray_out.origin.x) / ray_out.direction.x;
+
+        Ok((
+            ray_out.origin.z + ray_out.direction.z * principal_t,
+            ray_out.origin.z + ray_out.direction.z * focal_t,
+        ))
+    }
+
+    /// Finds the part of the rear element that lets light out of the lens,
+    /// for sensor positions between `from` and `to` from the center.
+    fn find_pupil_bounds(&self, from: f32, to: f32) -> PupilBounds {
+        let rear_radius = self.rear_radius();
+        let cell_size = 2.0 * rear_radius / PUPIL_GRID_SIZE as f32;
+        let mut bounds: PupilBounds = None;
+
+        for sensor_x in [from, to] {
+            let sensor_position = Point3::new(sensor_x, 0.0, 0.0);
+
+            for pupil_position in self.rear_grid() {
+                let direction = (pupil_position - sensor_position).normalize();
+                let ray = Ray::new(sensor_position, direction);
+
+                if self.trace(ray, D_LINE, Direction::TowardsScene).is_some() {
+                    let point = Point2::new(pupil_position.x, pupil_position.y);
+                    bounds = Some(match bounds {
+                        Some(bounds) => bounds.including(point),
+                        None => Bounds::new(point),
+                    });
+                }
+            }
+        }
+
+        // The grid only tests the centers of the cells.
+        bounds.map(|bounds| bounds.expanded(cell_size, rear_radius))
+    }
+
+    /// Adds up the light from the rear element that reaches the center of
+    /// the sensor.
+    fn find_center_light(&self) -> f32 {
+        let rear_radius = self.rear_radius();
+        let cell_size = 2.0 * rear_radius / PUPIL_GRID_SIZE as f32;
+
+        self.rear_grid()
+            .filter_map(|pupil_position| {
+                let direction = pupil_position.to_vec().normalize();
+                let ray = Ray::new(Point3::origin(), direction);
+
+                self.trace(ray, D_LINE, Direction::TowardsScene)
+                    .map(|_| direction.z.powi(4) * cell_size * cell_size)
+            })
+            .sum()
+    }
+
+    /// The centers of a grid of cells over the rear element.
+    fn rear_grid(&self) -> impl Iterator<Item = Point3<f32>> {
+        let rear_radius = self.rear_radius();
+        let rear_z = self.rear_z();
+        let cell_size = 2.0 * rear_radius / PUPIL_GRID_SIZE as f32;
+
+        (0..PUPIL_GRID_SIZE)
+            .flat_map(|y| (0..PUPIL_GRID_SIZE).map(move |x| (x, y)))
+            .map(move |(x, y)| {
+                Point3::new(
+                    -rear_radius + (x as f32 + 0.5) * cell_size,
+                    -rear_radius + (y as f32 + 0.5) * cell_size,
+                    rear_z,
+                )
+            })
+            .filter(move |position| position.x.hypot(position.y) <= rear_radius)
+    }
+
+    /// Sends a ray through the lens elements, refracting it at each surface.
+    /// The wavelength is in micrometers.
+    fn trace(
+        &self,
+        mut ray: Ray3<f32>,
+        wavelength: f32,
+        direction: Direction,
+    ) -> Option<Ray3<f32>> {
+        let element_count = self.elements.len();
+
+        for step in 0..element_count {
+            let index = match direction {
+                Direction::TowardsScene => element_count - 1 - step,
+                Direction::TowardsSensor => step,
+            };
+            let element = &self.elements[index];
+
+            let (distance, normal) = if element.is_stop() {
+                let distance = (element.z - ray.origin.z) / ray.direction.z;
+                (distance, None)
+            } else {
+                let (distance, normal) = element.intersect(&ray)?;
+                (distance, Some(normal))
+            };
+
+            if !(distance > 0.0 && distance.is_finite()) {
+                return None;
+            }
+
+            let hit = ray.origin + ray.direction * distance;
+            if hit.x * hit.x + hit.y * hit.y > element.aperture_radius * element.aperture_radius {
+                return None;
+            }
+            ray.origin = hit;
+
+            if let Some(normal) = normal {
+                let scene_side_ior = index
+                    .checked_sub(1)
+                    .map_or(1.0, |previous| self.elements[previous].ior(wavelength));
+                let sensor_side_ior = element.ior(wavelength);
+
+                let ior_ratio = match direction {
+                    Direction::TowardsScene => sensor_side_ior / scene_side_ior,
+                    Direction::TowardsSensor => scene_side_ior / sensor_side_ior,
+                };
+
+                ray.direction = refract(-ray.direction, normal, ior_ratio)?;
+            }
+        }
+
+        Some(ray)
+    }
+
+    /// Places the elements along the optical axis, from their thicknesses.
+    fn update_positions(&mut self) {
+        let mut z = 0.0;
+        for element in self.elements.iter_mut().rev() {
+            z -= element.thickness;
+            element.z = z;
+        }
+    }
+
+    fn front_z(&self) -> f32 {
+        self.elements.first().map_or(0.0, |element| element.z)
+    }
+
+    fn rear_z(&self) -> f32 {
+        self.elements.last().map_or(0.0, |element| element.z)
+    }
+
+    fn rear_radius(&self) -> f32 {
+        self.elements
+            .last()
+            .map_or(0.0, |element| element.aperture_radius)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Direction {
+    TowardsScene,
+    TowardsSensor,
+}
+
+struct LensElement {
+    /// The radius of the spherical surface, which is positive when it bulges
+    /// towards the scene. The aperture stop has a radius of 0.
+    curvature_radius: f32,
+    /// The distance to the next element, or to the sensor.
+    thickness: f32,
+    /// The glass behind the surface, or `None` for air.
+    glass: Option<Glass>,
+    aperture_radius: f32,
+    /// The position of the surface on the optical axis.
+    z: f32,
+}
+
+impl LensElement {
+    fn is_stop(&self) -> bool {
+        self.curvature_radius == 0.0
+    }
+
+    fn ior(&self, wavelength: f32) -> f32 {
+        self.glass.map_or(1.0, |glass| glass.ior(wavelength))
+    }
+
+    /// Finds the distance to the surface, and its normal, which faces the
+    /// incoming ray.
+    fn intersect(&self, ray: &Ray3<f32>) -> Option<(f32, Vector3<f32>)> {
+        let radius = self.curvature_radius;
+        let center = Point3::new(0.0, 0.0, self.z + radius);
+        let offset = ray.origin - center;
+
+        let a = ray.direction.magnitude2();
+        let b = 2.0 * ray.direction.dot(offset);
+        let c = offset.magnitude2() - radius * radius;
+        let discriminant = b * b - 4.0 * a * c;
+
+        if discriminant < 0.0 {
+            return None;
+        }
+
+        let root = discriminant.sqrt();
+        let near = (-b - root) / (2.0 * a);
+        let far = (-b + root) / (2.0 * a);
+
+        // Only one half of the sphere is part of the lens.
+        let use_near = (ray.direction.z > 0.0) != (radius < 0.0);
+        let distance = if use_near { near } else { far };
+
+        if distance < 0.0 {
+            return None;
+        }
+
+        let normal = (offset + ray.direction * distance).normalize();
+        let normal = if normal.dot(ray.direction) > 0.0 {
+            -normal
+        } else {
+            normal
+        };
+
+        Some((distance, normal))
+    }
+}
+
+/// The refractive index of a glass, following Cauchy's equation.
+#[derive(Clone, Copy)]
+struct Glass {
+    ior: f32,
+    dispersion: f32,
+}
+
+impl Glass {
+    /// Fits Cauchy's equation to the refractive index at the d line and the
+    /// Abbe number. An Abbe number of 0 means that there's no dispersion.
+    fn new(ior: f32, abbe_number: f32) -> Self {
+        let dispersion = if abbe_number > 0.0 {
+            (ior - 1.0) / (abbe_number * (F_LINE.powi(-2) - C_LINE.powi(-2)))
+        } else {
+            0.0
+        };
+
+        Glass {
+            ior: ior - dispersion / (D_LINE * D_LINE),
+            dispersion,
+        }
+    }
+
+    /// The wavelength is in micrometers.
+    fn ior(&self, wavelength: f32) -> f32 {
+        self.ior + self.dispersion / (wavelength * wavelength)
+    }
+}
+
+type PupilBounds = Option<Bounds>;
+
+#[derive(Clone, Copy)]
+struct Bounds {
+    min: Point2<f32>,
+    max: Point2<f32>,
+}
+
+impl Bounds {
+    fn new(point: Point2<f32>) -> Self {
+        Bounds {
+            min: point,
+            max: point,
+        }
+    }
+
+    fn including(self, point: Point2<f32>) -> Self {
+        Bounds {
+            min: Point2::new(self.min.x.min(point.x), self.min.y.min(point.y)),
+            max: Point2::new(self.max.x.max(point.x), self.max.y.max(point.y)),
+        }
+    }
+
+    /// Grows the bounds by `amount` on each side, without going outside
+    /// `limit` from the center.
+    fn expanded(self, amount: f32, limit: f32) -> Self {
+        Bounds {
+            min: Point2::new(
+                (self.min.x - amount).max(-limit),
+                (self.min.y - amount).max(-limit),
+            ),
+            max: Point2::new(
+                (self.max.x + amount).min(limit),
+                (self.max.y + amount).min(limit),
+            ),
+        }
+    }
+
+    fn area(&self) -> f32 {
+        (self.max.x - self.min.x) * (self.max.y - self.min.y)
+    }
+}
+
+/// Refracts a direction that points away from the surface. Returns `None`
+/// for total internal reflection.
+fn refract(incident: Vector3<f32>, normal: Vector3<f32>, ior_ratio: f32) -> Option<Vector3<f32>> {
+    let cos_incident = normal.dot(incident);
+    let sin2_incident = (1.0 - cos_incident * cos_incident).max(0.0);
+    let sin2_transmitted = ior_ratio * ior_ratio * sin2_incident;
+
+    if sin2_transmitted >= 1.0 {
+        return None;
+    }
+
+    let cos_transmitted = (1.0 - sin2_transmitted).sqrt();
+    Some(
+        (-incident * ior_ratio + normal * (ior_ratio * cos_incident - cos_transmitted)).normalize(),
+    )
+}
+
+/// Parses lens data, where each line describes a surface with its curvature
+/// radius, thickness, refractive index, Abbe number and aperture diameter,
+/// separated by whitespace. Empty lines and lines that start with `#` are
+/// skipped.
+fn parse_lens_data(content: &str) -> Result<Vec<LensElement>, String> {
+    let mut elements = Vec::new();
+
+    for (line_index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let values = line
+            .split_whitespace()
+            .map(str::parse::<f32>)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|error| format!("line {}: {}", line_index + 1, error))?;
+
+        let &[curvature_radius, thickness, ior, abbe_number, aperture] = &values[..] else {
+            return Err(format!(
+                "line {}: expected 5 values, but found {}",
+                line_index + 1,
+                values.len()
+            ));
+        };
+
+        if thickness < 0.0 || aperture <= 0.0 {
+            return Err(format!(
+                "line {}: the thickness can't be negative and the aperture must be positive",
+                line_index + 1
+            ));
+        }
+
+        elements.push(LensElement {
+            curvature_radius: curvature_radius * LENS_UNIT,
+            thickness: thickness * LENS_UNIT,
+            // Air is written as either 0 or 1.
+            glass: (ior > 1.0).then(|| Glass::new(ior, abbe_number)),
+            aperture_radius: aperture * 0.5 * LENS_UNIT,
+            z: 0.0,
+        });
+    }
+
+    if elements.is_empty() {
+        return Err("the lens has no elements".into());
+    }
+
+    Ok(elements)
+}
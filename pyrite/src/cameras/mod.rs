@@ -0,0 +1,647 @@
+use std::{error::Error, f32::consts, path::Path};
+
+use rand::Rng;
+
+use cgmath::{
+    Angle, EuclideanSpace, InnerSpace, Matrix4, Point2, Point3, Rad, SquareMatrix, Transform,
+    Vector2, Vector3,
+};
+use collision::{Ray, Ray3};
+
+use crate::film::{Area, FilmViews};
+
+pub(crate) use self::stereo::StereoLayout;
+
+use self::{
+    aperture::Aperture,
+    lens_system::LensSystem,
+    motion::CameraTransform,
+    stereo::{Stereo, StereoProjection},
+};
+
+use crate::project::{textures::Textures, Nodes};
+use crate::{
+    math::DIST_EPSILON,
+    project::eval_context::{EvalContext, Evaluate, EvaluateOr},
+    world::World,
+};
+
+mod aperture;
+mod lens_system;
+mod motion;
+mod stereo;
+
+pub(crate) enum Camera {
+    Perspective {
+        transform: CameraTransform,
+        view_plane: f32,
+        focus_distance: f32,
+        aperture: Aperture,
+    },
+    Orthographic {
+        transform: CameraTransform,
+        /// Half of the view width.
+        half_width: f32,
+    },
+    /// Covers the full sphere in an image with a 2:1 aspect ratio.
+    Equirectangular { transform: CameraTransform },
+    /// Projects a hemisphere, or more or less, onto a circle that touches
+    /// the shortest sides of the image.
+    Fisheye {
+        transform: CameraTransform,
+        /// The angle between the optical axis and the edge of the circle.
+        max_angle: f32,
+        projection: FisheyeProjection,
+        /// The radius of the circle in view units, where 1 is half of the
+        /// longest side of the image.
+        circle_radius: f32,
+    },
+    /// Traces the rays through a lens that is made of multiple elements.
+    LensSystem {
+        transform: CameraTransform,
+        lens: LensSystem,
+    },
+    /// Renders a view for each eye into the same film.
+    Stereo {
+        transform: CameraTransform,
+        stereo: Stereo,
+    },
+}
+
+/// A ray from the camera, with the time when it was sent out and how much of
+/// the light the camera lets through.
+pub(crate) struct CameraRay {
+    pub ray: Ray3<f32>,
+    pub time: f32,
+    pub weight: f32,
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum FisheyeProjection {
+    /// The distance from the center is proportional to the angle.
+    Equidistant,
+    /// Each part of the image covers the same solid angle.
+    Equisolid,
+}
+
+impl FisheyeProjection {
+    /// The distance from the center of the image circle, where 1 is the
+    /// edge, for an angle from the optical axis.
+    fn radius(self, angle: f32, max_angle: f32) -> f32 {
+        match self {
+            FisheyeProjection::Equidistant => angle / max_angle,
+            FisheyeProjection::Equisolid => (angle * 0.5).sin() / (max_angle * 0.5).sin(),
+        }
+    }
+
+    fn angle(self, radius: f32, max_angle: f32) -> f32 {
+        match self {
+            FisheyeProjection::Equidistant => radius * max_angle,
+            FisheyeProjection::Equisolid => 2.0 * (radius * (max_angle * 0.5).sin()).asin(),
+        }
+    }
+
+    /// The view area per solid angle at an angle from the optical axis.
+    fn density(self, angle: f32, max_angle: f32) -> f32 {
+        match self {
+            FisheyeProjection::Equidistant if angle > 0.0 => {
+                angle / (max_angle * max_angle * angle.sin())
+            }
+            FisheyeProjection::Equidistant => 1.0 / (max_angle * max_angle),
+            FisheyeProjection::Equisolid => 0.25 / (max_angle * 0.5).sin().powi(2),
+        }
+    }
+}
+
+impl Camera {
+    pub fn from_project(
+        project_camera: crate::project::Camera,
+        nodes: &Nodes,
+        textures: &Textures,
+        project_dir: &Path,
+        image_size: Vector2<u32>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let eval_context = EvalContext { nodes };
+
+        match project_camera {
+            crate::project::Camera::Perspective {
+                transform,
+                motion,
+                fov,
+                focus_distance,
+                aperture,
+                aperture_shape,
+                vignetting,
+            } => {
+                let fov: f32 = fov.evaluate(eval_context)?;
+                let fov_radians: Rad<_> = cgmath::Deg(fov * 0.5f32).into();
+                let view_plane = fov_radians.cos() / fov_radians.sin();
+
+                Ok(Camera::Perspective {
+                    transform: CameraTransform::from_project(&transform, motion, eval_context)?,
+                    view_plane,
+                    focus_distance: focus_distance.evaluate_or(eval_context, 1.0)?,
+                    aperture: Aperture::from_project(
+                        aperture.evaluate_or(eval_context, 0.0)?,
+                        aperture_shape,
+                        vignetting.evaluate_or(eval_context, 0.0)?,
+                        textures,
+                    )?,
+                })
+            }
+            crate::project::Camera::Orthographic {
+                transform,
+                motion,
+                width,
+            } => {
+                let width: f32 = width.evaluate(eval_context)?;
+
+                if width <= 0.0 {
+                    return Err("the orthographic camera width must be positive".into());
+                }
+
+                Ok(Camera::Orthographic {
+                    transform: CameraTransform::from_project(&transform, motion, eval_context)?,
+                    half_width: width * 0.5,
+                })
+            }
+            crate::project::Camera::Equirectangular { transform, motion } => {
+                Ok(Camera::Equirectangular {
+                    transform: CameraTransform::from_project(&transform, motion, eval_context)?,
+                })
+            }
+            crate::project::Camera::Fisheye {
+                transform,
+                motion,
+                fov,
+                projection,
+            } => {
+                let fov: f32 = fov.evaluate_or(eval_context, 180.0)?;
+
+                if fov <= 0.0 || fov > 360.0 {
+                    return Err(format!(
+                        "the fisheye field of view must be between 0 and 360 degrees, but it's {}",
+                        fov
+                    )
+                    .into());
+                }
+
+                let projection = match projection {
+                    None | Some(crate::project::FisheyeProjection::Equidistant) => {
+                        FisheyeProjection::Equidistant
+                    }
+                    Some(crate::project::FisheyeProjection::Equisolid) => {
+                        FisheyeProjection::Equisolid
+                    }
+                };
+
+                Ok(Camera::Fisheye {
+                    transform: CameraTransform::from_project(&transform, motion, eval_context)?,
+                    max_angle: Rad::from(cgmath::Deg(fov * 0.5)).0,
+                    projection,
+                    circle_radius: image_size.x.min(image_size.y) as f32
+                        / image_size.x.max(image_size.y) as f32,
+                })
+            }
+            crate::project::Camera::LensSystem {
+                transform,
+                motion,
+                file,
+                sensor_width,
+                focus_distance,
+                aperture,
+            } => Ok(Camera::LensSystem {
+                transform: CameraTransform::from_project(&transform, motion, eval_context)?,
+                lens: LensSystem::from_file(
+                    &project_dir.join(file),
+                    sensor_width.evaluate_or(eval_context, 36.0)?,
+                    focus_distance.evaluate(eval_context)?,
+                    aperture.evaluate(eval_context)?,
+                )?,
+            }),
+            crate::project::Camera::Stereo {
+                transform,
+                motion,
+                projection,
+                fov,
+                interocular_distance,
+                convergence_distance,
+                layout,
+            } => {
+                let projection = match projection {
+                    None | Some(crate::project::StereoProjection::Perspective) => {
+                        let fov: f32 = fov
+                            .ok_or("the perspective stereo camera needs a field of view")?
+                            .evaluate(eval_context)?;
+                        let fov_radians: Rad<_> = cgmath::Deg(fov * 0.5f32).into();
+
+                        StereoProjection::Perspective {
+                            view_plane: fov_radians.cos() / fov_radians.sin(),
+                        }
+                    }
+                    Some(crate::project::StereoProjection::Omnidirectional) => {
+                        StereoProjection::Omnidirectional
+                    }
+                };
+
+                let layout = match layout {
+                    None | Some(crate::project::StereoLayout::SideBySide) => {
+                        StereoLayout::SideBySide
+                    }
+                    Some(crate::project::StereoLayout::TopBottom) => StereoLayout::TopBottom,
+                    Some(crate::project::StereoLayout::Separate) => StereoLayout::Separate,
+                };
+
+                Ok(Camera::Stereo {
+                    transform: CameraTransform::from_project(&transform, motion, eval_context)?,
+                    stereo: Stereo::new(
+                        projection,
+                        layout,
+                        interocular_distance.evaluate_or(eval_context, 0.064)?,
+                        convergence_distance.evaluate(eval_context)?,
+                        image_size,
+                    )?,
+                })
+            }
+        }
+    }
+
+    /// Converts an area of pixels to view coordinates, where the longest side
+    /// of the image goes from -1 to 1. The same coordinates are used by the
+    /// film, so cameras that don't cover the whole image, such as circular
+    /// fisheye cameras, reject positions in `ray_towards` instead.
+    pub fn to_view_area(&self, area: &Area<usize>, width: usize, height: usize) -> Area<f32> {
+        let float_image_size = Vector2::new(width as f32, height as f32);
+        let float_coord = Point2::new(area.from.x as f32, area.from.y as f32);
+        let float_size = Vector2::new(area.size.x as f32, area.size.y as f32);
+
+        let max_dimension = float_image_size.x.max(float_image_size.y);
+
+        let from = (float_coord + (-float_image_size * 0.5)) / (max_dimension * 0.5);
+        let size = float_size / (max_dimension * 0.5);
+
+        Area::new(from, size)
+    }
+
+    /// Creates a ray towards a position in the view, for light with the
+    /// given wavelength, at a random time while the shutter is open. Returns
+    /// `None` if the position is outside the area that the camera covers,
+    /// or if the ray is blocked.
+    pub fn ray_towards<R: Rng>(
+        &self,
+        target: &Point2<f32>,
+        wavelength: f32,
+        rng: &mut R,
+    ) -> Option<CameraRay> {
+        let time = self.sample_time(rng);
+        let transform = self.transform().at(time);
+
+        let (ray, weight) = match *self {
+            Camera::Perspective {
+                view_plane,
+                focus_distance,
+                ref aperture,
+                ..
+            } => {
+                let view_position = *target;
+                let focus_x = target.x / view_plane * focus_distance;
+                let focus_y = target.y / view_plane * focus_distance;
+
+                let target = Point3::new(focus_x, -focus_y, -focus_distance);
+
+                let (origin, direction) = if aperture.is_pinhole() {
+                    (Point3::origin(), target.to_vec())
+                } else {
+                    let lens_position = aperture.sample(view_position, rng)?;
+                    let origin = Point3::new(lens_position.x, lens_position.y, 0.0);
+                    (origin, target - origin)
+                };
+
+                let ray = Ray::new(origin, direction.normalize()).transform(transform);
+                (ray, 1.0)
+            }
+            Camera::Orthographic { half_width, .. } => {
+                let origin = Point3::new(target.x * half_width, -target.y * half_width, 0.0);
+                (transformed_ray(origin, -Vector3::unit_z(), transform), 1.0)
+            }
+            Camera::Equirectangular { .. } => {
+                let longitude = target.x * consts::PI;
+                let latitude = -target.y * consts::PI;
+
+                if latitude.abs() > consts::FRAC_PI_2 {
+                    return None;
+                }
+
+                let direction = Vector3::new(
+                    latitude.cos() * longitude.sin(),
+                    latitude.sin(),
+                    -latitude.cos() * longitude.cos(),
+                );
+                (transformed_ray(Point3::origin(), direction, transform), 1.0)
+            }
+            Camera::Fisheye {
+                max_angle,
+                projection,
+                circle_radius,
+                ..
+            } => {
+                let distance = target.to_vec().magnitude();
+                let radius = distance / circle_radius;
+
+                if radius > 1.0 {
+                    return None;
+                }
+
+                let angle = projection.angle(radius, max_angle);
+                let (x, y) = if distance > 0.0 {
+                    (target.x / distance, -target.y / distance)
+                } else {
+                    (0.0, 0.0)
+                };
+                let direction = Vector3::new(angle.sin() * x, angle.sin() * y, -angle.cos());
+                (transformed_ray(Point3::origin(), direction, transform), 1.0)
+            }
+            Camera::LensSystem { ref lens, .. } => {
+                let (ray, weight) = lens.ray_towards(*target, wavelength, rng)?;
+                (
+                    transformed_ray(ray.origin, ray.direction, transform),
+                    weight,
+                )
+            }
+            Camera::Stereo { ref stereo, .. } => {
+                let (origin, direction) = stereo.ray_towards(*target)?;
+                (transformed_ray(origin, direction, transform), 1.0)
+            }
+        };
+
+        Some(CameraRay { ray, time, weight })
+    }
+
+    /// Finds where `target` ends up in the view at `time`, and the ray
+    /// between them, or returns `None` if the camera can't see it.
+    pub fn is_visible(
+        &self,
+        target: Point3<f32>,
+        time: f32,
+        world: &World,
+        rng: &mut impl Rng,
+    ) -> Option<(Point2<f32>, Ray3<f32>)> {
+        let transform = self.transform().at(time);
+
+        match *self {
+            Camera::Perspective {
+                view_plane,
+                focus_distance,
+                ref aperture,
+                ..
+            } => {
+                let inv_transform = if let Some(t) = transform.invert() {
+                    t
+                } else {
+                    return None;
+                };
+
+                let mut local_target = inv_transform.transform_point(target).to_vec();
+
+                if local_target.z >= 0.0 {
+                    return None;
+                }
+
+                let lens_position = if aperture.is_pinhole() {
+                    Point2::origin()
+                } else {
+                    aperture.sample_shape(rng)
+                };
+                let origin = Point3::new(lens_position.x, lens_position.y, 0.0);
+
+                let world_origin = transform.transform_point(origin);
+                let direction = target - world_origin;
+                let distance = direction.magnitude();
+                let ray = Ray::new(world_origin, direction / distance);
+                if let Some(hit) = world.intersect(ray) {
+                    if hit.distance < distance - DIST_EPSILON {
+                        return None;
+                    }
+                }
+
+                local_target.z += focus_distance;
+                let dist = local_target.z;
+                local_target -= origin.to_vec() * dist / focus_distance;
+                local_target.z -= focus_distance;
+
+                let view_plane_target = -local_target / local_target.z;
+                let focus_x = view_plane_target.x;
+                let focus_y = -view_plane_target.y;
+                let target_x = focus_x * view_plane;
+                let target_y = focus_y * view_plane;
+                let view_position = Point2::new(target_x, target_y);
+
+                if aperture.is_vignetted(lens_position, view_position) {
+                    return None;
+                }
+
+                Some((view_position, ray))
+            }
+            Camera::Orthographic { half_width, .. } => {
+                let inv_transform = transform.invert()?;
+                let local_target = inv_transform.transform_point(target);
+
+                if local_target.z >= 0.0 {
+                    return None;
+                }
+
+                // The ray starts on the view plane, straight behind the
+                // target.
+                let origin = Point3::new(local_target.x, local_target.y, 0.0);
+                let ray = unoccluded_ray(transform.transform_point(origin), target, world)?;
+
+                Some((
+                    Point2::new(local_target.x / half_width, -local_target.y / half_width),
+                    ray,
+                ))
+            }
+            Camera::Equirectangular { .. } => {
+                let local_direction = transform
+                    .invert()?
+                    .transform_point(target)
+                    .to_vec()
+                    .normalize();
+                let ray =
+                    unoccluded_ray(transform.transform_point(Point3::origin()), target, world)?;
+
+                let longitude = local_direction.x.atan2(-local_direction.z);
+                let latitude = local_direction.y.clamp(-1.0, 1.0).asin();
+
+                Some((
+                    Point2::new(longitude / consts::PI, -latitude / consts::PI),
+                    ray,
+                ))
+            }
+            Camera::Fisheye {
+                max_angle,
+                projection,
+                circle_radius,
+                ..
+            } => {
+                let local_direction = transform
+                    .invert()?
+                    .transform_point(target)
+                    .to_vec()
+                    .normalize();
+
+                let angle = (-local_direction.z).clamp(-1.0, 1.0).acos();
+                if angle > max_angle {
+                    return None;
+                }
+
+                let ray =
+                    unoccluded_ray(transform.transform_point(Point3::origin()), target, world)?;
+
+                let radius = projection.radius(angle, max_angle) * circle_radius;
+                let sideways = Vector2::new(local_direction.x, -local_direction.y);
+                let sideways_length = sideways.magnitude();
+                let position = if sideways_length > 0.0 {
+                    Point2::from_vec(sideways * (radius / sideways_length))
+                } else {
+                    Point2::origin()
+                };
+
+                Some((position, ray))
+            }
+            // Finding the way back through the lens would need a search for
+            // each connection, so light paths are not connected to it.
+            Camera::LensSystem { .. } => None,
+            // Each point is seen by both eyes, and the omnidirectional eyes
+            // move with the view direction, so light paths are not connected
+            // to it either.
+            Camera::Stereo { .. } => None,
+        }
+    }
+
+    /// The relative contribution of a light path that is connected to the
+    /// camera from `sq_distance` away, and lands on `position` in the view.
+    /// It's the view area per solid angle, divided by the squared distance.
+    pub fn connection_scale(&self, position: Point2<f32>, sq_distance: f32) -> f32 {
+        match *self {
+            // The view plane is tilted away from rays towards the edges, by
+            // the cosine of their angle to the optical axis, and it's also
+            // further away from them.
+            Camera::Perspective { view_plane, .. } => {
+                let sq_view_distance = view_plane * view_plane + position.to_vec().magnitude2();
+                sq_view_distance * sq_view_distance.sqrt() / (view_plane * sq_distance)
+            }
+            // The rays are parallel, so the distance doesn't matter, but a
+            // wider view spreads the light over a larger area.
+            Camera::Orthographic { half_width, .. } => 1.0 / (half_width * half_width),
+            // The image is stretched horizontally towards the poles.
+            Camera::Equirectangular { .. } => {
+                let latitude = position.y * consts::PI;
+                1.0 / (consts::PI * consts::PI * latitude.cos().max(1e-4) * sq_distance)
+            }
+            // The density is for a circle with radius 1, so it's scaled to
+            // the actual circle.
+            Camera::Fisheye {
+                max_angle,
+                projection,
+                circle_radius,
+                ..
+            } => {
+                let radius = position.to_vec().magnitude() / circle_radius;
+                let angle = projection.angle(radius.min(1.0), max_angle);
+                projection.density(angle, max_angle) * circle_radius * circle_radius / sq_distance
+            }
+            Camera::LensSystem { .. } | Camera::Stereo { .. } => 0.0,
+        }
+    }
+
+    /// Checks if light paths can be connected to the camera with
+    /// `is_visible`.
+    pub fn is_connectable(&self) -> bool {
+        match *self {
+            Camera::Perspective { .. }
+            | Camera::Orthographic { .. }
+            | Camera::Equirectangular { .. }
+            | Camera::Fisheye { .. } => true,
+            Camera::LensSystem { .. } | Camera::Stereo { .. } => false,
+        }
+    }
+
+    /// Checks if the camera rays depend on the wavelength, which means that
+    /// each ray can only carry a single wavelength.
+    pub fn is_dispersive(&self) -> bool {
+        match *self {
+            Camera::LensSystem { ref lens, .. } => lens.is_dispersive(),
+            Camera::Perspective { .. }
+            | Camera::Orthographic { .. }
+            | Camera::Equirectangular { .. }
+            | Camera::Fisheye { .. }
+            | Camera::Stereo { .. } => false,
+        }
+    }
+
+    /// The size of the film that the image is rendered into, which is
+    /// larger than the image when each eye is saved to its own file.
+    pub fn film_size(&self, image_size: Vector2<u32>) -> Vector2<u32> {
+        match *self {
+            Camera::Stereo { ref stereo, .. } => stereo.layout().film_size(image_size),
+            _ => image_size,
+        }
+    }
+
+    /// The stereo layout, if the camera has one view per eye.
+    pub fn stereo_layout(&self) -> Option<StereoLayout> {
+        match *self {
+            Camera::Stereo { ref stereo, .. } => Some(stereo.layout()),
+            _ => None,
+        }
+    }
+
+    /// Picks a random time while the shutter is open.
+    pub fn sample_time(&self, rng: &mut impl Rng) -> f32 {
+        self.transform().sample_time(rng)
+    }
+
+    /// How the film is divided between the views of the camera.
+    pub fn film_views(&self) -> FilmViews {
+        match self.stereo_layout() {
+            Some(StereoLayout::SideBySide | StereoLayout::Separate) => FilmViews::SideBySide,
+            Some(StereoLayout::TopBottom) => FilmViews::TopBottom,
+            None => FilmViews::Single,
+        }
+    }
+
+    fn transform(&self) -> &CameraTransform {
+        match *self {
+            Camera::Perspective { ref transform, .. }
+            | Camera::Orthographic { ref transform, .. }
+            | Camera::Equirectangular { ref transform }
+            | Camera::Fisheye { ref transform, .. }
+            | Camera::LensSystem { ref transform, .. }
+            | Camera::Stereo { ref transform, .. } => transform,
+        }
+    }
+}
+
+/// Creates a ray from `origin` to `target`, unless something is in the way.
+fn unoccluded_ray(origin: Point3<f32>, target: Point3<f32>, world: &World) -> Option<Ray3<f32>> {
+    let direction = target - origin;
+    let distance = direction.magnitude();
+    let ray = Ray::new(origin, direction / distance);
+    if let Some(hit) = world.intersect(ray) {
+        if hit.distance < distance - DIST_EPSILON {
+            return None;
+        }
+    }
+
+    Some(ray)
+}
+
+/// Transforms a ray from camera space to world space.
+fn transformed_ray(
+    origin: Point3<f32>,
+    direction: Vector3<f32>,
+    transform: Matrix4<f32>,
+) -> Ray3<f32> {
+    let ray = Ray::new(origin, direction).transform(transform);
+    Ray::new(ray.origin, ray.direction.normalize())
+}
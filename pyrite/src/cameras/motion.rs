@@ -0,0 +1,133 @@
+use std::error::Error;
+
+use cgmath::{InnerSpace, Matrix3, Matrix4, Quaternion, Vector3, VectorSpace};
+use rand::Rng;
+
+use crate::project::eval_context::{EvalContext, Evaluate};
+
+/// The placement of a camera, which may change while the shutter is open.
+pub(crate) struct CameraTransform {
+    /// Sorted by time. A camera that doesn't move has a single keyframe.
+    keyframes: Vec<Keyframe>,
+    shutter_open: f32,
+    shutter_close: f32,
+}
+
+impl CameraTransform {
+    pub fn from_project(
+        transform: &crate::project::Transform,
+        motion: Option<crate::project::CameraMotion>,
+        eval_context: EvalContext,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut keyframes = vec![Keyframe::new(0.0, transform.evaluate(eval_context)?)];
+
+        let (shutter_open, shutter_close) = if let Some(motion) = motion {
+            for keyframe in motion.keyframes {
+                keyframes.push(Keyframe::new(
+                    keyframe.time,
+                    keyframe.transform.evaluate(eval_context)?,
+                ));
+            }
+
+            (
+                motion.shutter_open.unwrap_or(0.0),
+                motion.shutter_close.unwrap_or(1.0),
+            )
+        } else {
+            (0.0, 0.0)
+        };
+
+        if shutter_close < shutter_open {
+            return Err(format!(
+                "the shutter closes at {}, before it opens at {}",
+                shutter_close, shutter_open
+            )
+            .into());
+        }
+
+        keyframes.sort_by(|a, b| a.time.total_cmp(&b.time));
+
+        Ok(CameraTransform {
+            keyframes,
+            shutter_open,
+            shutter_close,
+        })
+    }
+
+    /// Picks a random time while the shutter is open.
+    pub fn sample_time(&self, rng: &mut impl Rng) -> f32 {
+        if self.shutter_close > self.shutter_open {
+            rng.gen_range(self.shutter_open..self.shutter_close)
+        } else {
+            self.shutter_open
+        }
+    }
+
+    /// The transform at `time`, interpolated between the nearest keyframes.
+    pub fn at(&self, time: f32) -> Matrix4<f32> {
+        let next_index = self
+            .keyframes
+            .partition_point(|keyframe| keyframe.time <= time);
+
+        let (previous, next) = match (
+            next_index
+                .checked_sub(1)
+                .map(|index| &self.keyframes[index]),
+            self.keyframes.get(next_index),
+        ) {
+            (Some(previous), Some(next)) => (previous, next),
+            (Some(keyframe), None) | (None, Some(keyframe)) => return keyframe.matrix,
+            (None, None) => unreachable!("the camera should have at least one keyframe"),
+        };
+
+        let amount = (time - previous.time) / (next.time - previous.time);
+
+        // Taking the shortest way between the rotations.
+        let next_rotation = if previous.rotation.dot(next.rotation) < 0.0 {
+            -next.rotation
+        } else {
+            next.rotation
+        };
+
+        let translation = previous.translation.lerp(next.translation, amount);
+        let rotation = previous.rotation.nlerp(next_rotation, amount);
+        let scale = previous.scale.lerp(next.scale, amount);
+
+        Matrix4::from_translation(translation)
+            * Matrix4::from(rotation)
+            * Matrix4::from_nonuniform_scale(scale.x, scale.y, scale.z)
+    }
+}
+
+/// A camera transform at a point in time, split into parts that can be
+/// interpolated. Shearing is not preserved.
+struct Keyframe {
+    time: f32,
+    matrix: Matrix4<f32>,
+    translation: Vector3<f32>,
+    rotation: Quaternion<f32>,
+    scale: Vector3<f32>,
+}
+
+impl Keyframe {
+    fn new(time: f32, matrix: Matrix4<f32>) -> Self {
+        let scale = Vector3::new(
+            matrix.x.truncate().magnitude(),
+            matrix.y.truncate().magnitude(),
+            matrix.z.truncate().magnitude(),
+        );
+        let rotation = Matrix3::from_cols(
+            matrix.x.truncate() / scale.x,
+            matrix.y.truncate() / scale.y,
+            matrix.z.truncate() / scale.z,
+        );
+
+        Keyframe {
+            time,
+            matrix,
+            translation: matrix.w.truncate(),
+            rotation: Quaternion::from(rotation).normalize(),
+            scale,
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use std::{error::Error, f32::consts};
+
+use cgmath::{EuclideanSpace, Point2, Point3, Vector2, Vector3};
+
+/// A pair of eyes that are rendered next to each other in the same film.
+pub(crate) struct Stereo {
+    projection: StereoProjection,
+    layout: StereoLayout,
+    /// Half of the distance between the eyes.
+    eye_offset: f32,
+    /// The distance where the eyes' views meet. It's infinite when the eyes
+    /// look in parallel.
+    convergence_distance: f32,
+    /// The size of the film, in pixels.
+    film_size: Vector2<f32>,
+}
+
+impl Stereo {
+    pub fn new(
+        projection: StereoProjection,
+        layout: StereoLayout,
+        interocular_distance: f32,
+        convergence_distance: Option<f32>,
+        image_size: Vector2<u32>,
+    ) -> Result<Self, Box<dyn Error>> {
+        if interocular_distance < 0.0 {
+            return Err("the interocular distance can't be negative".into());
+        }
+
+        let convergence_distance = convergence_distance.unwrap_or(f32::INFINITY);
+        if convergence_distance <= 0.0 {
+            return Err("the convergence distance must be positive".into());
+        }
+
+        let film_size = layout.film_size(image_size);
+
+        Ok(Stereo {
+            projection,
+            layout,
+            eye_offset: interocular_distance * 0.5,
+            convergence_distance,
+            film_size: Vector2::new(film_size.x as f32, film_size.y as f32),
+        })
+    }
+
+    pub fn layout(&self) -> StereoLayout {
+        self.layout
+    }
+
+    /// Creates a ray in camera space towards a position in the view, from
+    /// the eye that covers that part of the view.
+    pub fn ray_towards(&self, target: Point2<f32>) -> Option<(Point3<f32>, Vector3<f32>)> {
+        let (eye, target) = self.eye_position(target);
+
+        let (direction, side) = match self.projection {
+            StereoProjection::Perspective { view_plane } => (
+                Vector3::new(target.x / view_plane, -target.y / view_plane, -1.0),
+                Vector3::unit_x(),
+            ),
+            StereoProjection::Omnidirectional => {
+                let longitude = target.x * consts::PI;
+                let latitude = -target.y * consts::PI;
+
+                if latitude.abs() > consts::FRAC_PI_2 {
+                    return None;
+                }
+
+                // The eyes are on a circle and turn with the view direction.
+                (
+                    Vector3::new(
+                        latitude.cos() * longitude.sin(),
+                        latitude.sin(),
+                        -latitude.cos() * longitude.cos(),
+                    ),
+                    Vector3::new(longitude.cos(), 0.0, longitude.sin()),
+                )
+            }
+        };
+
+        let origin = Point3::from_vec(side * eye.side() * self.eye_offset);
+
+        // The eyes are turned towards the same point at the convergence
+        // distance.
+        let direction = direction - origin.to_vec() / self.convergence_distance;
+
+        Some((origin, direction))
+    }
+
+    /// Finds which eye sees `target`, and where it is in that eye's own
+    /// view.
+    fn eye_position(&self, target: Point2<f32>) -> (Eye, Point2<f32>) {
+        let half_film_size = self.film_size * 0.5;
+        let film_scale = half_film_size.x.max(half_film_size.y);
+        let pixel = target * film_scale + half_film_size;
+
+        let (eye, eye_pixel, eye_size) = match self.layout {
+            StereoLayout::SideBySide | StereoLayout::Separate => {
+                let eye_size = Vector2::new(half_film_size.x, self.film_size.y);
+                if pixel.x < half_film_size.x {
+                    (Eye::Left, pixel, eye_size)
+                } else {
+                    (
+                        Eye::Right,
+                        pixel - Vector2::new(half_film_size.x, 0.0),
+                        eye_size,
+                    )
+                }
+            }
+            StereoLayout::TopBottom => {
+                let eye_size = Vector2::new(self.film_size.x, half_film_size.y);
+                if pixel.y < half_film_size.y {
+                    (Eye::Left, pixel, eye_size)
+                } else {
+                    (
+                        Eye::Right,
+                        pixel - Vector2::new(0.0, half_film_size.y),
+                        eye_size,
+                    )
+                }
+            }
+        };
+
+        let half_eye_size = eye_size * 0.5;
+        let eye_scale = half_eye_size.x.max(half_eye_size.y);
+
+        (eye, (eye_pixel - half_eye_size) / eye_scale)
+    }
+}
+
+pub(crate) enum StereoProjection {
+    Perspective {
+        view_plane: f32,
+    },
+    /// Omnidirectional stereo, for equirectangular VR panoramas.
+    Omnidirectional,
+}
+
+/// How the images of the eyes are placed in the output.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum StereoLayout {
+    /// The left eye is on the left side of the image.
+    SideBySide,
+    /// The left eye is on the top half of the image.
+    TopBottom,
+    /// Each eye is saved to its own file, with the image size of each file.
+    Separate,
+}
+
+impl StereoLayout {
+    /// The size of the film that both eyes are rendered into.
+    pub fn film_size(self, image_size: Vector2<u32>) -> Vector2<u32> {
+        match self {
+            StereoLayout::SideBySide | StereoLayout::TopBottom => image_size,
+            StereoLayout::Separate => Vector2::new(image_size.x * 2, image_size.y),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Eye {
+    Left,
+    Right,
+}
+
+impl Eye {
+    /// The direction of the eye from the center of the camera, along its X
+    /// axis.
+    fn side(self) -> f32 {
+        match self {
+            Eye::Left => -1.0,
+            Eye::Right => 1.0,
+        }
+    }
+}
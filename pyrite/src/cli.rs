@@ -0,0 +1,118 @@
+use std::{error::Error, str::FromStr};
+
+use crate::project::Overrides;
+
+pub(crate) fn print_usage(name: &str) {
+    eprintln!("usage: {} [options] project_file", name);
+    eprintln!(
+        "
+options:
+    --resume                    continue rendering from the last checkpoint
+    --verbose                   print diagnostics, such as grain contention
+    --resolution WIDTHxHEIGHT   override the image size
+    --pixel-samples N           override the number of samples per pixel
+    --threads N                 override the number of render threads
+    --time-limit SECONDS        render in passes until the time is up
+    --output FILE               override the output image file
+    --renderer TYPE             override the renderer type, such as simple,
+                                bidirectional or photon_mapping
+    --set KEY=VALUE             set a global Lua variable before the project
+                                file runs
+    --help                      show this message"
+    );
+}
+
+pub(crate) struct Arguments {
+    pub project_path: String,
+    pub resume: bool,
+    pub verbose: bool,
+    pub overrides: Overrides,
+}
+
+pub(crate) enum Command {
+    Render(Arguments),
+    Help,
+}
+
+pub(crate) fn parse(mut args: impl Iterator<Item = String>) -> Result<Command, Box<dyn Error>> {
+    let mut project_path = None;
+    let mut resume = false;
+    let mut verbose = false;
+    let mut overrides = Overrides::default();
+
+    while let Some(arg) = args.next() {
+        // Both "--flag value" and "--flag=value" are accepted.
+        let (flag, mut value) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => {
+                (flag.to_owned(), Some(value.to_owned()))
+            }
+            _ => (arg, None),
+        };
+
+        let mut value = |name: &str| {
+            value
+                .take()
+                .or_else(|| args.next())
+                .ok_or_else(|| format!("missing value for {}", name))
+        };
+
+        match &*flag {
+            "--help" | "-h" => return Ok(Command::Help),
+            "--resume" => resume = true,
+            "--verbose" | "-v" => verbose = true,
+            "--resolution" => {
+                let resolution = value(&flag)?;
+                let (width, height) = resolution
+                    .split_once('x')
+                    .ok_or_else(|| format!("expected WIDTHxHEIGHT, got {}", resolution))?;
+                overrides.width = Some(parse_value(&flag, width)?);
+                overrides.height = Some(parse_value(&flag, height)?);
+            }
+            "--pixel-samples" => {
+                overrides.pixel_samples = Some(parse_value(&flag, &value(&flag)?)?)
+            }
+            "--threads" => overrides.threads = Some(parse_value(&flag, &value(&flag)?)?),
+            "--time-limit" => overrides.time_limit = Some(parse_value(&flag, &value(&flag)?)?),
+            "--output" => {
+                // The project file resolves paths relative to itself, while
+                // this one should be relative to the working directory.
+                let path = std::env::current_dir()?.join(value(&flag)?);
+                let path = path
+                    .to_str()
+                    .ok_or_else(|| format!("{} is not valid UTF-8", path.display()))?;
+                overrides.file = Some(path.to_owned());
+            }
+            "--renderer" => overrides.renderer = Some(value(&flag)?),
+            "--set" => {
+                let assignment = value(&flag)?;
+                let (key, value) = assignment
+                    .split_once('=')
+                    .ok_or_else(|| format!("expected KEY=VALUE, got {}", assignment))?;
+                overrides.globals.push((key.to_owned(), value.to_owned()));
+            }
+            _ if flag.starts_with('-') => return Err(format!("unknown option {}", flag).into()),
+            _ if project_path.is_none() => project_path = Some(flag),
+            _ => return Err(format!("unexpected argument {}", flag).into()),
+        }
+    }
+
+    let project_path = project_path.ok_or("missing project file")?;
+
+    Ok(Command::Render(Arguments {
+        project_path,
+        resume,
+        verbose,
+        overrides,
+    }))
+}
+
+fn parse_value<T>(flag: &str, value: &str) -> Result<T, Box<dyn Error>>
+where
+    T: FromStr,
+    T::Err: Error + 'static,
+{
+    value
+        .trim()
+        .parse()
+        .map_err(|error| format!("invalid value {:?} for {}: {}", value, flag, error).into())
+}
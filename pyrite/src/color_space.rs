@@ -0,0 +1,329 @@
+use cgmath::{Matrix3, SquareMatrix, Vector3};
+
+use palette::Xyz;
+
+use crate::project::{ColorSpace, Illuminant};
+
+/// The white point of the XYZ colors from the film.
+pub(crate) const D65: [f32; 2] = [0.3127, 0.3290];
+
+/// The white point of the ACES color spaces.
+const D60: [f32; 2] = [0.32168, 0.33767];
+
+/// The white point of the ICC profile connection space, as XYZ.
+const D50_XYZ: [f32; 3] = [0.9642, 1.0, 0.8249];
+
+/// An RGB color space with its primaries, white point and transfer function.
+#[derive(Clone, Copy)]
+pub(crate) struct RgbSpace {
+    pub name: &'static str,
+    /// The red, green and blue primaries, as xy chromaticities.
+    pub primaries: [[f32; 2]; 3],
+    pub white: [f32; 2],
+    pub transfer: TransferFunction,
+}
+
+impl RgbSpace {
+    /// Returns the RGB space for a color space, or `None` for XYZ.
+    pub fn from_color_space(color_space: ColorSpace) -> Option<Self> {
+        let space = match color_space {
+            ColorSpace::Srgb => RgbSpace {
+                name: "sRGB",
+                primaries: [[0.64, 0.33], [0.30, 0.60], [0.15, 0.06]],
+                white: D65,
+                transfer: TransferFunction::Srgb,
+            },
+            ColorSpace::DisplayP3 => RgbSpace {
+                name: "Display P3",
+                primaries: [[0.680, 0.320], [0.265, 0.690], [0.150, 0.060]],
+                white: D65,
+                transfer: TransferFunction::Srgb,
+            },
+            ColorSpace::Rec2020 => RgbSpace {
+                name: "Rec. 2020",
+                primaries: [[0.708, 0.292], [0.170, 0.797], [0.131, 0.046]],
+                white: D65,
+                transfer: TransferFunction::Rec2020,
+            },
+            ColorSpace::AdobeRgb => RgbSpace {
+                name: "Adobe RGB (1998)",
+                primaries: [[0.64, 0.33], [0.21, 0.71], [0.15, 0.06]],
+                white: D65,
+                transfer: TransferFunction::Gamma(563.0 / 256.0),
+            },
+            ColorSpace::AcesCg => RgbSpace {
+                name: "ACEScg",
+                primaries: [[0.713, 0.293], [0.165, 0.830], [0.128, 0.044]],
+                white: D60,
+                transfer: TransferFunction::Linear,
+            },
+            ColorSpace::Xyz => return None,
+        };
+
+        Some(space)
+    }
+
+    /// The matrix that converts linear RGB to XYZ, relative to the space's
+    /// own white point.
+    pub fn rgb_to_xyz_matrix(&self) -> Matrix3<f32> {
+        let [red, green, blue] = self.primaries.map(xy_to_xyz);
+        let primaries = Matrix3::from_cols(red, green, blue);
+        let scale = primaries
+            .invert()
+            .expect("the primaries should be linearly independent")
+            * xy_to_xyz(self.white);
+
+        Matrix3::from_cols(red * scale.x, green * scale.y, blue * scale.z)
+    }
+
+    /// The matrix that converts XYZ colors from the film to linear RGB.
+    pub fn film_to_rgb_matrix(&self) -> Matrix3<f32> {
+        self.rgb_to_xyz_matrix()
+            .invert()
+            .expect("the RGB to XYZ matrix should be invertible")
+            * ChromaticAdaptation::Bradford.matrix(xy_to_xyz(D65), xy_to_xyz(self.white))
+    }
+
+    /// Converts an XYZ color from the film to linear RGB, without clamping.
+    pub fn film_to_rgb(&self, matrix: &Matrix3<f32>, color: Xyz) -> [f32; 3] {
+        (matrix * Vector3::new(color.x, color.y, color.z)).into()
+    }
+
+    /// Describes the color space as an ICC version 4 display profile.
+    pub fn icc_profile(&self) -> Vec<u8> {
+        let to_pcs = ChromaticAdaptation::Bradford.matrix(xy_to_xyz(self.white), D50_XYZ.into());
+        let colorants = to_pcs * self.rgb_to_xyz_matrix();
+
+        let tags = [
+            (*b"desc", multi_localized_text(self.name)),
+            (*b"cprt", multi_localized_text("No copyright, use freely")),
+            (*b"wtpt", xyz_tag(D50_XYZ.into())),
+            (*b"chad", matrix_tag(to_pcs)),
+            (*b"rXYZ", xyz_tag(colorants.x)),
+            (*b"gXYZ", xyz_tag(colorants.y)),
+            (*b"bXYZ", xyz_tag(colorants.z)),
+            (*b"rTRC", self.transfer.icc_curve()),
+            (*b"gTRC", self.transfer.icc_curve()),
+            (*b"bTRC", self.transfer.icc_curve()),
+        ];
+
+        let header_size = 128 + 4 + tags.len() * 12;
+        let mut data = Vec::new();
+        let mut table = Vec::new();
+        for (signature, tag) in &tags {
+            let offset = header_size + data.len();
+            table.extend_from_slice(signature);
+            table.extend_from_slice(&(offset as u32).to_be_bytes());
+            table.extend_from_slice(&(tag.len() as u32).to_be_bytes());
+
+            data.extend_from_slice(tag);
+            // Each tag starts at a multiple of 4 bytes.
+            data.resize(data.len().next_multiple_of(4), 0);
+        }
+
+        let mut profile = Vec::with_capacity(header_size + data.len());
+        profile.extend_from_slice(&((header_size + data.len()) as u32).to_be_bytes());
+        profile.extend_from_slice(&[0; 4]); // Preferred CMM
+        profile.extend_from_slice(&0x0430_0000u32.to_be_bytes()); // Version 4.3
+        profile.extend_from_slice(b"mntr");
+        profile.extend_from_slice(b"RGB ");
+        profile.extend_from_slice(b"XYZ ");
+        profile.extend_from_slice(&[0; 12]); // Creation date
+        profile.extend_from_slice(b"acsp");
+        profile.extend_from_slice(&[0; 24]); // Platform, flags, device and attributes
+        profile.extend_from_slice(&[0; 4]); // Perceptual rendering intent
+        for component in D50_XYZ {
+            profile.extend_from_slice(&s15_fixed16(component));
+        }
+        profile.extend_from_slice(&[0; 4]); // Creator
+        profile.extend_from_slice(&[0; 16]); // Profile ID
+        profile.resize(128, 0);
+
+        profile.extend_from_slice(&(tags.len() as u32).to_be_bytes());
+        profile.extend_from_slice(&table);
+        profile.extend_from_slice(&data);
+
+        profile
+    }
+}
+
+/// Converts between encoded and linear RGB values.
+#[derive(Clone, Copy)]
+pub(crate) enum TransferFunction {
+    Linear,
+    Srgb,
+    Rec2020,
+    Gamma(f32),
+}
+
+impl TransferFunction {
+    /// Encodes a linear value in the 0..1 range.
+    pub fn encode(self, value: f32) -> f32 {
+        match self {
+            TransferFunction::Linear => value,
+            TransferFunction::Srgb => {
+                if value <= 0.0031308 {
+                    value * 12.92
+                } else {
+                    1.055 * value.powf(1.0 / 2.4) - 0.055
+                }
+            }
+            TransferFunction::Rec2020 => {
+                if value < REC2020_BETA {
+                    value * 4.5
+                } else {
+                    REC2020_ALPHA * value.powf(0.45) - (REC2020_ALPHA - 1.0)
+                }
+            }
+            TransferFunction::Gamma(gamma) => value.powf(1.0 / gamma),
+        }
+    }
+
+    /// The decoding function as an ICC parametric curve.
+    fn icc_curve(self) -> Vec<u8> {
+        let (function_type, parameters) = match self {
+            TransferFunction::Linear => (0, vec![1.0]),
+            TransferFunction::Srgb => (
+                3,
+                vec![2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045],
+            ),
+            TransferFunction::Rec2020 => (
+                3,
+                vec![
+                    1.0 / 0.45,
+                    1.0 / REC2020_ALPHA,
+                    (REC2020_ALPHA - 1.0) / REC2020_ALPHA,
+                    1.0 / 4.5,
+                    REC2020_BETA * 4.5,
+                ],
+            ),
+            TransferFunction::Gamma(gamma) => (0, vec![gamma]),
+        };
+
+        let mut tag = Vec::new();
+        tag.extend_from_slice(b"para");
+        tag.extend_from_slice(&[0; 4]);
+        tag.extend_from_slice(&(function_type as u16).to_be_bytes());
+        tag.extend_from_slice(&[0; 2]);
+        for parameter in parameters {
+            tag.extend_from_slice(&s15_fixed16(parameter));
+        }
+        tag
+    }
+}
+
+const REC2020_ALPHA: f32 = 1.099_296_8;
+const REC2020_BETA: f32 = 0.018_053_97;
+
+pub(crate) fn xy_to_xyz([x, y]: [f32; 2]) -> Vector3<f32> {
+    Vector3::new(x / y, 1.0, (1.0 - x - y) / y)
+}
+
+/// A transform that adapts colors from one white point to another, by
+/// scaling the responses of the eye's cones.
+#[derive(Clone, Copy, Default)]
+pub(crate) enum ChromaticAdaptation {
+    #[default]
+    Bradford,
+    Cat02,
+    VonKries,
+}
+
+impl ChromaticAdaptation {
+    pub fn from_project(project: crate::project::ChromaticAdaptation) -> Self {
+        match project {
+            crate::project::ChromaticAdaptation::Bradford => ChromaticAdaptation::Bradford,
+            crate::project::ChromaticAdaptation::Cat02 => ChromaticAdaptation::Cat02,
+            crate::project::ChromaticAdaptation::VonKries => ChromaticAdaptation::VonKries,
+        }
+    }
+
+    /// The matrix that adapts XYZ colors from one white point to another,
+    /// both given as XYZ. The brightness of the white points is ignored.
+    pub fn matrix(self, from: Vector3<f32>, to: Vector3<f32>) -> Matrix3<f32> {
+        let cone_response = self.cone_response();
+        let from = cone_response * (from / from.y);
+        let to = cone_response * (to / to.y);
+        let scale =
+            Matrix3::from_diagonal(Vector3::new(to.x / from.x, to.y / from.y, to.z / from.z));
+
+        cone_response
+            .invert()
+            .expect("the cone response matrix should be invertible")
+            * scale
+            * cone_response
+    }
+
+    /// The matrix from XYZ to cone responses, written as columns.
+    fn cone_response(self) -> Matrix3<f32> {
+        match self {
+            ChromaticAdaptation::Bradford => Matrix3::new(
+                0.8951, -0.7502, 0.0389, 0.2664, 1.7135, -0.0685, -0.1614, 0.0367, 1.0296,
+            ),
+            ChromaticAdaptation::Cat02 => Matrix3::new(
+                0.7328, -0.7036, 0.0030, 0.4296, 1.6975, 0.0136, -0.1624, 0.0061, 0.9834,
+            ),
+            // The Hunt-Pointer-Estévez matrix, normalized to D65.
+            ChromaticAdaptation::VonKries => Matrix3::new(
+                0.40024, -0.2263, 0.0, 0.7076, 1.16532, 0.0, -0.08081, 0.0457, 0.91822,
+            ),
+        }
+    }
+}
+
+/// The white point of a standard illuminant, for the CIE 1931 2° observer.
+pub(crate) fn illuminant_white(illuminant: Illuminant) -> [f32; 2] {
+    match illuminant {
+        Illuminant::A => [0.44757, 0.40745],
+        Illuminant::D50 => [0.34567, 0.3585],
+        Illuminant::D55 => [0.33242, 0.34743],
+        Illuminant::D65 => D65,
+        Illuminant::D75 => [0.29902, 0.31485],
+        Illuminant::E => [1.0 / 3.0, 1.0 / 3.0],
+        Illuminant::F2 => [0.37208, 0.37529],
+        Illuminant::F7 => [0.31292, 0.32933],
+        Illuminant::F11 => [0.38052, 0.37713],
+    }
+}
+
+fn s15_fixed16(value: f32) -> [u8; 4] {
+    ((value * 65536.0).round() as i32).to_be_bytes()
+}
+
+fn xyz_tag(xyz: Vector3<f32>) -> Vec<u8> {
+    let mut tag = Vec::new();
+    tag.extend_from_slice(b"XYZ ");
+    tag.extend_from_slice(&[0; 4]);
+    for component in [xyz.x, xyz.y, xyz.z] {
+        tag.extend_from_slice(&s15_fixed16(component));
+    }
+    tag
+}
+
+/// Writes the matrix in row major order.
+fn matrix_tag(matrix: Matrix3<f32>) -> Vec<u8> {
+    let mut tag = Vec::new();
+    tag.extend_from_slice(b"sf32");
+    tag.extend_from_slice(&[0; 4]);
+    for row in 0..3 {
+        for column in 0..3 {
+            tag.extend_from_slice(&s15_fixed16(matrix[column][row]));
+        }
+    }
+    tag
+}
+
+fn multi_localized_text(text: &str) -> Vec<u8> {
+    let text: Vec<u8> = text.encode_utf16().flat_map(u16::to_be_bytes).collect();
+
+    let mut tag = Vec::new();
+    tag.extend_from_slice(b"mluc");
+    tag.extend_from_slice(&[0; 4]);
+    tag.extend_from_slice(&1u32.to_be_bytes()); // Number of records
+    tag.extend_from_slice(&12u32.to_be_bytes()); // Record size
+    tag.extend_from_slice(b"enUS");
+    tag.extend_from_slice(&(text.len() as u32).to_be_bytes());
+    tag.extend_from_slice(&28u32.to_be_bytes()); // Offset to the text
+    tag.extend_from_slice(&text);
+    tag
+}
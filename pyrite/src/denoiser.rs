@@ -0,0 +1,173 @@
+use palette::Xyz;
+
+use crate::film::Film;
+
+/// The B3 spline kernel, which is applied with increasing gaps.
+const KERNEL: [f32; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+/// Keeps the color weight finite where the variance is zero.
+const VARIANCE_EPSILON: f32 = 1e-4;
+
+/// Below this, the albedo is too dark to separate it from the lighting.
+const MIN_ALBEDO: f32 = 1e-3;
+
+/// An edge-avoiding à-trous wavelet filter, guided by the first hit albedo
+/// and normal, and the estimated noise of each pixel. Based on "Edge-Avoiding
+/// À-Trous Wavelet Transform for fast Global Illumination Filtering"
+/// (Dammertz et al. 2010) and its variance guided variant in SVGF (Schied et
+/// al. 2017).
+#[derive(Clone, Copy)]
+pub(crate) struct Denoiser {
+    iterations: u32,
+    color_sigma: f32,
+    normal_sigma: f32,
+    albedo_sigma: f32,
+}
+
+impl Denoiser {
+    pub fn from_project(project: crate::project::Denoiser) -> Self {
+        Denoiser {
+            iterations: project.iterations.unwrap_or(5),
+            color_sigma: project.color_sigma.unwrap_or(4.0),
+            normal_sigma: project.normal_sigma.unwrap_or(0.3),
+            albedo_sigma: project.albedo_sigma.unwrap_or(0.1),
+        }
+    }
+
+    /// Filters the developed pixels of `film`. The guides have one value per
+    /// pixel, in the same order.
+    pub fn apply(&self, film: &Film, pixels: &mut [Xyz], albedo: &[Xyz], normals: &[[f32; 3]]) {
+        let width = film.width();
+        let height = film.height();
+
+        // The albedo is divided out before filtering and multiplied back in
+        // afterwards, to keep texture details sharp.
+        let albedo: Vec<[f32; 3]> = albedo.iter().map(|&color| color.into()).collect();
+        let mut colors: Vec<[f32; 3]> = pixels
+            .iter()
+            .zip(&albedo)
+            .map(|(&color, albedo)| demodulate(color.into(), albedo))
+            .collect();
+
+        // The relative error of each pixel is assumed to be the same for
+        // all color components. It's capped, since it's unknown for pixels
+        // that have only been exposed in one half of the film.
+        let mut variances: Vec<f32> = film
+            .developed_pixels()
+            .zip(&colors)
+            .map(|(spectrum, color)| {
+                let estimate = spectrum.estimate();
+                if estimate.brightness > 0.0 {
+                    let relative_error = (estimate.error / estimate.brightness).min(1.0);
+                    (relative_error * color[1]).powi(2)
+                } else {
+                    0.0
+                }
+            })
+            .collect();
+
+        let mut filtered_colors = colors.clone();
+        let mut filtered_variances = variances.clone();
+
+        for iteration in 0..self.iterations {
+            // Larger gaps would only reach outside the image.
+            let Some(step) = 1usize
+                .checked_shl(iteration)
+                .filter(|&step| step < width.max(height))
+            else {
+                break;
+            };
+
+            for y in 0..height {
+                for x in 0..width {
+                    let index = x + y * width;
+                    let color = colors[index];
+                    let normal = normals[index];
+                    let albedo_color = albedo[index];
+                    let color_scale =
+                        self.color_sigma.powi(2) * variances[index] + VARIANCE_EPSILON;
+
+                    let mut color_sum = [0.0; 3];
+                    let mut variance_sum = 0.0;
+                    let mut weight_sum = 0.0;
+
+                    for (dy, y_kernel) in KERNEL.into_iter().enumerate() {
+                        let Some(sample_y) = offset(y, dy, step, height) else {
+                            continue;
+                        };
+
+                        for (dx, x_kernel) in KERNEL.into_iter().enumerate() {
+                            let Some(sample_x) = offset(x, dx, step, width) else {
+                                continue;
+                            };
+
+                            let sample_index = sample_x + sample_y * width;
+                            let sample_color = colors[sample_index];
+
+                            let color_weight =
+                                (-(color[1] - sample_color[1]).powi(2) / color_scale).exp();
+                            let normal_weight = (-distance_squared(normal, normals[sample_index])
+                                / self.normal_sigma.powi(2))
+                            .exp();
+                            let albedo_weight =
+                                (-distance_squared(albedo_color, albedo[sample_index])
+                                    / self.albedo_sigma.powi(2))
+                                .exp();
+
+                            let weight =
+                                x_kernel * y_kernel * color_weight * normal_weight * albedo_weight;
+
+                            for (sum, component) in color_sum.iter_mut().zip(sample_color) {
+                                *sum += component * weight;
+                            }
+                            variance_sum += variances[sample_index] * weight * weight;
+                            weight_sum += weight;
+                        }
+                    }
+
+                    // The pixel itself always has a positive weight.
+                    filtered_colors[index] = color_sum.map(|sum| sum / weight_sum);
+                    filtered_variances[index] = variance_sum / (weight_sum * weight_sum);
+                }
+            }
+
+            std::mem::swap(&mut colors, &mut filtered_colors);
+            std::mem::swap(&mut variances, &mut filtered_variances);
+        }
+
+        for ((pixel, color), albedo) in pixels.iter_mut().zip(colors).zip(&albedo) {
+            *pixel = remodulate(color, albedo).into();
+        }
+    }
+}
+
+fn demodulate(color: [f32; 3], albedo: &[f32; 3]) -> [f32; 3] {
+    let mut result = color;
+    for (component, &albedo) in result.iter_mut().zip(albedo) {
+        if albedo > MIN_ALBEDO {
+            *component /= albedo;
+        }
+    }
+    result
+}
+
+fn remodulate(color: [f32; 3], albedo: &[f32; 3]) -> [f32; 3] {
+    let mut result = color;
+    for (component, &albedo) in result.iter_mut().zip(albedo) {
+        if albedo > MIN_ALBEDO {
+            *component *= albedo;
+        }
+    }
+    result
+}
+
+/// The pixel `kernel_index - 2` steps away from `center`, if it's within the
+/// image.
+fn offset(center: usize, kernel_index: usize, step: usize, size: usize) -> Option<usize> {
+    let position = (center + kernel_index * step).checked_sub(2 * step)?;
+    (position < size).then_some(position)
+}
+
+fn distance_squared(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a.into_iter().zip(b).map(|(a, b)| (a - b) * (a - b)).sum()
+}
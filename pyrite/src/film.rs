@@ -1,3 +1,10 @@
+use std::{
+    error::Error,
+    io::{self, Read, Write},
+    ops::Range,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
 use crossbeam::atomic::AtomicCell;
 
 use noisy_float::prelude::*;
@@ -6,6 +13,8 @@ use cgmath::{BaseNum, Point2, Vector2};
 
 use rand::Rng;
 
+use crate::pixel_filter::PixelFilter;
+
 pub struct Film {
     width: usize,
     height: usize,
@@ -14,7 +23,10 @@ pub struct Film {
     wavelength_start: f32,
     wavelength_width: f32,
     grains_per_wavelength: f32,
+    filter: PixelFilter,
+    views: FilmViews,
     grains: Vec<Grain>,
+    contention: AtomicU64,
 }
 
 impl Film {
@@ -23,6 +35,8 @@ impl Film {
         height: usize,
         grains_per_pixel: usize,
         wavelength_span: (f32, f32),
+        filter: PixelFilter,
+        views: FilmViews,
     ) -> Self {
         let length = width * height * grains_per_pixel;
         let (wavelength_start, wavelength_end) = wavelength_span;
@@ -36,7 +50,10 @@ impl Film {
             wavelength_start,
             wavelength_width,
             grains_per_wavelength: grains_per_pixel as f32 / wavelength_width,
+            filter,
+            views,
             grains: std::iter::repeat_with(Grain::new).take(length).collect(),
+            contention: AtomicU64::new(0),
         }
     }
 
@@ -48,6 +65,25 @@ impl Film {
         self.height
     }
 
+    pub fn grains_per_pixel(&self) -> usize {
+        self.grains_per_pixel
+    }
+
+    pub fn wavelength_span(&self) -> (f32, f32) {
+        (
+            self.wavelength_start,
+            self.wavelength_start + self.wavelength_width,
+        )
+    }
+
+    /// The center wavelength of each grain in a pixel, in order.
+    pub fn grain_wavelengths(&self) -> impl Iterator<Item = f32> + '_ {
+        let grain_width = self.wavelength_width / self.grains_per_pixel as f32;
+
+        (0..self.grains_per_pixel)
+            .map(move |grain| self.wavelength_start + (grain as f32 + 0.5) * grain_width)
+    }
+
     pub fn get_pixel(&self, position: Point2<usize>) -> Option<&[Grain]> {
         if position.x >= self.width || position.y >= self.height {
             return None;
@@ -57,10 +93,6 @@ impl Film {
         Some(&self.grains[index..index + self.grains_per_pixel])
     }
 
-    pub fn get_pixel_f(&self, position: Point2<f32>) -> Option<&[Grain]> {
-        self.get_pixel(self.aspect_ratio.to_pixel(position)?)
-    }
-
     pub fn sample_wavelength<R: Rng>(&self, rng: &mut R) -> f32 {
         rng.gen_range(self.wavelength_start..self.wavelength_start + self.wavelength_width)
     }
@@ -87,19 +119,113 @@ impl Film {
     }
 
     pub fn expose(&self, position: Point2<f32>, sample: Sample) {
-        let grain_index = self.wavelength_to_grain(sample.wavelength);
+        if let Some(film_position) = self.aspect_ratio.to_film(position) {
+            self.splat(
+                film_position,
+                self.wavelength_to_grain(sample.wavelength),
+                sample.brightness,
+                sample.weight,
+                half_for(position),
+            );
+        }
+    }
+
+    /// Exposes a specific grain, for films that store other data than
+    /// spectra.
+    pub fn expose_grain(&self, position: Point2<f32>, grain: usize, value: f32, weight: f32) {
+        if let Some(film_position) = self.aspect_ratio.to_film(position) {
+            self.splat(film_position, grain, value, weight, half_for(position));
+        }
+    }
 
-        if let Some(pixel) = self.get_pixel_f(position) {
-            pixel[grain_index].expose(sample.brightness, sample.weight);
+    /// Adds light that was traced from a light source to `position`,
+    /// without counting it as a sample. The samples come from the camera, so
+    /// the brightness has to be scaled to how densely the camera samples
+    /// that part of the film. `origin` is the position of the camera sample
+    /// that the light path belongs to, which decides the half of the film.
+    pub fn expose_light(&self, position: Point2<f32>, origin: Point2<f32>, sample: Sample) {
+        if let Some(film_position) = self.aspect_ratio.to_film(position) {
+            let half = half_for(origin);
+            self.splat_with(
+                film_position,
+                self.wavelength_to_grain(sample.wavelength),
+                |grain, filter_weight| {
+                    grain.increment(sample.brightness * filter_weight, 0.0, half)
+                },
+            );
         }
     }
 
     pub fn get_pixel_ref_f(&self, position: Point2<f32>) -> Option<DetachedPixel> {
         Some(DetachedPixel {
-            grains: self.get_pixel_f(position)?,
+            film: self,
+            position: self.aspect_ratio.to_film(position)?,
+            half: half_for(position),
         })
     }
 
+    /// Exposes the grain in each pixel that's covered by the filter around
+    /// `position`, which is in pixel units.
+    fn splat(&self, position: Point2<f32>, grain: usize, value: f32, weight: f32, half: usize) {
+        self.splat_with(position, grain, |grain, filter_weight| {
+            grain.expose(value, weight * filter_weight, half)
+        });
+    }
+
+    /// Calls `expose` with the grain in each pixel that's covered by the
+    /// filter, and the filter weight. `expose` returns the number of retries,
+    /// which are counted as contention.
+    fn splat_with(&self, position: Point2<f32>, grain: usize, expose: impl Fn(&Grain, f32) -> u64) {
+        let radius = self.filter.radius();
+        let (columns, rows) = self.view_bounds(position);
+        let columns = pixel_range(position.x, radius, columns);
+
+        for y in pixel_range(position.y, radius, rows) {
+            let y_weight = self.filter.evaluate(y as f32 + 0.5 - position.y);
+            if y_weight == 0.0 {
+                continue;
+            }
+
+            for x in columns.clone() {
+                let filter_weight = y_weight * self.filter.evaluate(x as f32 + 0.5 - position.x);
+                if filter_weight == 0.0 {
+                    continue;
+                }
+
+                let index = (x + y * self.width) * self.grains_per_pixel + grain;
+                let retries = expose(&self.grains[index], filter_weight);
+
+                if retries > 0 {
+                    self.contention.fetch_add(retries, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    /// The columns and rows of the view that `position` is in. The filter
+    /// doesn't reach past them, so the views don't bleed into each other.
+    fn view_bounds(&self, position: Point2<f32>) -> (Range<usize>, Range<usize>) {
+        // The middle pixel is shared by both halves if the size is odd.
+        let half = |center: f32, size: usize| {
+            if center < size as f32 * 0.5 {
+                0..(size + 1) / 2
+            } else {
+                size / 2..size
+            }
+        };
+
+        match self.views {
+            FilmViews::Single => (0..self.width, 0..self.height),
+            FilmViews::SideBySide => (half(position.x, self.width), 0..self.height),
+            FilmViews::TopBottom => (0..self.width, half(position.y, self.height)),
+        }
+    }
+
+    /// Estimates the brightness and error of a pixel.
+    pub fn estimate_pixel(&self, position: Point2<usize>) -> Option<PixelEstimate> {
+        self.get_pixel(position).map(PixelEstimate::new)
+    }
+
     pub fn to_pixel_sample(&self, sample: &Sample) -> PixelSample {
         PixelSample {
             value: sample.brightness,
@@ -108,61 +234,263 @@ impl Film {
         }
     }
 
+    /// The number of times a grain had to be updated again, because another
+    /// thread changed it at the same time.
+    pub fn contention_events(&self) -> u64 {
+        self.contention.load(Ordering::Relaxed)
+    }
+
     pub fn developed_pixels(&self) -> DevelopedPixels<'_> {
         DevelopedPixels::new(self)
     }
+
+    /// Writes the film settings, the render settings and the raw grain
+    /// data, so the film can be restored and exposed further. `samples` is
+    /// the number of finished samples per pixel.
+    pub fn write_checkpoint(
+        &self,
+        settings: &CheckpointSettings,
+        samples: u32,
+        mut writer: impl Write,
+    ) -> io::Result<()> {
+        writer.write_all(CHECKPOINT_MAGIC)?;
+        writer.write_all(&CHECKPOINT_VERSION.to_le_bytes())?;
+        writer.write_all(&(self.width as u64).to_le_bytes())?;
+        writer.write_all(&(self.height as u64).to_le_bytes())?;
+        writer.write_all(&(self.grains_per_pixel as u64).to_le_bytes())?;
+        writer.write_all(&self.wavelength_start.to_le_bytes())?;
+        writer.write_all(&self.wavelength_width.to_le_bytes())?;
+        write_string(&mut writer, &format!("{:?}", self.filter))?;
+        write_string(&mut writer, &settings.renderer)?;
+        writer.write_all(&settings.pixel_samples.to_le_bytes())?;
+        writer.write_all(&settings.spectrum_samples.to_le_bytes())?;
+        writer.write_all(&samples.to_le_bytes())?;
+
+        self.write_grains(writer)
+    }
+
+    /// Writes the raw grain data, without any settings.
+    pub fn write_grains(&self, mut writer: impl Write) -> io::Result<()> {
+        for half in self.grains.iter().flat_map(|grain| &grain.halves) {
+            let GrainData {
+                accumulator,
+                weight,
+            } = half.load();
+
+            writer.write_all(&accumulator.raw().to_le_bytes())?;
+            writer.write_all(&weight.raw().to_le_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the grain data with the data from a checkpoint, and returns
+    /// the number of finished samples per pixel. The checkpoint has to be
+    /// made from a film with the same settings, and a renderer with the same
+    /// settings or more pixel samples.
+    pub fn read_checkpoint(
+        &self,
+        settings: &CheckpointSettings,
+        mut reader: impl Read,
+    ) -> Result<u32, Box<dyn Error>> {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != CHECKPOINT_MAGIC {
+            return Err("the file is not a film checkpoint".into());
+        }
+
+        let version = u32::from_le_bytes(read_bytes(&mut reader)?);
+        if version != CHECKPOINT_VERSION {
+            return Err(format!("unsupported checkpoint version: {}", version).into());
+        }
+
+        let width = u64::from_le_bytes(read_bytes(&mut reader)?);
+        let height = u64::from_le_bytes(read_bytes(&mut reader)?);
+        let grains_per_pixel = u64::from_le_bytes(read_bytes(&mut reader)?);
+        let wavelength_start = f32::from_le_bytes(read_bytes(&mut reader)?);
+        let wavelength_width = f32::from_le_bytes(read_bytes(&mut reader)?);
+
+        if (width, height, grains_per_pixel)
+            != (
+                self.width as u64,
+                self.height as u64,
+                self.grains_per_pixel as u64,
+            )
+        {
+            return Err(format!(
+                "the checkpoint has {}x{} pixels with {} spectrum bins, but the film has {}x{} pixels with {} spectrum bins",
+                width, height, grains_per_pixel, self.width, self.height, self.grains_per_pixel
+            )
+            .into());
+        }
+
+        if (wavelength_start, wavelength_width) != (self.wavelength_start, self.wavelength_width) {
+            return Err(format!(
+                "the checkpoint covers {} to {} nm, but the film covers {} to {} nm",
+                wavelength_start,
+                wavelength_start + wavelength_width,
+                self.wavelength_start,
+                self.wavelength_start + self.wavelength_width
+            )
+            .into());
+        }
+
+        let filter = read_string(&mut reader)?;
+        if filter != format!("{:?}", self.filter) {
+            return Err(format!(
+                "the checkpoint uses the pixel filter {}, but the film uses {:?}",
+                filter, self.filter
+            )
+            .into());
+        }
+
+        let checkpoint_settings = CheckpointSettings {
+            renderer: read_string(&mut reader)?,
+            pixel_samples: u32::from_le_bytes(read_bytes(&mut reader)?),
+            spectrum_samples: u32::from_le_bytes(read_bytes(&mut reader)?),
+        };
+        let samples = u32::from_le_bytes(read_bytes(&mut reader)?);
+
+        if checkpoint_settings.renderer != settings.renderer {
+            return Err(format!(
+                "the checkpoint was rendered with the {} renderer, but the project uses the {} renderer",
+                checkpoint_settings.renderer, settings.renderer
+            )
+            .into());
+        }
+
+        if checkpoint_settings.spectrum_samples != settings.spectrum_samples {
+            return Err(format!(
+                "the checkpoint has {} spectrum samples, but the project has {} spectrum samples",
+                checkpoint_settings.spectrum_samples, settings.spectrum_samples
+            )
+            .into());
+        }
+
+        // More pixel samples only continue the render further.
+        if checkpoint_settings.pixel_samples > settings.pixel_samples {
+            return Err(format!(
+                "the checkpoint has {} pixel samples, but the project only has {} pixel samples",
+                checkpoint_settings.pixel_samples, settings.pixel_samples
+            )
+            .into());
+        }
+
+        self.read_grains(reader)?;
+
+        Ok(samples)
+    }
+
+    /// Replaces the grain data with raw grain data from `write_grains`.
+    pub fn read_grains(&self, mut reader: impl Read) -> Result<(), Box<dyn Error>> {
+        for half in self.grains.iter().flat_map(|grain| &grain.halves) {
+            let accumulator = f32::from_le_bytes(read_bytes(&mut reader)?);
+            let weight = f32::from_le_bytes(read_bytes(&mut reader)?);
+
+            half.store(GrainData {
+                accumulator: N32::try_new(accumulator)
+                    .ok_or("the checkpoint contains an invalid grain value")?,
+                weight: N32::try_new(weight)
+                    .ok_or("the checkpoint contains an invalid grain weight")?,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// The render settings that are stored in a checkpoint, to make sure that
+/// it's resumed with compatible settings.
+pub(crate) struct CheckpointSettings {
+    pub renderer: String,
+    pub pixel_samples: u32,
+    pub spectrum_samples: u32,
+}
+
+const CHECKPOINT_MAGIC: &[u8; 8] = b"PYRFILM\0";
+const CHECKPOINT_VERSION: u32 = 4;
+
+pub(crate) fn read_bytes<const N: usize>(reader: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+pub(crate) fn write_string(writer: &mut impl Write, string: &str) -> io::Result<()> {
+    writer.write_all(&(string.len() as u64).to_le_bytes())?;
+    writer.write_all(string.as_bytes())
+}
+
+pub(crate) fn read_string(reader: &mut impl Read) -> Result<String, Box<dyn Error>> {
+    let length = u64::from_le_bytes(read_bytes(reader)?);
+    let mut bytes = Vec::new();
+    reader.take(length).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != length {
+        return Err("the checkpoint ended too early".into());
+    }
+
+    Ok(String::from_utf8(bytes)?)
+}
+
+/// Picks one of the two halves of the film from the exact sample position.
+/// Every sample from the same path has the same position, so they end up in
+/// the same half, which keeps the halves independent of each other.
+fn half_for(position: Point2<f32>) -> usize {
+    let bits = (position.x.to_bits() as u64) << 32 | position.y.to_bits() as u64;
+    (bits.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 63) as usize
 }
 
-#[repr(transparent)]
+/// The exposure of a wavelength range in a pixel. It's split into two
+/// halves, that each receive about half of the samples, to be able to
+/// estimate the variance.
 pub struct Grain {
-    data: AtomicCell<GrainData>,
+    halves: [AtomicCell<GrainData>; 2],
 }
 
 impl Grain {
     fn new() -> Self {
         Self {
-            data: AtomicCell::new(GrainData::new()),
+            halves: [
+                AtomicCell::new(GrainData::new()),
+                AtomicCell::new(GrainData::new()),
+            ],
         }
     }
 
-    pub fn expose(&self, value: f32, weight: f32) {
-        self.increment(value * weight, weight);
+    /// Adds a weighted value to one of the halves and returns the number of
+    /// retries.
+    pub fn expose(&self, value: f32, weight: f32, half: usize) -> u64 {
+        self.increment(value * weight, weight, half)
     }
 
     pub fn develop(&self) -> f32 {
-        let GrainData {
-            weight,
-            accumulator,
-        } = self.data.load();
-
-        if weight > 0.0 {
-            (accumulator / weight).into()
-        } else {
-            0.0
-        }
+        let [first, second] = self.halves.each_ref().map(AtomicCell::load);
+        first
+            .add(second.accumulator.raw(), second.weight.raw())
+            .develop()
     }
 
-    fn increment(&self, increment: f32, weight: f32) {
-        let mut currant_data = self.data.load();
-        let mut attempts = 0;
-
-        // Discard the sample if multiple threads are stuck updating the grain
-        while attempts < 5 {
-            let result = self
-                .data
-                .compare_exchange(currant_data, currant_data.add(increment, weight));
-
-            if let Err(current) = result {
-                currant_data = current;
-                attempts += 1;
-            } else {
-                break;
-            }
+    fn increment(&self, increment: f32, weight: f32, half: usize) -> u64 {
+        let data = &self.halves[half];
+        let mut current_data = data.load();
+        let mut retries = 0;
+
+        // Try again with the new value if another thread got there first
+        while let Err(current) =
+            data.compare_exchange(current_data, current_data.add(increment, weight))
+        {
+            current_data = current;
+            retries += 1;
         }
+
+        retries
     }
 }
 
+// The alignment makes `AtomicCell` use a native 64 bit atomic, instead of
+// falling back to a lock.
 #[derive(Copy, Clone, Eq, PartialEq)]
+#[repr(C, align(8))]
 struct GrainData {
     accumulator: N32,
     weight: N32,
@@ -182,15 +510,77 @@ impl GrainData {
             weight: self.weight + weight,
         }
     }
+
+    fn develop(&self) -> f32 {
+        if self.weight > 0.0 {
+            (self.accumulator / self.weight).into()
+        } else {
+            0.0
+        }
+    }
+}
+
+/// The indices of the pixels with centers within `radius` from `center`,
+/// limited to `bounds`.
+fn pixel_range(center: f32, radius: f32, bounds: Range<usize>) -> Range<usize> {
+    let (start, end) = (bounds.start as f32, bounds.end as f32);
+    let from = (center - 0.5 - radius).ceil().clamp(start, end) as usize;
+    let to = ((center - 0.5 + radius).floor() + 1.0).clamp(start, end) as usize;
+    from..to.max(from)
 }
 
 pub struct DetachedPixel<'a> {
-    grains: &'a [Grain],
+    film: &'a Film,
+    position: Point2<f32>,
+    half: usize,
 }
 
 impl<'a> DetachedPixel<'a> {
     pub fn expose(&self, sample: PixelSample) {
-        self.grains[sample.grain].expose(sample.value, sample.weight);
+        self.film.splat(
+            self.position,
+            sample.grain,
+            sample.value,
+            sample.weight,
+            self.half,
+        );
+    }
+}
+
+/// The total brightness of a pixel and its estimated standard error, based on
+/// the difference between the two halves of the film.
+#[derive(Copy, Clone)]
+pub struct PixelEstimate {
+    pub brightness: f32,
+    pub error: f32,
+}
+
+impl PixelEstimate {
+    fn new(grains: &[Grain]) -> Self {
+        let mut brightness = 0.0;
+        let mut halves = [0.0; 2];
+        let mut exposed = [false; 2];
+
+        for grain in grains {
+            brightness += grain.develop();
+
+            for (half, data) in grain.halves.iter().enumerate() {
+                let data = data.load();
+                halves[half] += data.develop();
+                exposed[half] |= data.weight > 0.0;
+            }
+        }
+
+        // The halves are two independent estimates, so the error of their
+        // average is half of their difference. Nothing can be said about
+        // the error if one of them is missing.
+        let error = if exposed == [true, true] {
+            (halves[0] - halves[1]).abs() * 0.5
+        } else {
+            f32::INFINITY
+        };
+
+        PixelEstimate { brightness, error }
     }
 }
 
@@ -200,14 +590,25 @@ pub struct PixelSample {
     grain: usize,
 }
 
-struct AspectRatio {
+/// How the film is divided into views that are filtered separately, such
+/// as the eyes of a stereo camera.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum FilmViews {
+    Single,
+    /// The left and right halves.
+    SideBySide,
+    /// The top and bottom halves.
+    TopBottom,
+}
+
+pub(crate) struct AspectRatio {
     size: f32,
     ratio: f32,
     orientation: Orientation,
 }
 
 impl AspectRatio {
-    fn new(width: usize, height: usize) -> AspectRatio {
+    pub(crate) fn new(width: usize, height: usize) -> AspectRatio {
         if width >= height {
             AspectRatio {
                 size: width as f32,
@@ -230,16 +631,14 @@ impl AspectRatio {
         }
     }
 
-    fn to_pixel(&self, point: Point2<f32>) -> Option<Point2<usize>> {
+    /// Converts a view position to a position in pixel units.
+    pub(crate) fn to_film(&self, point: Point2<f32>) -> Option<Point2<f32>> {
         if self.contains(point) {
             let (x, y) = match self.orientation {
                 Orientation::Horizontal => (point.x + 1.0, point.y + self.ratio),
                 Orientation::Vertical => (point.x + self.ratio, point.y + 1.0),
             };
-            Some(Point2::new(
-                (self.size * x * 0.5) as usize,
-                (self.size * y * 0.5) as usize,
-            ))
+            Some(Point2::new(self.size * x * 0.5, self.size * y * 0.5))
         } else {
             None
         }
@@ -251,6 +650,7 @@ enum Orientation {
     Vertical,
 }
 
+#[derive(Clone, Copy)]
 pub struct Area<S> {
     pub from: Point2<S>,
     pub size: Vector2<S>,
@@ -296,7 +696,7 @@ impl<'a> Iterator for DevelopedPixels<'a> {
     fn next(&mut self) -> Option<Self::Item> {
         let end = self.index + self.film.grains_per_pixel;
 
-        let result = if end < self.film.grains.len() {
+        let result = if end <= self.film.grains.len() {
             Some(Spectrum {
                 min: self.film.wavelength_start,
                 max: self.film.wavelength_start + self.film.wavelength_width,
@@ -339,4 +739,13 @@ impl<'a> Spectrum<'a> {
     pub fn spectrum_width(&self) -> (f32, f32) {
         (self.min, self.max)
     }
+
+    /// The developed value of each grain, in order of increasing wavelength.
+    pub fn grain_values(&self) -> impl Iterator<Item = f32> + 'a {
+        self.grains.iter().map(Grain::develop)
+    }
+
+    pub fn estimate(&self) -> PixelEstimate {
+        PixelEstimate::new(self.grains)
+    }
 }
@@ -6,7 +6,7 @@ use collision::Ray3;
 use rand::Rng;
 
 use crate::math::utils::{sample_cone, sample_hemisphere, sample_sphere};
-use crate::shapes::{Intersection, Shape, SurfaceData};
+use crate::shapes::{Intersection, Shape, ShapeSurfacePoint, SurfaceData, SurfacePoint};
 use crate::{materials::Material, tracer::LightProgram};
 
 pub(crate) enum Lamp<'p> {
@@ -63,10 +63,7 @@ impl<'p> Lamp<'p> {
 
                 let SurfaceData { normal, texture } = surface_point.get_surface_data();
 
-                let weight = shape.solid_angle_towards(&target).unwrap_or_else(|| {
-                    let cos_in = normal.vector().dot(-direction).abs();
-                    cos_in * shape.surface_area() / sq_distance
-                });
+                let weight = shape_weight(shape, target, direction, sq_distance, normal.vector());
                 Sample {
                     direction,
                     sq_distance: Some(sq_distance),
@@ -113,6 +110,54 @@ impl<'p> Lamp<'p> {
     }
 }
 
+/// The weight that `Lamp::sample` would give to light from `surface_point`
+/// towards `target`, or `None` if the point isn't on a shape that is used as
+/// a lamp.
+pub(crate) fn emitter_weight(surface_point: &SurfacePoint, target: Point3<f32>) -> Option<f32> {
+    let shape = emitter_shape(surface_point)?;
+
+    let v = surface_point.position - target;
+    let normal = surface_point.get_surface_data().normal.vector();
+
+    Some(shape_weight(
+        shape,
+        target,
+        v.normalize(),
+        v.magnitude2(),
+        normal,
+    ))
+}
+
+/// The surface area of the lamp that `surface_point` is on, or `None` if
+/// the point isn't on a shape that is used as a lamp.
+pub(crate) fn emitter_area(surface_point: &SurfacePoint) -> Option<f32> {
+    emitter_shape(surface_point).map(Shape::surface_area)
+}
+
+fn emitter_shape<'a>(surface_point: &SurfacePoint<'a>) -> Option<&'a Shape<'a>> {
+    let shape = match surface_point.shape {
+        ShapeSurfacePoint::Sphere { shape } | ShapeSurfacePoint::Triangle { shape, .. } => shape,
+        ShapeSurfacePoint::Plane { .. } | ShapeSurfacePoint::RayMarched { .. } => return None,
+    };
+
+    shape.get_material().is_emissive().then_some(shape)
+}
+
+/// The inverse of the probability density per solid angle, for a sample in
+/// `direction` from `target`.
+fn shape_weight(
+    shape: &Shape,
+    target: Point3<f32>,
+    direction: Vector3<f32>,
+    sq_distance: f32,
+    normal: Vector3<f32>,
+) -> f32 {
+    shape.solid_angle_towards(&target).unwrap_or_else(|| {
+        let cos_in = normal.dot(-direction).abs();
+        cos_in * shape.surface_area() / sq_distance
+    })
+}
+
 pub(crate) struct Sample<'a> {
     pub direction: Vector3<f32>,
     pub sq_distance: Option<f32>,
@@ -2,41 +2,54 @@
 
 use std::time::Instant;
 
-use image;
-
 use std::{
     borrow::Cow,
     convert::TryFrom,
     error::Error,
+    fs::File,
+    io::{BufReader, BufWriter, Write},
     ops::{Add, AddAssign, Div, Mul},
     path::Path,
 };
 
-use cgmath::Vector2;
+use cgmath::{Vector2, Vector3};
 
 use palette::{
     cast::{self, ArrayCast},
-    FromColor, LinSrgb, Srgb, Xyz,
+    convert::FromColorUnclamped,
+    LinSrgb, Xyz,
 };
 
 use bumpalo::Bump;
 
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 
-use film::{Film, Spectrum};
+use aovs::{developed_vectors, object_id_color, AovKind, Aovs};
+use color_space::ChromaticAdaptation;
+use denoiser::Denoiser;
+use film::{CheckpointSettings, Film, Spectrum};
+use output::{OutputFile, SpectralOutputFile};
+use pixel_filter::PixelFilter;
 use program::{
     ExecutionContext, NumberInput, ProgramCompiler, ProgramFor, ProgramInput, Resources,
     VectorInput,
 };
-use project::{expressions::Vector, meshes::Meshes, Nodes, ProjectData};
+use project::{expressions::Vector, meshes::Meshes, spectra::Spectra, Nodes, ProjectData};
 use renderer::ProgressIndicator;
+use tone_mapping::ToneMapping;
 
+mod aovs;
 mod cameras;
+mod cli;
+mod color_space;
+mod denoiser;
 mod film;
 mod lamp;
 mod light_source;
 mod materials;
 mod math;
+mod output;
+mod pixel_filter;
 mod program;
 mod project;
 mod renderer;
@@ -44,6 +57,7 @@ mod rgb;
 mod shapes;
 mod spatial;
 mod texture;
+mod tone_mapping;
 mod tracer;
 mod utils;
 mod world;
@@ -54,71 +68,107 @@ fn main() {
     let name = args.next().unwrap_or("pyrite".into());
     let arena = Bump::new();
 
-    if let Some(project_path) = args.next() {
-        let loading_started = Instant::now();
+    let cli::Arguments {
+        project_path,
+        resume,
+        verbose,
+        overrides,
+    } = match cli::parse(args) {
+        Ok(cli::Command::Render(arguments)) => arguments,
+        Ok(cli::Command::Help) => {
+            cli::print_usage(&name);
+            return;
+        }
+        Err(error) => {
+            eprintln!("error: {}", error);
+            cli::print_usage(&name);
+            return;
+        }
+    };
+
+    let loading_started = Instant::now();
+
+    let ProjectData {
+        nodes,
+        meshes,
+        spectra,
+        textures,
+        project,
+    } = match project::load_project(&project_path, &overrides) {
+        Ok(project) => project,
+        Err(error) => {
+            eprintln!("error while loading project file: {}", error);
+            return;
+        }
+    };
 
-        let ProjectData {
-            nodes,
-            meshes,
-            spectra,
-            textures,
-            project,
-        } = match project::load_project(&project_path) {
-            Ok(project) => project,
-            Err(error) => {
-                eprintln!("error while loading project file: {}", error);
+    let programs = ProgramCompiler::new(&arena);
+    let mut resources = Resources {
+        textures,
+        spectra,
+        nodes,
+    };
+
+    let project_dir = Path::new(&project_path)
+        .parent()
+        .expect("could not get the project path parent directory");
+    let parse_result = parse_project(
+        project,
+        project_dir,
+        programs,
+        &meshes,
+        &mut resources,
+        &arena,
+    );
+    let loading_ended = Instant::now();
+
+    match parse_result {
+        Ok((image, context)) => {
+            let rendering_started = Instant::now();
+            if let Err(error) = render(image, context, resume, verbose) {
+                eprintln!("error while rendering: {}", error);
                 return;
             }
-        };
-
-        let programs = ProgramCompiler::new(&arena);
-        let mut resources = Resources {
-            textures,
-            spectra,
-            nodes,
-        };
-
-        let parse_result = parse_project(project, programs, &meshes, &mut resources, &arena);
-        let loading_ended = Instant::now();
-
-        match parse_result {
-            Ok((image, context)) => {
-                let rendering_started = Instant::now();
-                render(image, context, project_path);
-                let rendering_ended = Instant::now();
-
-                println!("Done.");
-                println!(
-                    "Project loading: {}",
-                    indicatif::FormattedDuration(loading_ended - loading_started)
-                );
-                println!(
-                    "Rendering: {}",
-                    indicatif::FormattedDuration(rendering_ended - rendering_started)
-                );
-                println!(
-                    "Total: {}",
-                    indicatif::FormattedDuration(rendering_ended - loading_started)
-                );
-            }
-            Err(error) => eprintln!("error while parsing project: {}", error),
+            let rendering_ended = Instant::now();
+
+            println!("Done.");
+            println!(
+                "Project loading: {}",
+                indicatif::FormattedDuration(loading_ended - loading_started)
+            );
+            println!(
+                "Rendering: {}",
+                indicatif::FormattedDuration(rendering_ended - rendering_started)
+            );
+            println!(
+                "Total: {}",
+                indicatif::FormattedDuration(rendering_ended - loading_started)
+            );
         }
-    } else {
-        eprintln!("usage: {} project_file", name);
+        Err(error) => eprintln!("error while parsing project: {}", error),
     }
 }
 
 fn parse_project<'p>(
     project: project::Project,
+    project_dir: &Path,
     programs: ProgramCompiler<'p>,
     meshes: &Meshes,
     resources: &'p mut Resources,
     arena: &'p Bump,
 ) -> Result<(ImageSettings<'p>, RenderContext<'p>), Box<dyn Error>> {
-    let image = ImageSettings::from_project(project.image, programs, &mut resources.nodes)?;
+    let image =
+        ImageSettings::from_project(project.image, project_dir, programs, &mut resources.nodes)?;
+    let image_size = Vector2::new(image.width, image.height);
 
     let config = RenderContext {
-        camera: cameras::Camera::from_project(project.camera, &resources.nodes)?,
+        camera: cameras::Camera::from_project(
+            project.camera,
+            &resources.nodes,
+            &resources.textures,
+            project_dir,
+            image_size,
+        )?,
         renderer: renderer::Renderer::from_project(project.renderer),
         world: world::World::from_project(
             project.world,
@@ -133,12 +183,15 @@ fn parse_project<'p>(
     Ok((image, config))
 }
 
-fn render<P: AsRef<Path>>(
+fn render(
     image_settings: ImageSettings<'_>,
     config: RenderContext<'_>,
-    project_path: P,
-) {
+    resume: bool,
+    verbose: bool,
+) -> Result<(), Box<dyn Error>> {
     let image_size = Vector2::new(image_settings.width, image_settings.height);
+    // Stereo cameras may render both eyes into a larger film.
+    let film_size = config.camera.film_size(image_size);
 
     let progress = MultiProgress::new();
 
@@ -167,33 +220,78 @@ fn render<P: AsRef<Path>>(
 
     let preview_progress = progress.add(ProgressBar::new_spinner());
 
-    let mut pixels = image::ImageBuffer::new(image_size.x, image_size.y);
+    let mut pixels = vec![Xyz::default(); (film_size.x * film_size.y) as usize];
 
-    let rgb_curves = None; /*image_settings.rgb_curves.map(|(red, green, blue)| {
-                               (
-                                   Interpolated { points: red },
-                                   Interpolated { points: green },
-                                   Interpolated { points: blue },
-                               )
-                           });*/
+    let rgb_curves = image_settings.rgb_curves.as_ref().map(|curves| {
+        RgbCurves::new(
+            curves,
+            &config.resources.spectra,
+            config.renderer.spectrum_span,
+        )
+    });
 
-    let project_path = project_path.as_ref();
-    let render_path = project_path
-        .parent()
-        .unwrap_or(project_path)
-        .join("render.png");
+    let output_file = &image_settings.output_file;
 
     /*let f = |mut tile: Tile| {
         config.renderer.render_tile(&mut tile, &config.camera, &config.world);
     };*/
 
     let film = Film::new(
-        image_size.x as usize,
-        image_size.y as usize,
+        film_size.x as usize,
+        film_size.y as usize,
         config.renderer.spectrum_bins,
         config.renderer.spectrum_span,
+        image_settings.pixel_filter,
+        config.camera.film_views(),
     );
 
+    let mut aov_kinds: Vec<_> = image_settings
+        .aov_files
+        .iter()
+        .map(|&(kind, _)| kind)
+        .collect();
+    if image_settings.denoiser.is_some() {
+        aov_kinds.extend([AovKind::Albedo, AovKind::Normal]);
+    }
+    let aovs = Aovs::new(
+        &aov_kinds,
+        &config.renderer.connection_strategies(),
+        film_size.x as usize,
+        film_size.y as usize,
+        config.renderer.spectrum_bins,
+        config.renderer.spectrum_span,
+        image_settings.pixel_filter,
+        config.camera.film_views(),
+    );
+
+    let checkpoint_path = output_file.path().with_extension("checkpoint");
+    let checkpoint_settings = config.renderer.checkpoint_settings();
+    let mut finished_samples = 0;
+    if resume {
+        println!("Resuming from {}...", checkpoint_path.display());
+        let file = File::open(&checkpoint_path)
+            .map_err(|error| format!("could not open {}: {}", checkpoint_path.display(), error))?;
+        let mut reader = BufReader::new(file);
+        finished_samples = film
+            .read_checkpoint(&checkpoint_settings, &mut reader)
+            .and_then(|samples| {
+                aovs.read_checkpoint(&mut reader)?;
+                config.renderer.read_checkpoint(&mut reader)?;
+                Ok(samples)
+            })
+            .map_err(|error| {
+                format!(
+                    "could not resume from {}: {}",
+                    checkpoint_path.display(),
+                    error
+                )
+            })?;
+        println!(
+            "{} samples per pixel are already finished",
+            finished_samples
+        );
+    }
+
     let mut filter_exe = ExecutionContext::new(config.resources);
     let mut filter = image_settings.filter.map(|white| {
         move |intensity: f32, wavelength: f32| {
@@ -201,39 +299,67 @@ fn render<P: AsRef<Path>>(
         }
     });
 
-    let mut white_balance_exe = ExecutionContext::new(config.resources);
-    let mut white_balance = image_settings.white.map(|white| {
-        let mut wavelength = config.renderer.spectrum_span.0;
-        let mut max = 0.0f32;
-        let mut d65_max = 0.0f32;
-
-        while wavelength < config.renderer.spectrum_span.1 {
-            max = max.max(white_balance_exe.run(white, &SpectrumSamplingInput { wavelength }));
-            d65_max = d65_max.max(light_source::D65.get(wavelength));
-            wavelength += 1.0;
-        }
+    // The colors are adapted from the chosen white point to D65, which is
+    // the white point of the output color spaces.
+    let white_balance = image_settings
+        .white
+        .as_ref()
+        .map(|white| {
+            let white: [f32; 3] = match *white {
+                WhitePoint::Spectrum(white) => {
+                    let mut exe = ExecutionContext::new(config.resources);
+                    spectrum_to_xyz(config.renderer.spectrum_span, 1.0, (), |_, wavelength| {
+                        exe.run(white, &SpectrumSamplingInput { wavelength })
+                    })
+                    .into()
+                }
+                WhitePoint::Temperature(temperature) => {
+                    spectrum_to_xyz(config.renderer.spectrum_span, 1.0, (), |_, wavelength| {
+                        math::blackbody(wavelength, temperature)
+                    })
+                    .into()
+                }
+                WhitePoint::Chromaticity(white) => color_space::xy_to_xyz(white).into(),
+            };
+            let white = Vector3::from(white);
+
+            if white.y <= 0.0 {
+                return Err("the white point needs to have a positive brightness");
+            }
 
-        move |intensity: f32, wavelength: f32| {
-            let white_intensity =
-                white_balance_exe.run(white, &SpectrumSamplingInput { wavelength }) / max;
-            let neutral = intensity / white_intensity.max(0.000001);
-            neutral * (light_source::D65.get(wavelength) / d65_max)
-        }
-    });
+            Ok(image_settings
+                .chromatic_adaptation
+                .matrix(white, color_space::xy_to_xyz(color_space::D65)))
+        })
+        .transpose()?;
 
     let mut spectrum_get = |spectrum: &Spectrum, wavelength: f32| {
         let intensity = spectrum.get(wavelength);
 
-        let filtered = if let Some(filter) = &mut filter {
+        if let Some(filter) = &mut filter {
             filter(intensity, wavelength)
         } else {
             intensity
-        };
+        }
+    };
 
-        if let Some(white_balance) = &mut white_balance {
-            white_balance(filtered, wavelength)
-        } else {
-            filtered
+    let mut develop = |film: &Film, step_size: f32, pixels: &mut [Xyz]| {
+        for (spectrum, pixel) in film.developed_pixels().zip(pixels) {
+            let color = if let Some(curves) = &rgb_curves {
+                let color = spectrum_to_rgb(step_size, spectrum, |s, w| spectrum_get(s, w), curves);
+                Xyz::from_color_unclamped(color)
+            } else {
+                spectrum_to_xyz(spectrum.spectrum_width(), step_size, spectrum, |s, w| {
+                    spectrum_get(s, w)
+                })
+            };
+
+            *pixel = if let Some(white_balance) = &white_balance {
+                let color = white_balance * Vector3::new(color.x, color.y, color.z);
+                Xyz::new(color.x, color.y, color.z)
+            } else {
+                color
+            };
         }
     };
 
@@ -242,15 +368,17 @@ fn render<P: AsRef<Path>>(
 
     crossbeam::thread::scope(|scope| {
         scope.spawn(|_| {
-            config.renderer.render(
+            finished_samples = config.renderer.render(
                 &film,
+                &aovs,
                 task_runner,
-                |status| {
+                |status, finished_samples| {
                     let time_since_print = last_print.map(|last_print| Instant::now() - last_print);
 
-                    let should_print = time_since_print
-                        .map(|time| time.as_millis() >= 500)
-                        .unwrap_or(true);
+                    let should_print = status.pass_finished
+                        || time_since_print
+                            .map(|time| time.as_millis() >= 500)
+                            .unwrap_or(true);
 
                     if should_print {
                         global_progress.set_message(status.message.to_owned());
@@ -259,36 +387,39 @@ fn render<P: AsRef<Path>>(
                         last_print = Some(Instant::now());
 
                         let time_since_image = Instant::now() - last_image;
-                        if time_since_image.as_secs() >= 20 {
+                        if status.pass_finished || time_since_image.as_secs() >= 20 {
                             let begin_iter = Instant::now();
                             preview_progress.set_message("Updating preview...");
 
-                            for (spectrum, pixel) in
-                                film.developed_pixels().zip(pixels.pixels_mut())
-                            {
-                                let rgb: Srgb<u8> = if let Some((red, green, blue)) = &rgb_curves {
-                                    let color =
-                                        spectrum_to_rgb(30.0, spectrum, &red, &green, &blue);
-                                    Srgb::from_linear(color)
-                                } else {
-                                    let color = spectrum_to_xyz(
-                                        spectrum.spectrum_width(),
-                                        30.0,
-                                        spectrum,
-                                        |s, w| spectrum_get(s, w),
-                                    );
-                                    LinSrgb::from_color(color).into_encoding()
-                                };
-
-                                *pixel = image::Rgb(rgb.into());
-                            }
+                            develop_image(
+                                &image_settings,
+                                &film,
+                                &aovs,
+                                30.0,
+                                &mut pixels,
+                                &mut develop,
+                            );
                             let diff = (Instant::now() - begin_iter).as_millis() as f64 / 1000.0;
 
-                            if let Err(e) = pixels.save(&render_path) {
+                            if let Err(e) =
+                                save_image(output_file, &config.camera, film_size, &pixels)
+                            {
                                 preview_progress.finish_with_message(format!(
                                     "Error while writing preview: {}",
                                     e
                                 ));
+                            } else if let Err(e) = save_checkpoint(
+                                &film,
+                                &aovs,
+                                &config.renderer,
+                                &checkpoint_settings,
+                                finished_samples,
+                                &checkpoint_path,
+                            ) {
+                                preview_progress.finish_with_message(format!(
+                                    "Error while writing checkpoint: {}",
+                                    e
+                                ));
                             } else {
                                 preview_progress.finish_with_message(format!(
                                     "Preview updated ({} seconds)",
@@ -299,6 +430,7 @@ fn render<P: AsRef<Path>>(
                         }
                     }
                 },
+                finished_samples,
                 &config.camera,
                 &config.world,
                 config.resources,
@@ -310,42 +442,294 @@ fn render<P: AsRef<Path>>(
     })
     .unwrap();
 
+    if verbose {
+        println!(
+            "Grain updates retried because of contention: {}",
+            film.contention_events()
+        );
+    }
     println!("Saving final result...");
 
-    for (spectrum, pixel) in film.developed_pixels().zip(pixels.pixels_mut()) {
-        let rgb: Srgb<u8> = if let Some((red, green, blue)) = &rgb_curves {
-            let color = spectrum_to_rgb(2.0, spectrum, &red, &green, &blue);
-            Srgb::from_linear(color)
-        } else {
-            let color = spectrum_to_xyz(spectrum.spectrum_width(), 2.0, spectrum, |s, w| {
-                spectrum_get(s, w)
-            });
-            LinSrgb::from_color(color).into_encoding()
-        };
+    develop_image(
+        &image_settings,
+        &film,
+        &aovs,
+        2.0,
+        &mut pixels,
+        &mut develop,
+    );
+
+    if let Err(e) = save_image(output_file, &config.camera, film_size, &pixels) {
+        println!(
+            "error while writing {}: {}",
+            output_file.path().display(),
+            e
+        );
+    }
+
+    if let Some(spectral_output_file) = &image_settings.spectral_output_file {
+        if let Err(e) = spectral_output_file.save(&film) {
+            println!(
+                "error while writing {}: {}",
+                spectral_output_file.path().display(),
+                e
+            );
+        }
+    }
+
+    for (kind, aov_file) in &image_settings.aov_files {
+        if let Err(e) = save_aov(
+            *kind,
+            aov_file,
+            &aovs,
+            &config.camera,
+            film_size,
+            image_settings.tone_mapping.exposure(),
+            &mut develop,
+        ) {
+            println!("error while writing {}: {}", aov_file.path().display(), e);
+        }
+    }
+
+    if let Err(e) = save_checkpoint(
+        &film,
+        &aovs,
+        &config.renderer,
+        &checkpoint_settings,
+        finished_samples,
+        &checkpoint_path,
+    ) {
+        println!("error while writing {}: {}", checkpoint_path.display(), e);
+    }
+
+    Ok(())
+}
+
+/// Saves the developed image, or one image per eye for stereo cameras that
+/// save them separately.
+fn save_image(
+    file: &OutputFile,
+    camera: &cameras::Camera,
+    film_size: Vector2<u32>,
+    pixels: &[Xyz],
+) -> Result<(), Box<dyn Error>> {
+    save_eyes(file, camera, film_size, pixels, OutputFile::save)
+}
+
+/// Calls `save` with the whole film, or with each eye and its own file for
+/// stereo cameras that save them separately. The film has both eyes side by
+/// side in that case.
+fn save_eyes<T: Copy>(
+    file: &OutputFile,
+    camera: &cameras::Camera,
+    film_size: Vector2<u32>,
+    values: &[T],
+    save: impl Fn(&OutputFile, u32, u32, &[T]) -> Result<(), Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    if camera.stereo_layout() != Some(cameras::StereoLayout::Separate) {
+        return save(file, film_size.x, film_size.y, values);
+    }
+
+    let eye_width = film_size.x / 2;
+    for (eye, suffix) in ["_left", "_right"].into_iter().enumerate() {
+        let eye_start = eye * eye_width as usize;
+        let eye_values: Vec<T> = values
+            .chunks(film_size.x as usize)
+            .flat_map(|row| &row[eye_start..eye_start + eye_width as usize])
+            .copied()
+            .collect();
+
+        let eye_file = file.with_suffix(suffix);
+        save(&eye_file, eye_width, film_size.y, &eye_values)?;
+    }
+
+    Ok(())
+}
+
+/// Develops the film into the pixels of the output image. It's the same for
+/// the previews and the final image.
+fn develop_image(
+    image_settings: &ImageSettings<'_>,
+    film: &Film,
+    aovs: &Aovs,
+    step_size: f32,
+    pixels: &mut [Xyz],
+    develop: &mut impl FnMut(&Film, f32, &mut [Xyz]),
+) {
+    develop(film, step_size, pixels);
+
+    if let Some(denoiser) = &image_settings.denoiser {
+        denoise(denoiser, film, aovs, step_size, pixels, develop);
+    }
+
+    image_settings.tone_mapping.apply(pixels);
+}
+
+fn denoise(
+    denoiser: &Denoiser,
+    film: &Film,
+    aovs: &Aovs,
+    step_size: f32,
+    pixels: &mut [Xyz],
+    develop: &mut impl FnMut(&Film, f32, &mut [Xyz]),
+) {
+    let (Some(albedo_film), Some(normal_film)) =
+        (aovs.spectral(AovKind::Albedo), aovs.data(AovKind::Normal))
+    else {
+        return;
+    };
+
+    let mut albedo = vec![Xyz::default(); pixels.len()];
+    develop(albedo_film, step_size, &mut albedo);
+    let normals = developed_vectors(normal_film);
+
+    denoiser.apply(film, pixels, &albedo, &normals);
+}
+
+fn save_aov(
+    kind: AovKind,
+    file: &OutputFile,
+    aovs: &Aovs,
+    camera: &cameras::Camera,
+    film_size: Vector2<u32>,
+    exposure: f32,
+    develop: &mut impl FnMut(&Film, f32, &mut [Xyz]),
+) -> Result<(), Box<dyn Error>> {
+    if kind == AovKind::Strategies {
+        // The strategies are lighting too, so they are exposed the same way.
+        for (strategy, film) in aovs.strategies() {
+            let suffix = format!(
+                "_s{}_t{}",
+                strategy.light_vertices, strategy.camera_vertices
+            );
+            let mut pixels = vec![Xyz::default(); film.width() * film.height()];
+            develop(film, 2.0, &mut pixels);
+
+            for pixel in &mut pixels {
+                *pixel = *pixel * exposure;
+            }
+
+            save_image(&file.with_suffix(&suffix), camera, film_size, &pixels)?;
+        }
+
+        return Ok(());
+    }
 
-        *pixel = image::Rgb(rgb.into());
+    if let Some(film) = aovs.spectral(kind) {
+        let mut pixels = vec![Xyz::default(); film.width() * film.height()];
+        develop(film, 2.0, &mut pixels);
+
+        // The lighting is exposed like the main image, to be able to add
+        // them back together, but it's not tone mapped.
+        if let AovKind::Direct | AovKind::Indirect = kind {
+            for pixel in &mut pixels {
+                *pixel = *pixel * exposure;
+            }
+        }
+
+        return save_image(file, camera, film_size, &pixels);
     }
 
-    if let Err(e) = pixels.save(&render_path) {
-        println!("error while writing image: {}", e);
+    let values: Vec<[f32; 3]> = if let Some(film) = aovs.data(kind) {
+        let mut values = developed_vectors(film);
+
+        // Integer formats can't store negative values.
+        if kind == AovKind::Normal && !file.is_floating_point() {
+            for value in &mut values {
+                *value = value.map(|component| component * 0.5 + 0.5);
+            }
+        }
+
+        values
+    } else if let Some(object_ids) = aovs.object_ids() {
+        object_ids
+            .ids()
+            .map(|id| {
+                if file.is_floating_point() {
+                    [id as f32; 3]
+                } else {
+                    object_id_color(id)
+                }
+            })
+            .collect()
+    } else {
+        return Ok(());
+    };
+
+    save_eyes(file, camera, film_size, &values, OutputFile::save_data)
+}
+
+/// Writes the film, the AOVs and the renderer state to a temporary file
+/// first, to keep the previous checkpoint intact if the process is
+/// interrupted while writing.
+fn save_checkpoint(
+    film: &Film,
+    aovs: &Aovs,
+    renderer: &renderer::Renderer,
+    settings: &CheckpointSettings,
+    samples: u32,
+    path: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let temporary_path = path.with_extension("checkpoint.tmp");
+
+    let mut file = BufWriter::new(File::create(&temporary_path)?);
+    film.write_checkpoint(settings, samples, &mut file)?;
+    aovs.write_checkpoint(&mut file)?;
+    renderer.write_checkpoint(&mut file)?;
+    file.flush()?;
+    drop(file);
+
+    std::fs::rename(temporary_path, path)?;
+
+    Ok(())
+}
+
+/// Color response curves for developing the film directly to RGB, instead
+/// of going through XYZ. The RGB values are treated as linear sRGB.
+struct RgbCurves<'a> {
+    red: &'a project::spectra::Spectrum<f32>,
+    green: &'a project::spectra::Spectrum<f32>,
+    blue: &'a project::spectra::Spectrum<f32>,
+    /// The response to a constant spectrum of 1, which becomes white.
+    white: LinSrgb,
+}
+
+impl<'a> RgbCurves<'a> {
+    fn new(curves: &project::RgbCurves, spectra: &'a Spectra, spectrum_span: (f32, f32)) -> Self {
+        let red = spectra.get(curves.red);
+        let green = spectra.get(curves.green);
+        let blue = spectra.get(curves.blue);
+
+        RgbCurves {
+            red,
+            green,
+            blue,
+            white: spectrum_to_tristimulus(spectrum_span, 1.0, (), |_, _| 1.0, red, green, blue),
+        }
     }
 }
 
 fn spectrum_to_rgb(
     step_size: f32,
     spectrum: Spectrum,
-    red: &project::spectra::Spectrum<f32>,
-    green: &project::spectra::Spectrum<f32>,
-    blue: &project::spectra::Spectrum<f32>,
+    sample: impl FnMut(&Spectrum, f32) -> f32,
+    curves: &RgbCurves,
 ) -> LinSrgb {
-    spectrum_to_tristimulus(
+    let color: LinSrgb = spectrum_to_tristimulus(
         spectrum.spectrum_width(),
         step_size,
         spectrum,
-        Spectrum::get,
-        red,
-        green,
-        blue,
+        sample,
+        curves.red,
+        curves.green,
+        curves.blue,
+    );
+
+    let normalize = |value: f32, white: f32| if white > 0.0 { value / white } else { 0.0 };
+    LinSrgb::new(
+        normalize(color.red, curves.white.red),
+        normalize(color.green, curves.white.green),
+        normalize(color.blue, curves.white.blue),
     )
 }
 
@@ -365,9 +749,13 @@ fn spectrum_to_xyz<S>(
         &xyz::response::Z,
     );
 
-    color * 3.444 // Scale up to better match D65 light source data
+    color * XYZ_SCALE
 }
 
+/// Scales the XYZ values up, to give a constant spectrum of 1 a luminance
+/// close to 1. That's what an exposure of 0 EV is relative to.
+const XYZ_SCALE: f32 = 3.444;
+
 fn spectrum_to_tristimulus<T, S>(
     (min, max): (f32, f32),
     step_size: f32,
@@ -427,14 +815,29 @@ struct RenderContext<'p> {
 struct ImageSettings<'a> {
     width: u32,
     height: u32,
-    file: Option<String>,
+    output_file: OutputFile,
+    spectral_output_file: Option<SpectralOutputFile>,
+    pixel_filter: PixelFilter,
+    aov_files: Vec<(AovKind, OutputFile)>,
+    denoiser: Option<Denoiser>,
+    tone_mapping: ToneMapping,
+    rgb_curves: Option<project::RgbCurves>,
     filter: Option<ProgramFor<'a, SpectrumSamplingInput, f32>>,
-    white: Option<ProgramFor<'a, SpectrumSamplingInput, f32>>,
+    white: Option<WhitePoint<'a>>,
+    chromatic_adaptation: ChromaticAdaptation,
+}
+
+/// The color of the light that should appear white in the image.
+enum WhitePoint<'a> {
+    Spectrum(ProgramFor<'a, SpectrumSamplingInput, f32>),
+    Temperature(f32),
+    Chromaticity([f32; 2]),
 }
 
 impl<'a> ImageSettings<'a> {
     fn from_project(
         project: project::Image,
+        project_dir: &Path,
         programs: ProgramCompiler<'a>,
         nodes: &mut Nodes,
     ) -> Result<Self, Box<dyn Error>> {
@@ -442,20 +845,111 @@ impl<'a> ImageSettings<'a> {
             width,
             height,
             file,
+            bit_depth,
+            color_space,
+            spectral_file,
+            pixel_filter,
+            aovs,
+            denoiser,
+            exposure,
+            iso,
+            shutter_speed,
+            f_number,
+            tone_mapping,
+            white_luminance,
+            rgb_curves,
             filter,
             white,
+            white_temperature,
+            white_illuminant,
+            chromatic_adaptation,
         } = project;
 
+        let output_path = project_dir.join(file.as_deref().unwrap_or("render.png"));
+        let spectral_output_file = spectral_file
+            .map(|file| SpectralOutputFile::new(project_dir.join(file)))
+            .transpose()?;
+
+        let white =
+            match (white, white_temperature, white_illuminant) {
+                (None, None, None) => None,
+                (Some(white), None, None) => {
+                    Some(WhitePoint::Spectrum(programs.compile(&white, nodes)?))
+                }
+                (None, Some(temperature), None) => Some(WhitePoint::Temperature(temperature)),
+                (None, None, Some(illuminant)) => Some(WhitePoint::Chromaticity(
+                    color_space::illuminant_white(illuminant),
+                )),
+                _ => return Err(
+                    "only one of `white`, `white_temperature` and `white_illuminant` can be set"
+                        .into(),
+                ),
+            };
+
+        let mut aov_files = Vec::new();
+        if let Some(aovs) = aovs {
+            let project::Aovs {
+                depth,
+                normal,
+                texture_coordinates,
+                albedo,
+                object_id,
+                direct,
+                indirect,
+                strategies,
+            } = aovs;
+
+            let files = [
+                (AovKind::Depth, depth),
+                (AovKind::Normal, normal),
+                (AovKind::TextureCoordinates, texture_coordinates),
+                (AovKind::Albedo, albedo),
+                (AovKind::ObjectId, object_id),
+                (AovKind::Direct, direct),
+                (AovKind::Indirect, indirect),
+                (AovKind::Strategies, strategies),
+            ];
+
+            for (kind, file) in files {
+                if let Some(file) = file {
+                    let path = project_dir.join(file);
+                    aov_files.push((
+                        kind,
+                        OutputFile::new(path, None, project::ColorSpace::Srgb)?,
+                    ));
+                }
+            }
+        }
+
+        let color_space = color_space.unwrap_or(project::ColorSpace::Srgb);
+
         Ok(ImageSettings {
             width,
             height,
-            file,
+            output_file: OutputFile::new(output_path, bit_depth, color_space)?,
+            spectral_output_file,
+            pixel_filter: pixel_filter
+                .map(PixelFilter::from_project)
+                .unwrap_or_default(),
+            aov_files,
+            denoiser: denoiser.map(Denoiser::from_project),
+            tone_mapping: ToneMapping::from_project(
+                exposure,
+                iso,
+                shutter_speed,
+                f_number,
+                tone_mapping,
+                white_luminance,
+                color_space,
+            ),
+            rgb_curves,
             filter: filter
                 .map(|filter| programs.compile(&filter, nodes))
                 .transpose()?,
-            white: white
-                .map(|white| programs.compile(&white, nodes))
-                .transpose()?,
+            white,
+            chromatic_adaptation: chromatic_adaptation
+                .map(ChromaticAdaptation::from_project)
+                .unwrap_or_default(),
         })
     }
 }
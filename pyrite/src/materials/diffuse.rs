@@ -21,9 +21,14 @@ pub(crate) fn scatter(
         probability: 1.0,
         dispersed: false,
         brdf: Some(lambertian),
+        pdf: Some(uniform_hemisphere),
     }
 }
 
 fn lambertian(_ray_in: Vector3<f32>, ray_out: Vector3<f32>, normal: Vector3<f32>) -> f32 {
     2.0 * normal.dot(ray_out).abs()
 }
+
+fn uniform_hemisphere(_ray_in: Vector3<f32>, _ray_out: Vector3<f32>, _normal: Vector3<f32>) -> f32 {
+    0.5 * std::f32::consts::FRAC_1_PI
+}
@@ -17,5 +17,6 @@ pub(crate) fn scatter(in_direction: Vector3<f32>, normal: Vector3<f32>) -> Scatt
         probability: 1.0,
         dispersed: false,
         brdf: None,
+        pdf: None,
     }
 }
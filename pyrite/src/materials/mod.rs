@@ -15,7 +15,7 @@ use crate::{
         Nodes,
     },
     shapes::Normal,
-    tracer::{Brdf, LightProgram, NormalInput},
+    tracer::{Brdf, LightProgram, NormalInput, Pdf},
 };
 use rand::{prelude::SliceRandom, Rng};
 
@@ -27,11 +27,13 @@ mod refractive;
 pub(crate) struct Material<'a> {
     surface: SurfaceMaterial<'a>,
     normal_map: Option<ProgramFor<'a, NormalInput, Vector>>,
+    object_id: u32,
 }
 
 impl<'a> Material<'a> {
     pub(crate) fn from_project(
         material: crate::project::Material,
+        object_id: u32,
         programs: ProgramCompiler<'a>,
         nodes: &mut Nodes,
         allocator: &'a bumpalo::Bump,
@@ -42,9 +44,16 @@ impl<'a> Material<'a> {
                 .normal_map
                 .map(|program| programs.compile(&program, nodes))
                 .transpose()?,
+            object_id,
         })
     }
 
+    /// Identifies the object the material belongs to. It starts at 1 and
+    /// increases for each object in the world.
+    pub(crate) fn object_id(&self) -> u32 {
+        self.object_id
+    }
+
     pub(crate) fn choose_component(&self, rng: &mut impl Rng) -> MaterialComponent<'a> {
         self.surface
             .components
@@ -364,6 +373,9 @@ pub(crate) enum Scattering {
         probability: f32,
         dispersed: bool,
         brdf: Option<Brdf>,
+        /// The probability density of `out_direction`, per solid angle. It's
+        /// `None` for specular reflections.
+        pdf: Option<Pdf>,
     },
     Emitted,
 }
@@ -32,6 +32,7 @@ pub(crate) fn scatter(
         probability,
         dispersed,
         brdf: None,
+        pdf: None,
     }
 }
 
@@ -0,0 +1,558 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use exr::{
+    meta::attribute::Chromaticities,
+    prelude::{
+        f16, AnyChannel, AnyChannels, AttributeValue, FlatSamples, Image, SpecificChannels, Text,
+        Vec2, WritableImage,
+    },
+};
+use image::{
+    codecs::{hdr::HdrEncoder, jpeg::JpegEncoder},
+    ColorType, EncodableLayout, ImageBuffer, ImageFormat, PixelWithColorType,
+};
+use palette::Xyz;
+use tiff::{
+    encoder::{colortype, TiffEncoder},
+    tags::Tag,
+};
+
+use crate::{color_space::RgbSpace, film::Film, project::ColorSpace};
+
+/// An image file that the developed film is written to. The file format is
+/// chosen from the file extension.
+pub(crate) struct OutputFile {
+    path: PathBuf,
+    format: ImageFormat,
+    pixel_format: PixelFormat,
+    color_space: ColorSpace,
+}
+
+impl OutputFile {
+    pub(crate) fn new(
+        path: PathBuf,
+        bit_depth: Option<u32>,
+        color_space: ColorSpace,
+    ) -> Result<Self, Box<dyn Error>> {
+        let format = ImageFormat::from_path(&path).map_err(|error| {
+            format!(
+                "could not determine the image format of {}: {}",
+                path.display(),
+                error
+            )
+        })?;
+
+        let pixel_format = match (format, bit_depth) {
+            (ImageFormat::Png | ImageFormat::Jpeg, None | Some(8))
+            | (ImageFormat::Tiff, Some(8)) => PixelFormat::Rgb8,
+            (ImageFormat::Png, Some(16)) | (ImageFormat::Tiff, None | Some(16)) => {
+                PixelFormat::Rgb16
+            }
+            (ImageFormat::OpenExr, Some(16)) => PixelFormat::Rgb16F,
+            (ImageFormat::OpenExr | ImageFormat::Hdr, None | Some(32)) => PixelFormat::Rgb32F,
+            (
+                ImageFormat::Png
+                | ImageFormat::Jpeg
+                | ImageFormat::Tiff
+                | ImageFormat::OpenExr
+                | ImageFormat::Hdr,
+                Some(bit_depth),
+            ) => {
+                return Err(format!(
+                    "{}-bit color is not supported when writing {}",
+                    bit_depth,
+                    path.display()
+                )
+                .into())
+            }
+            _ => {
+                return Err(format!(
+                    "unsupported output format for {}, expected PNG, JPEG, TIFF, OpenEXR or Radiance HDR",
+                    path.display()
+                )
+                .into())
+            }
+        };
+
+        if let (ColorSpace::Xyz, PixelFormat::Rgb8 | PixelFormat::Rgb16) =
+            (color_space, pixel_format)
+        {
+            return Err(format!(
+                "XYZ colors can only be written as floating point values, which is not possible for {}",
+                path.display()
+            )
+            .into());
+        }
+
+        Ok(OutputFile {
+            path,
+            format,
+            pixel_format,
+            color_space,
+        })
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The same kind of file, with `suffix` added to the file name, such as
+    /// `render_left.png` for `render.png`.
+    pub(crate) fn with_suffix(&self, suffix: &str) -> Self {
+        let mut file_name = self.path.file_stem().unwrap_or_default().to_owned();
+        file_name.push(suffix);
+        if let Some(extension) = self.path.extension() {
+            file_name.push(".");
+            file_name.push(extension);
+        }
+
+        OutputFile {
+            path: self.path.with_file_name(file_name),
+            format: self.format,
+            pixel_format: self.pixel_format,
+            color_space: self.color_space,
+        }
+    }
+
+    pub(crate) fn save(
+        &self,
+        width: u32,
+        height: u32,
+        pixels: &[Xyz],
+    ) -> Result<(), Box<dyn Error>> {
+        let Some(space) = RgbSpace::from_color_space(self.color_space) else {
+            // XYZ has already been limited to floating point formats.
+            let colors: Vec<[f32; 3]> = pixels.iter().map(|&color| color.into()).collect();
+            return self.save_float(width, height, &colors, Some(XYZ_CHROMATICITIES));
+        };
+
+        let matrix = space.film_to_rgb_matrix();
+        let colors = pixels
+            .iter()
+            .map(|&color| space.film_to_rgb(&matrix, color));
+        let encoded = colors
+            .clone()
+            .flatten()
+            .map(|value| space.transfer.encode(value.clamp(0.0, 1.0)));
+
+        match self.pixel_format {
+            PixelFormat::Rgb8 => self.save_color_buffer(
+                width,
+                height,
+                &space,
+                ColorBuffer::Rgb8(
+                    encoded
+                        .map(|value| (value * u8::MAX as f32).round() as u8)
+                        .collect(),
+                ),
+            ),
+            PixelFormat::Rgb16 => self.save_color_buffer(
+                width,
+                height,
+                &space,
+                ColorBuffer::Rgb16(
+                    encoded
+                        .map(|value| (value * u16::MAX as f32).round() as u16)
+                        .collect(),
+                ),
+            ),
+            PixelFormat::Rgb16F | PixelFormat::Rgb32F => {
+                // The values are written as they are, without clamping, to
+                // preserve the full dynamic range.
+                let [red, green, blue] = space.primaries.map(|[x, y]| Vec2(x, y));
+                let chromaticities = Chromaticities {
+                    red,
+                    green,
+                    blue,
+                    white: Vec2(space.white[0], space.white[1]),
+                };
+                self.save_float(
+                    width,
+                    height,
+                    &colors.collect::<Vec<_>>(),
+                    Some(chromaticities),
+                )
+            }
+        }
+    }
+
+    pub(crate) fn is_floating_point(&self) -> bool {
+        matches!(self.pixel_format, PixelFormat::Rgb16F | PixelFormat::Rgb32F)
+    }
+
+    /// Writes other data than colors, such as depth or normals. The values
+    /// are written as they are to floating point formats, and clamped to
+    /// 0..1 without gamma correction for integer formats.
+    pub(crate) fn save_data(
+        &self,
+        width: u32,
+        height: u32,
+        values: &[[f32; 3]],
+    ) -> Result<(), Box<dyn Error>> {
+        let components = values.iter().flatten().map(|value| value.clamp(0.0, 1.0));
+
+        match self.pixel_format {
+            PixelFormat::Rgb8 => self.save_buffer::<image::Rgb<u8>>(
+                width,
+                height,
+                components
+                    .map(|value| (value * u8::MAX as f32).round() as u8)
+                    .collect(),
+            ),
+            PixelFormat::Rgb16 => self.save_buffer::<image::Rgb<u16>>(
+                width,
+                height,
+                components
+                    .map(|value| (value * u16::MAX as f32).round() as u16)
+                    .collect(),
+            ),
+            PixelFormat::Rgb16F | PixelFormat::Rgb32F => {
+                self.save_float(width, height, values, None)
+            }
+        }
+    }
+
+    fn save_float(
+        &self,
+        width: u32,
+        height: u32,
+        colors: &[[f32; 3]],
+        chromaticities: Option<Chromaticities>,
+    ) -> Result<(), Box<dyn Error>> {
+        if let ImageFormat::Hdr = self.format {
+            self.save_hdr(width, height, colors)
+        } else {
+            self.save_exr(width, height, colors, chromaticities)
+        }
+    }
+
+    /// Writes encoded colors, together with a description of the color
+    /// space. That's an sRGB chunk for sRGB PNG files and an ICC profile
+    /// otherwise.
+    fn save_color_buffer(
+        &self,
+        width: u32,
+        height: u32,
+        space: &RgbSpace,
+        buffer: ColorBuffer,
+    ) -> Result<(), Box<dyn Error>> {
+        let file = BufWriter::new(File::create(&self.path)?);
+
+        match (self.format, buffer) {
+            (ImageFormat::Png, buffer) => {
+                let mut info = png::Info::with_size(width, height);
+                info.color_type = png::ColorType::Rgb;
+
+                let data = match buffer {
+                    ColorBuffer::Rgb8(data) => {
+                        info.bit_depth = png::BitDepth::Eight;
+                        data
+                    }
+                    ColorBuffer::Rgb16(data) => {
+                        info.bit_depth = png::BitDepth::Sixteen;
+                        data.iter().flat_map(|value| value.to_be_bytes()).collect()
+                    }
+                };
+
+                if let ColorSpace::Srgb = self.color_space {
+                    info.srgb = Some(png::SrgbRenderingIntent::Perceptual);
+                } else {
+                    // The cHRM chunk is for readers that don't support ICC
+                    // profiles.
+                    let [red, green, blue] = space.primaries.map(|[x, y]| (x, y));
+                    info.source_chromaticities = Some(png::SourceChromaticities::new(
+                        (space.white[0], space.white[1]),
+                        red,
+                        green,
+                        blue,
+                    ));
+                    info.icc_profile = Some(space.icc_profile().into());
+                }
+
+                let mut writer = png::Encoder::with_info(file, info)?.write_header()?;
+                writer.write_image_data(&data)?;
+                writer.finish()?;
+            }
+            (ImageFormat::Jpeg, ColorBuffer::Rgb8(data)) => {
+                let mut jpeg = Vec::new();
+                JpegEncoder::new(&mut jpeg).encode(&data, width, height, ColorType::Rgb8)?;
+                write_jpeg_with_icc_profile(file, &jpeg, &space.icc_profile())?;
+            }
+            (ImageFormat::Tiff, buffer) => {
+                let mut encoder = TiffEncoder::new(file)?;
+                let icc_profile = space.icc_profile();
+
+                match buffer {
+                    ColorBuffer::Rgb8(data) => {
+                        let mut image = encoder.new_image::<colortype::RGB8>(width, height)?;
+                        image
+                            .encoder()
+                            .write_tag(ICC_PROFILE_TAG, &icc_profile[..])?;
+                        image.write_data(&data)?;
+                    }
+                    ColorBuffer::Rgb16(data) => {
+                        let mut image = encoder.new_image::<colortype::RGB16>(width, height)?;
+                        image
+                            .encoder()
+                            .write_tag(ICC_PROFILE_TAG, &icc_profile[..])?;
+                        image.write_data(&data)?;
+                    }
+                }
+            }
+            _ => unreachable!("the pixel format should match the image format"),
+        }
+
+        Ok(())
+    }
+
+    fn save_buffer<P>(
+        &self,
+        width: u32,
+        height: u32,
+        data: Vec<P::Subpixel>,
+    ) -> Result<(), Box<dyn Error>>
+    where
+        P: PixelWithColorType,
+        [P::Subpixel]: EncodableLayout,
+    {
+        ImageBuffer::<P, _>::from_raw(width, height, data)
+            .expect("the pixel buffer should match the image size")
+            .save_with_format(&self.path, self.format)?;
+
+        Ok(())
+    }
+
+    fn save_hdr(&self, width: u32, height: u32, colors: &[[f32; 3]]) -> Result<(), Box<dyn Error>> {
+        let colors: Vec<_> = colors.iter().map(|&color| image::Rgb(color)).collect();
+        let file = BufWriter::new(File::create(&self.path)?);
+        HdrEncoder::new(file).encode(&colors, width as usize, height as usize)?;
+
+        Ok(())
+    }
+
+    fn save_exr(
+        &self,
+        width: u32,
+        height: u32,
+        colors: &[[f32; 3]],
+        chromaticities: Option<Chromaticities>,
+    ) -> Result<(), Box<dyn Error>> {
+        let size = (width as usize, height as usize);
+        let get_color = |Vec2(x, y): Vec2<usize>| colors[x + y * size.0];
+
+        if let PixelFormat::Rgb16F = self.pixel_format {
+            let channels = SpecificChannels::rgb(|position| {
+                let [red, green, blue] = get_color(position);
+                (
+                    f16::from_f32(red),
+                    f16::from_f32(green),
+                    f16::from_f32(blue),
+                )
+            });
+            let mut image = Image::from_channels(size, channels);
+            image.attributes.chromaticities = chromaticities;
+            image.write().to_file(&self.path)?;
+        } else {
+            let channels = SpecificChannels::rgb(|position| {
+                let [red, green, blue] = get_color(position);
+                (red, green, blue)
+            });
+            let mut image = Image::from_channels(size, channels);
+            image.attributes.chromaticities = chromaticities;
+            image.write().to_file(&self.path)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+enum PixelFormat {
+    Rgb8,
+    Rgb16,
+    Rgb16F,
+    Rgb32F,
+}
+
+/// Encoded RGB values, with three components per pixel.
+enum ColorBuffer {
+    Rgb8(Vec<u8>),
+    Rgb16(Vec<u16>),
+}
+
+/// The TIFF tag for embedded ICC profiles.
+const ICC_PROFILE_TAG: Tag = Tag::Unknown(34675);
+
+/// Makes OpenEXR readers treat the RGB channels as X, Y and Z.
+const XYZ_CHROMATICITIES: Chromaticities = Chromaticities {
+    red: Vec2(1.0, 0.0),
+    green: Vec2(0.0, 1.0),
+    blue: Vec2(0.0, 0.0),
+    white: Vec2(1.0 / 3.0, 1.0 / 3.0),
+};
+
+/// Inserts the ICC profile into JPEG data, as APP2 segments right after the
+/// start of image marker.
+fn write_jpeg_with_icc_profile(
+    mut file: impl Write,
+    jpeg: &[u8],
+    icc_profile: &[u8],
+) -> std::io::Result<()> {
+    const SIGNATURE: &[u8] = b"ICC_PROFILE\0";
+    // The segment length includes itself, the signature and the chunk
+    // numbers.
+    const MAX_CHUNK_SIZE: usize = u16::MAX as usize - 2 - SIGNATURE.len() - 2;
+
+    let (start_of_image, rest) = jpeg.split_at(2);
+    file.write_all(start_of_image)?;
+
+    let chunks = icc_profile.chunks(MAX_CHUNK_SIZE);
+    let num_chunks = chunks.len() as u8;
+    for (index, chunk) in chunks.enumerate() {
+        let length = (2 + SIGNATURE.len() + 2 + chunk.len()) as u16;
+        file.write_all(&[0xFF, 0xE2])?;
+        file.write_all(&length.to_be_bytes())?;
+        file.write_all(SIGNATURE)?;
+        file.write_all(&[index as u8 + 1, num_chunks])?;
+        file.write_all(chunk)?;
+    }
+
+    file.write_all(rest)?;
+    file.flush()
+}
+
+/// A file that receives the full spectral data of the film, with one channel
+/// per grain. The file format is chosen from the file extension.
+pub(crate) struct SpectralOutputFile {
+    path: PathBuf,
+    format: SpectralFormat,
+}
+
+impl SpectralOutputFile {
+    pub(crate) fn new(path: PathBuf) -> Result<Self, Box<dyn Error>> {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase);
+
+        let format = match extension.as_deref() {
+            Some("exr") => SpectralFormat::OpenExr,
+            Some("raw" | "img" | "bip") => SpectralFormat::Envi,
+            _ => {
+                return Err(format!(
+                    "unsupported spectral output format for {}, expected OpenEXR (.exr) or ENVI (.raw, .img or .bip)",
+                    path.display()
+                )
+                .into())
+            }
+        };
+
+        Ok(SpectralOutputFile { path, format })
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub(crate) fn save(&self, film: &Film) -> Result<(), Box<dyn Error>> {
+        match self.format {
+            SpectralFormat::OpenExr => self.save_exr(film),
+            SpectralFormat::Envi => self.save_envi(film),
+        }
+    }
+
+    /// Writes one channel per grain, following the naming convention from
+    /// "An OpenEXR Layout for Spectral Images" (Fichet et al. 2021).
+    fn save_exr(&self, film: &Film) -> Result<(), Box<dyn Error>> {
+        let num_pixels = film.width() * film.height();
+        let mut grains = vec![Vec::with_capacity(num_pixels); film.grains_per_pixel()];
+
+        for spectrum in film.developed_pixels() {
+            for (grain, value) in grains.iter_mut().zip(spectrum.grain_values()) {
+                grain.push(value);
+            }
+        }
+
+        let channels = film
+            .grain_wavelengths()
+            .zip(grains)
+            .map(|(wavelength, values)| {
+                let wavelength = format!("{:.2}", wavelength).replace('.', ",");
+                let name = format!("S0.{}nm", wavelength);
+                AnyChannel::new(Text::new_or_panic(name), FlatSamples::F32(values))
+            })
+            .collect();
+
+        let mut image =
+            Image::from_channels((film.width(), film.height()), AnyChannels::sort(channels));
+
+        let (wavelength_start, wavelength_end) = film.wavelength_span();
+        let attributes = &mut image.attributes.other;
+        attributes.insert(
+            Text::new_or_panic("spectralLayoutVersion"),
+            AttributeValue::Text(Text::new_or_panic("1.0")),
+        );
+        attributes.insert(
+            Text::new_or_panic("wavelengthStart"),
+            AttributeValue::F32(wavelength_start),
+        );
+        attributes.insert(
+            Text::new_or_panic("wavelengthEnd"),
+            AttributeValue::F32(wavelength_end),
+        );
+
+        image.write().to_file(&self.path)?;
+
+        Ok(())
+    }
+
+    /// Writes the grains as band interleaved 32 bit floats, with an ENVI
+    /// header next to the data file, named like it with `.hdr` added.
+    fn save_envi(&self, film: &Film) -> Result<(), Box<dyn Error>> {
+        let mut file = BufWriter::new(File::create(&self.path)?);
+        for spectrum in film.developed_pixels() {
+            for value in spectrum.grain_values() {
+                file.write_all(&value.to_le_bytes())?;
+            }
+        }
+        file.flush()?;
+
+        let wavelengths: Vec<_> = film
+            .grain_wavelengths()
+            .map(|wavelength| wavelength.to_string())
+            .collect();
+
+        // The full name is kept, so the header can't replace a `.hdr` image
+        // with the same stem.
+        let mut header_path = self.path.clone().into_os_string();
+        header_path.push(".hdr");
+
+        let mut header = BufWriter::new(File::create(header_path)?);
+        writeln!(header, "ENVI")?;
+        writeln!(header, "description = {{Spectral render from Pyrite}}")?;
+        writeln!(header, "samples = {}", film.width())?;
+        writeln!(header, "lines = {}", film.height())?;
+        writeln!(header, "bands = {}", film.grains_per_pixel())?;
+        writeln!(header, "header offset = 0")?;
+        writeln!(header, "file type = ENVI Standard")?;
+        writeln!(header, "data type = 4")?;
+        writeln!(header, "interleave = bip")?;
+        writeln!(header, "byte order = 0")?;
+        writeln!(header, "wavelength units = Nanometers")?;
+        writeln!(header, "wavelength = {{{}}}", wavelengths.join(", "))?;
+        header.flush()?;
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+enum SpectralFormat {
+    OpenExr,
+    Envi,
+}
@@ -0,0 +1,112 @@
+use std::f32::consts::PI;
+
+/// Decides how much a sample contributes to the pixels around it. The
+/// filters are separable and their radius is measured in pixels.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum PixelFilter {
+    Box { radius: f32 },
+    Tent { radius: f32 },
+    Gaussian { radius: f32, alpha: f32 },
+    Mitchell { radius: f32, b: f32, c: f32 },
+    Lanczos { radius: f32 },
+}
+
+impl PixelFilter {
+    pub fn from_project(project_filter: crate::project::PixelFilter) -> Self {
+        match project_filter {
+            crate::project::PixelFilter::Box { radius } => PixelFilter::Box {
+                radius: radius.unwrap_or(0.5),
+            },
+            crate::project::PixelFilter::Tent { radius } => PixelFilter::Tent {
+                radius: radius.unwrap_or(1.0),
+            },
+            crate::project::PixelFilter::Gaussian { radius, alpha } => PixelFilter::Gaussian {
+                radius: radius.unwrap_or(1.5),
+                alpha: alpha.unwrap_or(2.0),
+            },
+            crate::project::PixelFilter::Mitchell { radius, b, c } => PixelFilter::Mitchell {
+                radius: radius.unwrap_or(2.0),
+                b: b.unwrap_or(1.0 / 3.0),
+                c: c.unwrap_or(1.0 / 3.0),
+            },
+            crate::project::PixelFilter::Lanczos { radius } => PixelFilter::Lanczos {
+                radius: radius.unwrap_or(3.0),
+            },
+        }
+    }
+
+    pub fn radius(&self) -> f32 {
+        match *self {
+            PixelFilter::Box { radius }
+            | PixelFilter::Tent { radius }
+            | PixelFilter::Gaussian { radius, .. }
+            | PixelFilter::Mitchell { radius, .. }
+            | PixelFilter::Lanczos { radius } => radius,
+        }
+    }
+
+    /// The weight of a sample along one axis, where `x` is the distance from
+    /// the pixel center. The weight may be negative for filters with negative
+    /// lobes.
+    pub fn evaluate(&self, x: f32) -> f32 {
+        match *self {
+            // Half open, to not let samples on the edge hit two pixels.
+            PixelFilter::Box { radius } => {
+                if x > -radius && x <= radius {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            PixelFilter::Tent { radius } => (radius - x.abs()).max(0.0),
+            PixelFilter::Gaussian { radius, alpha } => {
+                ((-alpha * x * x).exp() - (-alpha * radius * radius).exp()).max(0.0)
+            }
+            PixelFilter::Mitchell { radius, b, c } => {
+                if x.abs() > radius {
+                    return 0.0;
+                }
+
+                let x = (2.0 * x / radius).abs();
+                let x2 = x * x;
+                let x3 = x2 * x;
+
+                if x > 1.0 {
+                    ((-b - 6.0 * c) * x3
+                        + (6.0 * b + 30.0 * c) * x2
+                        + (-12.0 * b - 48.0 * c) * x
+                        + (8.0 * b + 24.0 * c))
+                        / 6.0
+                } else {
+                    ((12.0 - 9.0 * b - 6.0 * c) * x3
+                        + (-18.0 + 12.0 * b + 6.0 * c) * x2
+                        + (6.0 - 2.0 * b))
+                        / 6.0
+                }
+            }
+            PixelFilter::Lanczos { radius } => {
+                if x.abs() > radius {
+                    0.0
+                } else {
+                    sinc(x) * sinc(x / radius)
+                }
+            }
+        }
+    }
+}
+
+impl Default for PixelFilter {
+    /// A box filter that covers exactly one pixel.
+    fn default() -> Self {
+        PixelFilter::Box { radius: 0.5 }
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    let x = (x * PI).abs();
+    if x < 1e-5 {
+        1.0
+    } else {
+        x.sin() / x
+    }
+}
@@ -158,6 +158,17 @@ function spectrum(properties)
     return properties
 end
 
+-- Loads a spectrum curve from a CSV file, where the first column is the
+-- wavelength. The column is the value column to read, starting from 1.
+function csv_spectrum(path, column)
+    local points = {}
+    for _, row in ipairs(read_csv(path)) do
+        table.insert(points, {row[1], row[(column or 1) + 1]})
+    end
+
+    return spectrum {format = "curve", points = points}
+end
+
 function rgb(red, green, blue)
     local properties = {
         type = "rgb",
@@ -272,6 +283,42 @@ transform = {
         _pyrite.make_basic(properties)
         return properties
     end,
+    matrix = function(properties)
+        properties.type = "matrix"
+        _pyrite.make_basic(properties)
+        return properties
+    end,
+    translate = function(properties)
+        properties.type = "translate"
+        _pyrite.make_basic(properties)
+        return properties
+    end,
+    -- Rotates around `axis`, or around the X, Y and Z axes in `order`.
+    rotate = function(properties)
+        if properties.axis == nil then
+            properties.type = "euler"
+        else
+            properties.type = "rotate"
+        end
+        _pyrite.make_basic(properties)
+        return properties
+    end,
+    quaternion = function(properties)
+        properties.type = "quaternion"
+        _pyrite.make_basic(properties)
+        return properties
+    end,
+    scale = function(properties)
+        properties.type = "scale"
+        _pyrite.make_basic(properties)
+        return properties
+    end,
+    -- The transforms are applied in order, starting with the first.
+    compose = function(transforms)
+        local properties = {type = "compose", transforms = transforms}
+        _pyrite.make_basic(properties)
+        return properties
+    end,
 }
 
 camera = {
@@ -280,6 +327,72 @@ camera = {
         _pyrite.make_basic(properties)
         return properties
     end,
+    orthographic = function(properties)
+        properties.type = "orthographic"
+        _pyrite.make_basic(properties)
+        return properties
+    end,
+    equirectangular = function(properties)
+        properties.type = "equirectangular"
+        _pyrite.make_basic(properties)
+        return properties
+    end,
+    fisheye = function(properties)
+        properties.type = "fisheye"
+        _pyrite.make_basic(properties)
+        return properties
+    end,
+    lens_system = function(properties)
+        properties.type = "lens_system"
+        _pyrite.make_basic(properties)
+        return properties
+    end,
+    stereo = function(properties)
+        properties.type = "stereo"
+        _pyrite.make_basic(properties)
+        return properties
+    end,
+}
+
+aperture_shape = {
+    polygon = function(properties)
+        properties.type = "polygon"
+        _pyrite.make_basic(properties)
+        return properties
+    end,
+    texture = function(properties)
+        properties.type = "texture"
+        _pyrite.make_basic(properties)
+        return properties
+    end,
+}
+
+pixel_filter = {
+    box = function(properties)
+        properties.type = "box"
+        _pyrite.make_basic(properties)
+        return properties
+    end,
+    tent = function(properties)
+        properties.type = "tent"
+        _pyrite.make_basic(properties)
+        return properties
+    end,
+    gaussian = function(properties)
+        properties.type = "gaussian"
+        _pyrite.make_basic(properties)
+        return properties
+    end,
+    mitchell = function(properties)
+        properties.type = "mitchell"
+        _pyrite.make_basic(properties)
+        return properties
+    end,
+    lanczos = function(properties)
+        properties.type = "lanczos"
+        _pyrite.make_basic(properties)
+        return properties
+    end,
 }
 
 renderer = {
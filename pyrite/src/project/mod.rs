@@ -2,19 +2,19 @@ use std::{collections::HashMap, error::Error, path::Path};
 
 use mlua::Lua;
 
-use cgmath::{Matrix4, SquareMatrix, Vector3};
+use cgmath::{Deg, InnerSpace, Matrix4, Quaternion, SquareMatrix, Vector3};
 
 use path_slash::PathBufExt;
 use typed_nodes::Key;
 
-use eval_context::{EvalContext, Evaluate};
+use eval_context::{EvalContext, Evaluate, EvaluateOr};
 use meshes::{MeshId, MeshLoader, Meshes};
 use tables::Tables;
-use textures::{TextureLoader, Textures};
+use textures::{MonoTextureId, TextureLoader, Textures};
 
 use self::{
     parse_context::ParseContext,
-    spectra::{Spectra, SpectrumLoader},
+    spectra::{Spectra, SpectrumId, SpectrumLoader},
 };
 
 pub(crate) mod eval_context;
@@ -26,7 +26,10 @@ pub(crate) mod spectra;
 mod tables;
 pub(crate) mod textures;
 
-pub(crate) fn load_project<'p, P: AsRef<Path>>(path: P) -> Result<ProjectData, Box<dyn Error>> {
+pub(crate) fn load_project<'p, P: AsRef<Path>>(
+    path: P,
+    overrides: &Overrides,
+) -> Result<ProjectData, Box<dyn Error>> {
     let project_dir = path
         .as_ref()
         .parent()
@@ -52,14 +55,27 @@ pub(crate) fn load_project<'p, P: AsRef<Path>>(path: P) -> Result<ProjectData, B
         lua.create_function(move |_context, table: mlua::Table| lua_tables.assign_id(&table))?;
     lua.globals().set("assign_id", assign_id)?;
 
+    // Register read_csv
+    let csv_dir = project_dir.to_owned();
+    let read_csv = lua.create_function(move |_context, path: String| {
+        read_csv(&csv_dir.join(path)).map_err(mlua::Error::external)
+    })?;
+    lua.globals().set("read_csv", read_csv)?;
+
     // Load project building library
     lua.load(include_str!("lib.lua"))
         .set_name("<pyrite>/lib.lua")?
         .exec()?;
 
+    // Set globals from the command line
+    for (key, value) in &overrides.globals {
+        lua.globals()
+            .set(key.as_str(), parse_global_value(&lua, value)?)?;
+    }
+
     // Run project file
     let project_file = std::fs::read_to_string(&path)?;
-    let project = lua
+    let project: mlua::Value = lua
         .load(&project_file)
         .set_name(
             path.as_ref()
@@ -69,6 +85,8 @@ pub(crate) fn load_project<'p, P: AsRef<Path>>(path: P) -> Result<ProjectData, B
         )?
         .eval()?;
 
+    overrides.apply(&project)?;
+
     // Parse project config
     let mut nodes = Nodes::new();
     let mut meshes = MeshLoader::new(project_dir);
@@ -92,6 +110,86 @@ pub(crate) fn load_project<'p, P: AsRef<Path>>(path: P) -> Result<ProjectData, B
     })
 }
 
+/// Project settings that are replaced after the project file has run, and
+/// additional global variables for the project file.
+#[derive(Default)]
+pub(crate) struct Overrides {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub file: Option<String>,
+    pub pixel_samples: Option<u32>,
+    pub threads: Option<usize>,
+    pub time_limit: Option<f32>,
+    pub renderer: Option<String>,
+    pub globals: Vec<(String, String)>,
+}
+
+impl Overrides {
+    fn apply(&self, project: &mlua::Value) -> mlua::Result<()> {
+        // Anything else than a table is reported when parsing the project.
+        let mlua::Value::Table(project) = project else {
+            return Ok(());
+        };
+
+        set_field(project, "image", "width", self.width)?;
+        set_field(project, "image", "height", self.height)?;
+        set_field(project, "image", "file", self.file.as_deref())?;
+        set_field(project, "renderer", "pixel_samples", self.pixel_samples)?;
+        set_field(project, "renderer", "threads", self.threads)?;
+        set_field(project, "renderer", "time_limit", self.time_limit)?;
+        set_field(project, "renderer", "type", self.renderer.as_deref())?;
+
+        Ok(())
+    }
+}
+
+fn set_field<'lua, T: mlua::ToLua<'lua>>(
+    project: &mlua::Table<'lua>,
+    table: &str,
+    key: &str,
+    value: Option<T>,
+) -> mlua::Result<()> {
+    if let Some(value) = value {
+        project.get::<_, mlua::Table>(table)?.set(key, value)?;
+    }
+
+    Ok(())
+}
+
+/// Reads the rows of a CSV file with numeric columns. Rows with other values,
+/// such as headers, are skipped.
+fn read_csv(path: &Path) -> Result<Vec<Vec<f64>>, Box<dyn Error + Send + Sync>> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|error| format!("could not read {}: {}", path.display(), error))?;
+
+    let rows = content
+        .lines()
+        .filter_map(|line| {
+            line.split(',')
+                .map(|value| value.trim().parse().ok())
+                .collect::<Option<Vec<f64>>>()
+        })
+        .collect();
+
+    Ok(rows)
+}
+
+/// Interprets command line values as Lua integers, numbers, booleans or
+/// strings, in that order.
+fn parse_global_value<'lua>(lua: &'lua Lua, value: &str) -> mlua::Result<mlua::Value<'lua>> {
+    let value = if let Ok(integer) = value.parse() {
+        mlua::Value::Integer(integer)
+    } else if let Ok(number) = value.parse() {
+        mlua::Value::Number(number)
+    } else if let Ok(boolean) = value.parse() {
+        mlua::Value::Boolean(boolean)
+    } else {
+        mlua::Value::String(lua.create_string(value)?)
+    };
+
+    Ok(value)
+}
+
 pub(crate) struct ProjectData {
     pub nodes: Nodes,
     pub meshes: Meshes,
@@ -113,19 +211,211 @@ pub struct Image {
     pub width: u32,
     pub height: u32,
     pub file: Option<String>,
+    pub bit_depth: Option<u32>,
+    pub color_space: Option<ColorSpace>,
+    pub spectral_file: Option<String>,
+    pub pixel_filter: Option<PixelFilter>,
+    pub aovs: Option<Aovs>,
+    pub denoiser: Option<Denoiser>,
+    pub exposure: Option<f32>,
+    pub iso: Option<f32>,
+    pub shutter_speed: Option<f32>,
+    pub f_number: Option<f32>,
+    pub tone_mapping: Option<ToneMapping>,
+    pub white_luminance: Option<f32>,
+    pub rgb_curves: Option<RgbCurves>,
     pub filter: Option<expressions::Expression>,
     pub white: Option<expressions::Expression>,
+    pub white_temperature: Option<f32>,
+    pub white_illuminant: Option<Illuminant>,
+    pub chromatic_adaptation: Option<ChromaticAdaptation>,
+}
+
+#[derive(typed_nodes::FromLua)]
+pub struct Denoiser {
+    pub iterations: Option<u32>,
+    pub color_sigma: Option<f32>,
+    pub normal_sigma: Option<f32>,
+    pub albedo_sigma: Option<f32>,
+}
+
+/// Output files for the auxiliary buffers.
+#[derive(typed_nodes::FromLua)]
+pub struct Aovs {
+    pub depth: Option<String>,
+    pub normal: Option<String>,
+    pub texture_coordinates: Option<String>,
+    pub albedo: Option<String>,
+    pub object_id: Option<String>,
+    pub direct: Option<String>,
+    pub indirect: Option<String>,
+    /// The base name for one image per bidirectional connection strategy.
+    pub strategies: Option<String>,
+}
+
+#[derive(Copy, Clone, typed_nodes::FromLua)]
+pub enum ColorSpace {
+    Srgb,
+    DisplayP3,
+    Rec2020,
+    AdobeRgb,
+    AcesCg,
+    Xyz,
+}
+
+/// The CIE standard illuminants that can be used as white points.
+#[derive(Copy, Clone, typed_nodes::FromLua)]
+pub enum Illuminant {
+    A,
+    D50,
+    D55,
+    D65,
+    D75,
+    E,
+    F2,
+    F7,
+    F11,
+}
+
+#[derive(Copy, Clone, typed_nodes::FromLua)]
+pub enum ChromaticAdaptation {
+    Bradford,
+    Cat02,
+    VonKries,
+}
+
+/// The sensitivity of each color channel of a camera sensor or film stock.
+#[derive(typed_nodes::FromLua)]
+pub struct RgbCurves {
+    pub red: SpectrumId,
+    pub green: SpectrumId,
+    pub blue: SpectrumId,
+}
+
+#[derive(Copy, Clone, typed_nodes::FromLua)]
+pub enum ToneMapping {
+    Clip,
+    Reinhard,
+    Aces,
+    Agx,
+}
+
+#[derive(typed_nodes::FromLua)]
+pub enum PixelFilter {
+    Box {
+        radius: Option<f32>,
+    },
+    Tent {
+        radius: Option<f32>,
+    },
+    Gaussian {
+        radius: Option<f32>,
+        alpha: Option<f32>,
+    },
+    Mitchell {
+        radius: Option<f32>,
+        b: Option<f32>,
+        c: Option<f32>,
+    },
+    Lanczos {
+        radius: Option<f32>,
+    },
 }
 
 #[derive(typed_nodes::FromLua)]
 pub enum Camera {
     Perspective {
         transform: Transform,
+        motion: Option<CameraMotion>,
 
         fov: self::expressions::Expression,
         focus_distance: Option<self::expressions::Expression>,
         aperture: Option<self::expressions::Expression>,
+        aperture_shape: Option<ApertureShape>,
+        vignetting: Option<self::expressions::Expression>,
     },
+    Orthographic {
+        transform: Transform,
+        motion: Option<CameraMotion>,
+
+        width: self::expressions::Expression,
+    },
+    Equirectangular {
+        transform: Transform,
+        motion: Option<CameraMotion>,
+    },
+    Fisheye {
+        transform: Transform,
+        motion: Option<CameraMotion>,
+
+        fov: Option<self::expressions::Expression>,
+        projection: Option<FisheyeProjection>,
+    },
+    LensSystem {
+        transform: Transform,
+        motion: Option<CameraMotion>,
+
+        file: String,
+        sensor_width: Option<self::expressions::Expression>,
+        focus_distance: Option<self::expressions::Expression>,
+        aperture: Option<self::expressions::Expression>,
+    },
+    Stereo {
+        transform: Transform,
+        motion: Option<CameraMotion>,
+
+        projection: Option<StereoProjection>,
+        fov: Option<self::expressions::Expression>,
+        interocular_distance: Option<self::expressions::Expression>,
+        convergence_distance: Option<self::expressions::Expression>,
+        layout: Option<StereoLayout>,
+    },
+}
+
+/// Additional camera transforms for moving cameras. The camera's own
+/// transform is used at time 0.
+#[derive(typed_nodes::FromLua)]
+pub struct CameraMotion {
+    pub shutter_open: Option<f32>,
+    pub shutter_close: Option<f32>,
+    pub keyframes: Vec<TransformKeyframe>,
+}
+
+#[derive(typed_nodes::FromLua)]
+pub struct TransformKeyframe {
+    pub time: f32,
+    pub transform: Transform,
+}
+
+#[derive(typed_nodes::FromLua)]
+pub enum ApertureShape {
+    Polygon {
+        blades: u32,
+        rotation: Option<f32>,
+        curvature: Option<f32>,
+    },
+    Texture {
+        texture: MonoTextureId,
+    },
+}
+
+#[derive(Copy, Clone, typed_nodes::FromLua)]
+pub enum FisheyeProjection {
+    Equidistant,
+    Equisolid,
+}
+
+#[derive(Copy, Clone, typed_nodes::FromLua)]
+pub enum StereoProjection {
+    Perspective,
+    Omnidirectional,
+}
+
+#[derive(Copy, Clone, typed_nodes::FromLua)]
+pub enum StereoLayout {
+    SideBySide,
+    TopBottom,
+    Separate,
 }
 
 #[derive(typed_nodes::FromLua)]
@@ -138,6 +428,9 @@ pub enum Renderer {
         #[typed_nodes(flatten)]
         shared: RendererShared,
         light_bounces: Option<u32>,
+        /// The longest paths, in bounces, that get their own strategy
+        /// images.
+        strategy_bounces: Option<u32>,
     },
     PhotonMapping {
         #[typed_nodes(flatten)]
@@ -146,6 +439,11 @@ pub enum Renderer {
         photon_bounces: Option<u32>,
         photons: Option<usize>,
         photon_passes: Option<usize>,
+        /// Shrinks the radius for each pixel over the passes.
+        progressive: Option<bool>,
+        /// The fraction of the new photons that is kept when the radius
+        /// shrinks.
+        alpha: Option<f32>,
     },
 }
 
@@ -158,6 +456,9 @@ pub struct RendererShared {
     pub spectrum_samples: Option<u32>,
     pub spectrum_resolution: Option<usize>,
     pub tile_size: Option<usize>,
+    pub time_limit: Option<f32>,
+    pub noise_limit: Option<f32>,
+    pub adaptive_sampling: Option<f32>,
 }
 
 #[derive(typed_nodes::FromLua)]
@@ -249,6 +550,61 @@ pub enum Transform {
         to: self::expressions::Expression,
         up: Option<self::expressions::Expression>,
     },
+    /// A 4x4 matrix, written row by row.
+    Matrix { values: Vec<f32> },
+    Translate {
+        offset: self::expressions::Expression,
+    },
+    /// A rotation around an axis, in degrees.
+    Rotate {
+        axis: self::expressions::Expression,
+        angle: self::expressions::Expression,
+    },
+    Quaternion {
+        x: self::expressions::Expression,
+        y: self::expressions::Expression,
+        z: self::expressions::Expression,
+        w: self::expressions::Expression,
+    },
+    /// Rotations around the X, Y and Z axes, in degrees, applied in `order`.
+    Euler {
+        x: Option<self::expressions::Expression>,
+        y: Option<self::expressions::Expression>,
+        z: Option<self::expressions::Expression>,
+        order: Option<EulerOrder>,
+    },
+    Scale {
+        factor: self::expressions::Expression,
+    },
+    /// Applies the transforms in order, starting with the first.
+    Compose {
+        #[typed_nodes(recursive)]
+        transforms: Vec<Transform>,
+    },
+}
+
+#[derive(Copy, Clone, typed_nodes::FromLua)]
+pub enum EulerOrder {
+    Xyz,
+    Xzy,
+    Yxz,
+    Yzx,
+    Zxy,
+    Zyx,
+}
+
+impl EulerOrder {
+    /// The axis indices, in the order they are applied.
+    fn axes(self) -> [usize; 3] {
+        match self {
+            EulerOrder::Xyz => [0, 1, 2],
+            EulerOrder::Xzy => [0, 2, 1],
+            EulerOrder::Yxz => [1, 0, 2],
+            EulerOrder::Yzx => [1, 2, 0],
+            EulerOrder::Zxy => [2, 0, 1],
+            EulerOrder::Zyx => [2, 1, 0],
+        }
+    }
 }
 
 impl Evaluate<Matrix4<f32>> for Transform {
@@ -264,6 +620,76 @@ impl Evaluate<Matrix4<f32>> for Transform {
                     .invert()
                     .ok_or("could not invert view matrix")?
             }
+            Transform::Matrix { values } => {
+                if values.len() != 16 {
+                    return Err(format!(
+                        "a transform matrix has 16 values, but found {}",
+                        values.len()
+                    )
+                    .into());
+                }
+
+                // The values are written row by row, but the matrix is
+                // indexed by column first.
+                let mut matrix = Matrix4::identity();
+                for (index, &value) in values.iter().enumerate() {
+                    matrix[index % 4][index / 4] = value;
+                }
+                matrix
+            }
+            Transform::Translate { offset } => Matrix4::from_translation(offset.evaluate(context)?),
+            Transform::Rotate { axis, angle } => {
+                let axis: Vector3<f32> = axis.evaluate(context)?;
+                let angle: f32 = angle.evaluate(context)?;
+
+                if axis.magnitude2() == 0.0 {
+                    return Err("the rotation axis can't have zero length".into());
+                }
+
+                Matrix4::from_axis_angle(axis.normalize(), Deg(angle))
+            }
+            Transform::Quaternion { x, y, z, w } => {
+                let rotation = Quaternion::new(
+                    w.evaluate(context)?,
+                    x.evaluate(context)?,
+                    y.evaluate(context)?,
+                    z.evaluate(context)?,
+                );
+
+                if rotation.magnitude2() == 0.0 {
+                    return Err("the rotation quaternion can't have zero length".into());
+                }
+
+                Matrix4::from(rotation.normalize())
+            }
+            Transform::Euler { x, y, z, order } => {
+                let x: f32 = x.evaluate_or(context, 0.0)?;
+                let y: f32 = y.evaluate_or(context, 0.0)?;
+                let z: f32 = z.evaluate_or(context, 0.0)?;
+                let rotations = [
+                    Matrix4::from_angle_x(Deg(x)),
+                    Matrix4::from_angle_y(Deg(y)),
+                    Matrix4::from_angle_z(Deg(z)),
+                ];
+
+                let order = order.unwrap_or(EulerOrder::Xyz);
+                let mut matrix = Matrix4::identity();
+                for axis in order.axes() {
+                    matrix = rotations[axis] * matrix;
+                }
+                matrix
+            }
+            Transform::Scale { factor } => {
+                let factor: Vector3<f32> = factor.evaluate(context)?;
+                Matrix4::from_nonuniform_scale(factor.x, factor.y, factor.z)
+            }
+            Transform::Compose { transforms } => {
+                let mut matrix = Matrix4::identity();
+                for transform in transforms {
+                    matrix = transform.evaluate(context)? * matrix;
+                }
+                matrix
+            }
         })
     }
 }
@@ -6,7 +6,7 @@ use rand::Rng;
 
 use crate::{
     cameras::Camera,
-    film::{Area, Sample},
+    film::{Area, Film, Sample},
     program::ExecutionContext,
     tracer::{self, Bounce, RenderContext},
 };
@@ -16,6 +16,27 @@ pub(crate) fn contribute<'a>(
     main_sample: &mut (Sample, f32),
     additional_samples: &mut [(Sample, f32)],
     exe: &mut ExecutionContext<'a>,
+) {
+    add_light(bounce, main_sample, additional_samples, false, exe);
+}
+
+/// Like `contribute`, but only adds the light from the sky and directional
+/// lights, which are far away from the scene.
+pub(crate) fn contribute_distant<'a>(
+    bounce: &Bounce<'a>,
+    main_sample: &mut (Sample, f32),
+    additional_samples: &mut [(Sample, f32)],
+    exe: &mut ExecutionContext<'a>,
+) {
+    add_light(bounce, main_sample, additional_samples, true, exe);
+}
+
+fn add_light<'a>(
+    bounce: &Bounce<'a>,
+    main_sample: &mut (Sample, f32),
+    additional_samples: &mut [(Sample, f32)],
+    distant_only: bool,
+    exe: &mut ExecutionContext<'a>,
 ) {
     let &Bounce {
         ref ty,
@@ -27,9 +48,15 @@ pub(crate) fn contribute<'a>(
         texture,
         probability,
         ref direct_light,
+        object_id: _,
+        distant,
     } = bounce;
 
     if ty.is_emission() {
+        if distant_only && !distant {
+            return;
+        }
+
         let initial_input = RenderContext {
             wavelength: main_sample.0.wavelength,
             incident,
@@ -63,7 +90,12 @@ pub(crate) fn contribute<'a>(
         }
 
         for direct in direct_light {
+            if distant_only && !direct.directional {
+                continue;
+            }
+
             let &tracer::DirectLight {
+                directional: _,
                 dispersed: l_dispersed,
                 color: l_color,
                 incident: l_incident,
@@ -99,8 +131,10 @@ pub(crate) fn contribute<'a>(
     }
 }
 
+#[derive(Clone)]
 pub struct Tile {
     pub area: Area<f32>,
+    pub pixels: Area<usize>,
     pub width: usize,
     pub height: usize,
 }
@@ -176,6 +210,7 @@ pub(crate) fn make_tiles(
             );
             tiles.push(Tile {
                 area: camera.to_view_area(&Area::new(start, size), film_width, film_height),
+                pixels: Area::new(start, size),
                 width: size.x,
                 height: size.y,
             });
@@ -186,3 +221,59 @@ pub(crate) fn make_tiles(
 
     tiles
 }
+
+/// Distributes `pixel_samples` samples per pixel, on average, over the tiles,
+/// in proportion to their estimated relative error. Tiles that get more than
+/// `max_task_samples` samples per pixel are split into multiple tasks, to keep
+/// all threads busy.
+pub(crate) fn distribute_samples(
+    film: &Film,
+    tiles: Vec<Tile>,
+    pixel_samples: u32,
+    max_task_samples: u32,
+) -> Vec<(Tile, u32)> {
+    let errors: Vec<f32> = tiles.iter().map(|tile| tile_error(film, tile)).collect();
+    let total_error: f32 = errors.iter().sum();
+    let total_samples = pixel_samples as f32 * (film.width() * film.height()) as f32;
+
+    let mut tasks = Vec::with_capacity(tiles.len());
+
+    for (tile, error) in tiles.into_iter().zip(errors) {
+        let mut samples = if total_error > 0.0 {
+            (total_samples * error / total_error / tile.area() as f32).round() as u32
+        } else {
+            pixel_samples
+        };
+
+        while samples > max_task_samples {
+            tasks.push((tile.clone(), max_task_samples));
+            samples -= max_task_samples;
+        }
+
+        if samples > 0 {
+            tasks.push((tile, samples));
+        }
+    }
+
+    tasks
+}
+
+/// The sum of the relative errors of the pixels in a tile. Pixels without an
+/// error estimate count as fully uncertain.
+fn tile_error(film: &Film, tile: &Tile) -> f32 {
+    let Area { from, size } = tile.pixels;
+    let mut error = 0.0;
+
+    for y in from.y..from.y + size.y {
+        for x in from.x..from.x + size.x {
+            if let Some(estimate) = film.estimate_pixel(Point2::new(x, y)) {
+                error += (estimate.error / (estimate.brightness + ERROR_EPSILON)).min(1.0);
+            }
+        }
+    }
+
+    error
+}
+
+/// Keeps dark pixels from dominating the relative error.
+const ERROR_EPSILON: f32 = 0.001;
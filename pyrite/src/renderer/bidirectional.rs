@@ -5,34 +5,117 @@ use cgmath::{EuclideanSpace, InnerSpace, Point2, Vector3};
 use collision::Ray3;
 
 use super::{
-    algorithm::{contribute, make_tiles, Tile},
+    algorithm::{distribute_samples, make_tiles, Tile},
     LocalProgress, Progress, Renderer, TaskRunner,
 };
-use crate::cameras::Camera;
-use crate::film::{Film, Sample};
-use crate::lamp::{RaySample, Surface};
-use crate::tracer::{trace, Bounce, BounceType};
-use crate::utils::pairs;
+use crate::aovs::{Aovs, Strategy};
+use crate::cameras::{Camera, CameraRay};
+use crate::film::{AspectRatio, Film, Sample};
+use crate::lamp::{self, Lamp, RaySample, Surface};
+use crate::tracer::{trace_directional, Bounce, BounceType, NormalInput, RenderContext};
 use crate::{
-    materials::ProbabilityInput,
+    materials::{ProbabilityInput, Scattering},
     math::DIST_EPSILON,
     program::{ExecutionContext, Resources},
     world::World,
 };
 use std::{
     cell::Cell,
+    f32::consts::PI,
     time::{Duration, Instant},
 };
 
+/// The probability density of light leaving a lamp in any direction, per
+/// solid angle. Emissive surfaces are seen from both sides, so they emit
+/// in both directions.
+const EMISSION_DENSITY: f32 = 0.25 / PI;
+
 pub struct BidirParams {
     pub bounces: u32,
+    /// The longest paths, in bounces, that get their own strategy images.
+    pub strategy_bounces: u32,
+}
+
+/// The strategies that can be saved as separate images, from the shortest
+/// paths to the longest.
+pub(crate) fn strategies(renderer: &Renderer, config: &BidirParams) -> Vec<Strategy> {
+    let limits = Limits::new(renderer, config);
+    let mut strategies = Vec::new();
+
+    // The shortest paths only have the camera and a light source.
+    for vertices in 2..=config.strategy_bounces as usize + 2 {
+        for camera_vertices in 1..=vertices.min(limits.camera_vertices) {
+            let light_vertices = vertices - camera_vertices;
+            if light_vertices <= limits.light_vertices {
+                strategies.push(Strategy {
+                    light_vertices,
+                    camera_vertices,
+                });
+            }
+        }
+    }
+
+    strategies
 }
 
 pub(crate) fn render<F: FnMut(Progress<'_>)>(
     film: &Film,
-    task_runner: TaskRunner,
+    aovs: &Aovs,
+    task_runner: &TaskRunner,
     mut on_status: F,
     renderer: &Renderer,
+    pixel_samples: u32,
+    config: &BidirParams,
+    world: &World,
+    camera: &Camera,
+    resources: &Resources,
+) {
+    let (uniform_samples, adaptive_samples) = renderer.split_adaptive_samples(pixel_samples);
+
+    let tiles = make_tiles(film.width(), film.height(), renderer.tile_size, camera);
+    render_tiles(
+        "Rendering",
+        tiles
+            .into_iter()
+            .map(|tile| (tile, uniform_samples))
+            .collect(),
+        film,
+        aovs,
+        task_runner,
+        &mut on_status,
+        renderer,
+        config,
+        world,
+        camera,
+        resources,
+    );
+
+    if adaptive_samples > 0 {
+        let tiles = make_tiles(film.width(), film.height(), renderer.tile_size, camera);
+        render_tiles(
+            "Adaptive sampling",
+            distribute_samples(film, tiles, adaptive_samples, uniform_samples),
+            film,
+            aovs,
+            task_runner,
+            &mut on_status,
+            renderer,
+            config,
+            world,
+            camera,
+            resources,
+        );
+    }
+}
+
+fn render_tiles<F: FnMut(Progress<'_>)>(
+    status_message: &str,
+    tiles: Vec<(Tile, u32)>,
+    film: &Film,
+    aovs: &Aovs,
+    task_runner: &TaskRunner,
+    on_status: &mut F,
+    renderer: &Renderer,
     config: &BidirParams,
     world: &World,
     camera: &Camera,
@@ -42,29 +125,43 @@ pub(crate) fn render<F: FnMut(Progress<'_>)>(
         XorShiftRng::from_rng(rand::thread_rng()).expect("could not generate RNG")
     }
 
-    let tiles = make_tiles(film.width(), film.height(), renderer.tile_size, camera);
-
-    let status_message = "Rendering";
     on_status(Progress {
         progress: 0,
-        message: &status_message,
+        message: status_message,
+        pass_finished: false,
     });
 
     let mut progress: usize = 0;
     let num_tiles = tiles.len();
+    let density = SampleDensity::new(film, renderer.tile_size, &tiles);
 
     task_runner.run_tasks(
-        tiles.into_iter().map(|f| (f, gen_rng())),
-        |index, (tile, rng), progress| {
+        tiles
+            .into_iter()
+            .map(|(tile, pixel_samples)| (tile, pixel_samples, gen_rng())),
+        |index, (tile, pixel_samples, rng), progress| {
             render_tile(
-                index, rng, tile, film, camera, world, resources, renderer, config, progress,
+                index,
+                rng,
+                tile,
+                film,
+                aovs,
+                camera,
+                world,
+                resources,
+                renderer,
+                pixel_samples,
+                config,
+                &density,
+                progress,
             );
         },
         |_, _| {
             progress += 1;
             on_status(Progress {
                 progress: ((progress * 100) / num_tiles) as u8,
-                message: &status_message,
+                message: status_message,
+                pass_finished: false,
             });
         },
     );
@@ -75,19 +172,34 @@ fn render_tile<R: Rng>(
     mut rng: R,
     tile: Tile,
     film: &Film,
+    aovs: &Aovs,
     camera: &Camera,
     world: &World,
     resources: &Resources,
     renderer: &Renderer,
+    pixel_samples: u32,
     bidir_params: &BidirParams,
+    density: &SampleDensity,
     progress: LocalProgress,
 ) {
-    let mut lamp_path = Vec::with_capacity(bidir_params.bounces as usize + 1);
-    let mut camera_path = Vec::with_capacity(renderer.bounces as usize);
-    let mut additional_samples = Vec::with_capacity(renderer.spectrum_samples as usize - 1);
+    let connections = Connections {
+        world,
+        camera,
+        aovs,
+        limits: Limits {
+            camera_connectable: camera.is_connectable(),
+            ..Limits::new(renderer, bidir_params)
+        },
+        view_area: view_area(film),
+    };
+
+    let mut camera_path = Subpath::with_capacity(renderer.bounces as usize);
+    let mut light_path = Subpath::with_capacity(bidir_params.bounces as usize + 1);
+    let mut wavelengths = Vec::with_capacity(renderer.spectrum_samples as usize);
+    let mut contributions = Contributions::default();
     let mut exe = ExecutionContext::new(resources);
 
-    let iterations = tile.area() as u64 * renderer.pixel_samples as u64;
+    let iterations = tile.area() as u64 * pixel_samples as u64;
     let message = format!("Tile {}", index);
     let mut last_progress = Instant::now();
     progress.show(message, iterations);
@@ -98,301 +210,1155 @@ fn render_tile<R: Rng>(
             last_progress = Instant::now();
         }
 
-        lamp_path.clear();
-        camera_path.clear();
-        additional_samples.clear();
-
         let position = tile.sample_point(&mut rng);
-        additional_samples.extend(
-            film.sample_many_wavelengths(&mut rng, renderer.spectrum_samples as usize)
-                .map(|wavelength| {
-                    (
-                        Sample {
-                            wavelength,
-                            brightness: 0.0,
-                            weight: 1.0,
-                        },
-                        1.0,
-                    )
-                }),
-        );
 
-        let mut main_sample =
-            additional_samples.swap_remove(rng.gen_range(0..additional_samples.len()));
-        let wavelength = main_sample.0.wavelength;
-
-        let camera_ray = camera.ray_towards(&position, &mut rng);
-        let lamp_sample = world
-            .pick_lamp(&mut rng)
-            .and_then(|(l, p)| l.sample_ray(&mut rng).map(|r| (r, p)));
-        if let Some((lamp_sample, probability)) = lamp_sample {
-            let RaySample {
-                mut ray,
-                surface,
-                weight,
-            } = lamp_sample;
-
-            let (color, material_probability, dispersed, normal, texture) = match surface {
-                Surface::Physical {
-                    normal,
-                    material,
-                    texture,
-                } => {
-                    let component = material.choose_emissive(&mut rng);
-                    let input = ProbabilityInput {
-                        wavelength,
-                        wavelength_used: Cell::new(false),
-                        normal,
-                        incident: -ray.direction,
-                        texture_coordinate: texture,
-                    };
+        // The first wavelength is the main wavelength, which decides how the
+        // paths are scattered.
+        wavelengths.clear();
+        wavelengths
+            .extend(film.sample_many_wavelengths(&mut rng, renderer.spectrum_samples as usize));
+        let main_index = rng.gen_range(0..wavelengths.len());
+        wavelengths.swap(0, main_index);
 
-                    let probability = component.get_probability(&mut exe, &input);
+        if camera.is_dispersive() {
+            // The camera ray only works for the main wavelength.
+            wavelengths.truncate(1);
+        }
 
-                    (
-                        component.bsdf.color,
-                        probability,
-                        input.wavelength_used.get(),
-                        normal,
-                        texture,
-                    )
-                }
-                Surface::Color(color) => (color, 1.0, false, ray.direction, Point2::origin()),
-            };
-            ray.origin += normal * DIST_EPSILON;
+        let Some(CameraRay {
+            ray: camera_ray,
+            time,
+            weight: camera_weight,
+        }) = camera.ray_towards(&position, wavelengths[0], &mut rng)
+        else {
+            // The camera doesn't let any light through towards this position.
+            contributions.clear(wavelengths.len(), aovs.strategies().len());
+            connections.expose(film, position, &wavelengths, &contributions);
+            continue;
+        };
 
-            lamp_path.push(Bounce {
-                ty: BounceType::Emission,
-                dispersed,
-                color,
-                incident: Vector3::new(0.0, 0.0, 0.0),
-                position: ray.origin,
-                normal,
-                texture,
-                probability: weight / (probability * material_probability),
-                direct_light: vec![],
-            });
+        camera_path.clear(wavelengths.len(), camera_weight);
+        trace_subpath(
+            &mut camera_path,
+            camera_ray,
+            time,
+            0.0,
+            renderer.bounces,
+            true,
+            &wavelengths,
+            world,
+            &mut rng,
+            &mut exe,
+        );
 
-            trace(
-                &mut lamp_path,
-                &mut rng,
-                ray,
-                wavelength,
-                world,
+        // The camera samples the view area evenly, so the density of the
+        // first vertex depends on how the view is projected.
+        if let Some(first) = camera_path.vertices.first_mut() {
+            if !matches!(first.emitter, Some(Emitter::Sky)) {
+                let sq_distance = (first.bounce.position - camera_ray.origin).magnitude2();
+                first.forward_density = camera.connection_scale(position, sq_distance)
+                    * first.cos(camera_ray.direction)
+                    / connections.view_area;
+            }
+
+            aovs.record_surface(
+                position,
+                camera_ray.origin,
+                &first.bounce,
+                wavelengths.iter().copied(),
+                &mut exe,
+            );
+        }
+
+        light_path.clear(wavelengths.len(), 1.0);
+        if let Some((lamp, probability)) = pick_lamp(world, &mut rng) {
+            trace_light_path(
+                &mut light_path,
+                lamp,
+                probability,
+                time,
                 bidir_params.bounces,
-                0,
+                &wavelengths,
+                world,
+                &mut rng,
                 &mut exe,
             );
+        }
 
-            pairs(&mut lamp_path, |to, from| {
-                to.incident = -from.incident;
-                if let BounceType::Diffuse(_, ref mut o) = from.ty {
-                    *o = from.incident
+        // Dispersed paths only work for the main wavelength.
+        let active_wavelengths = if camera_path.is_dispersed() || light_path.is_dispersed() {
+            1
+        } else {
+            wavelengths.len()
+        };
+        contributions.clear(active_wavelengths, aovs.strategies().len());
+
+        for (index, vertex) in camera_path.vertices.iter().enumerate() {
+            match vertex.bounce.ty {
+                BounceType::Emission => {
+                    connections.add_emission(&camera_path, index, &mut contributions);
                 }
-            });
+                BounceType::Diffuse(..) => {
+                    connections.sample_lamp(
+                        &camera_path,
+                        index,
+                        &wavelengths,
+                        &mut contributions,
+                        &mut rng,
+                        &mut exe,
+                    );
 
-            if lamp_path.len() > 1 {
-                if let Some(last) = lamp_path.pop() {
-                    match last.ty {
-                        BounceType::Diffuse(_, _) | BounceType::Specular => lamp_path.push(last),
-                        BounceType::Emission => {}
+                    for light_index in 1..light_path.vertices.len() {
+                        connections.connect(
+                            &camera_path,
+                            index,
+                            &light_path,
+                            light_index,
+                            &mut contributions,
+                        );
                     }
                 }
+                BounceType::Specular => {}
             }
-            lamp_path.reverse();
         }
 
-        trace(
-            &mut camera_path,
-            &mut rng,
-            camera_ray,
-            wavelength,
-            world,
-            renderer.bounces,
-            renderer.light_samples,
-            &mut exe,
-        );
+        connections.expose(film, position, &wavelengths, &contributions);
 
-        let total = (camera_path.len() * lamp_path.len()) as f32;
-        let weight = 1.0 / total;
+        if connections.limits.camera_connectable {
+            for index in 0..light_path.vertices.len() {
+                connections.connect_to_camera(
+                    film,
+                    density,
+                    &light_path,
+                    index,
+                    position,
+                    time,
+                    &wavelengths[..active_wavelengths],
+                    &mut rng,
+                );
+            }
+        }
+    }
+}
 
-        let mut use_additional = true;
+fn pick_lamp<'w>(world: &'w World, rng: &mut impl Rng) -> Option<(&'w Lamp<'w>, f32)> {
+    if world.lights.is_empty() {
+        None
+    } else {
+        world.pick_lamp(rng)
+    }
+}
 
-        for bounce in &camera_path {
-            use_additional = !bounce.dispersed && use_additional;
-            let additional_samples_slice = if use_additional {
-                &mut *additional_samples
-            } else {
-                &mut []
-            };
+/// The area of the view, where the longest side goes from -1 to 1.
+fn view_area(film: &Film) -> f32 {
+    let longest_side = film.width().max(film.height()) as f32;
+    4.0 * film.width() as f32 * film.height() as f32 / (longest_side * longest_side)
+}
 
-            contribute(bounce, &mut main_sample, additional_samples_slice, &mut exe);
+/// The longest camera and light paths, counted in vertices. The camera and
+/// the light source are vertices too.
+#[derive(Clone, Copy)]
+struct Limits {
+    camera_vertices: usize,
+    light_vertices: usize,
+    /// Light paths can be connected directly to the camera.
+    camera_connectable: bool,
+}
 
-            for mut contribution in connect_paths(
-                &bounce,
-                &main_sample,
-                &additional_samples,
-                &lamp_path,
-                world,
-                use_additional,
-                &mut exe,
-            ) {
-                contribution.weight = weight;
-                film.expose(position, contribution);
+impl Limits {
+    fn new(renderer: &Renderer, config: &BidirParams) -> Self {
+        Limits {
+            camera_vertices: renderer.bounces as usize + 1,
+            light_vertices: config.bounces as usize + 1,
+            camera_connectable: true,
+        }
+    }
+}
+
+/// What kind of light source a vertex is on.
+#[derive(Clone, Copy)]
+enum Emitter {
+    /// An emissive surface that is sampled as a lamp, with its surface area.
+    Area(f32),
+    /// A point light, which can't be hit by the camera paths.
+    Point,
+    /// An emissive surface that is only found by hitting it.
+    Unsampled,
+    /// The sky or a directional light.
+    Sky,
+}
+
+/// A point on a camera or light path, with the probability densities of
+/// reaching it from either direction. The densities are per area.
+struct Vertex<'a> {
+    bounce: Bounce<'a>,
+    emitter: Option<Emitter>,
+    /// The density of reaching the vertex from the previous vertex on its
+    /// own path.
+    forward_density: f32,
+    /// The density of reaching the vertex from the next vertex, if the path
+    /// had been traced in the other direction.
+    reverse_density: f32,
+    /// The throughput factor towards the next vertex on its own path,
+    /// without the color.
+    scattering: f32,
+}
+
+impl<'a> Vertex<'a> {
+    fn is_delta(&self) -> bool {
+        matches!(self.bounce.ty, BounceType::Specular)
+    }
+
+    /// The cosine of the angle between `direction` and the surface normal,
+    /// for converting densities per solid angle to densities per area.
+    fn cos(&self, direction: Vector3<f32>) -> f32 {
+        if let Some(Emitter::Point) = self.emitter {
+            1.0
+        } else {
+            self.bounce.normal.dot(direction).abs()
+        }
+    }
+
+    /// The density of scattering light from `incoming` towards `outgoing`,
+    /// per solid angle.
+    fn direction_density(&self, incoming: Vector3<f32>, outgoing: Vector3<f32>) -> f32 {
+        match (&self.bounce.ty, self.emitter) {
+            (&BounceType::Diffuse(_, pdf, _), _) => pdf(incoming, outgoing, self.bounce.normal),
+            (BounceType::Emission, Some(Emitter::Area(_) | Emitter::Point)) => EMISSION_DENSITY,
+            _ => 0.0,
+        }
+    }
+
+    /// The throughput factor from the vertex towards `direction`, together
+    /// with the density of scattering in that direction, or `None` if the
+    /// vertex can't be connected that way.
+    fn connect(&self, direction: Vector3<f32>) -> Option<(f32, f32)> {
+        let incident = self.bounce.incident;
+        let normal = self.bounce.normal;
+
+        match (&self.bounce.ty, self.emitter) {
+            (&BounceType::Diffuse(brdf, pdf, _), _) => {
+                // The light has to leave on the side where the path arrived.
+                if incident.dot(normal) * direction.dot(normal) >= 0.0 {
+                    return None;
+                }
+
+                let density = pdf(incident, direction, normal);
+                Some((brdf(incident, normal, direction) * density, density))
+            }
+            (BounceType::Emission, Some(Emitter::Area(_) | Emitter::Point)) => {
+                Some((self.cos(direction), EMISSION_DENSITY))
             }
+            _ => None,
+        }
+    }
+
+    /// The density of reaching `previous` from this vertex, per area, if the
+    /// light had arrived from `incoming` instead of from `previous`.
+    fn density_back(&self, incoming: Vector3<f32>, previous: &Vertex<'_>) -> f32 {
+        let sq_distance = (self.bounce.position - previous.bounce.position).magnitude2();
+        self.direction_density(incoming, -self.bounce.incident) * previous.cos(self.bounce.incident)
+            / sq_distance
+    }
+}
+
+/// A camera or light path, together with the spectral values of its
+/// vertices.
+struct Subpath<'a> {
+    vertices: Vec<Vertex<'a>>,
+    /// The throughput from the start of the path to each vertex, for each
+    /// wavelength.
+    throughput: Vec<f32>,
+    /// The color of each vertex, times its probability, for each wavelength.
+    colors: Vec<f32>,
+    wavelengths: usize,
+    /// The throughput towards the first vertex.
+    start: f32,
+}
+
+impl<'a> Subpath<'a> {
+    fn with_capacity(vertices: usize) -> Self {
+        Subpath {
+            vertices: Vec::with_capacity(vertices),
+            throughput: Vec::new(),
+            colors: Vec::new(),
+            wavelengths: 0,
+            start: 1.0,
         }
+    }
 
-        film.expose(position, main_sample.0.clone());
+    fn clear(&mut self, wavelengths: usize, start: f32) {
+        self.vertices.clear();
+        self.throughput.clear();
+        self.colors.clear();
+        self.wavelengths = wavelengths;
+        self.start = start;
+    }
 
-        if use_additional {
-            for &(ref sample, _) in &additional_samples {
-                film.expose(position, sample.clone());
+    fn push(&mut self, vertex: Vertex<'a>, wavelengths: &[f32], exe: &mut ExecutionContext<'a>) {
+        if let Some(previous) = self.vertices.last() {
+            let from = self.throughput.len() - self.wavelengths;
+            for index in from..from + self.wavelengths {
+                let throughput = self.throughput[index] * self.colors[index] * previous.scattering;
+                self.throughput.push(throughput);
             }
+        } else {
+            self.throughput
+                .extend(std::iter::repeat(self.start).take(self.wavelengths));
         }
 
-        let weight = 1.0 / lamp_path.len() as f32;
-        for (i, bounce) in lamp_path.iter().enumerate() {
-            if let BounceType::Diffuse(_, _) = bounce.ty {
-            } else {
-                continue;
+        {
+            let bounce = &vertex.bounce;
+            let initial_input = RenderContext {
+                wavelength: wavelengths[0],
+                incident: bounce.incident,
+                normal: bounce.normal,
+                texture: bounce.texture,
+            };
+            let mut exe = bounce.color.memoize(initial_input, exe);
+
+            self.colors.push(exe.run() * bounce.probability);
+            for &wavelength in &wavelengths[1..] {
+                exe.update_input().set_wavelength(wavelength);
+                self.colors.push(exe.run() * bounce.probability);
             }
+        }
 
-            let camera_hit = camera.is_visible(bounce.position, &world, &mut rng);
-            if let Some((position, ray)) = camera_hit {
-                if position.x > -1.0 && position.x < 1.0 && position.y > -1.0 && position.y < 1.0 {
-                    let sq_distance = (ray.origin - bounce.position).magnitude2();
-                    let scale = 1.0 / (sq_distance);
-                    let brdf_in = bounce.ty.brdf(-ray.direction, bounce.normal)
-                        / bounce.ty.brdf(bounce.incident, bounce.normal);
-
-                    main_sample.0.brightness = 0.0;
-                    main_sample.0.weight = weight;
-                    main_sample.1 = scale;
-
-                    use_additional = true;
-                    for &mut (ref mut sample, ref mut reflectance) in &mut additional_samples {
-                        sample.brightness = 0.0;
-                        sample.weight = weight;
-                        *reflectance = scale;
-                    }
+        self.vertices.push(vertex);
+    }
 
-                    for (i, bounce) in lamp_path[i..].iter().enumerate() {
-                        use_additional = !bounce.dispersed && use_additional;
-                        let additional_samples = if use_additional {
-                            &mut *additional_samples
-                        } else {
-                            &mut []
-                        };
-
-                        contribute(bounce, &mut main_sample, additional_samples, &mut exe);
-
-                        if i == 0 {
-                            main_sample.1 *= brdf_in;
-                            for (_, reflectance) in additional_samples {
-                                *reflectance *= brdf_in;
-                            }
-                        }
-                    }
+    fn throughput(&self, index: usize) -> &[f32] {
+        &self.throughput[index * self.wavelengths..(index + 1) * self.wavelengths]
+    }
 
-                    film.expose(position, main_sample.0.clone());
+    fn color(&self, index: usize) -> &[f32] {
+        &self.colors[index * self.wavelengths..(index + 1) * self.wavelengths]
+    }
 
-                    if use_additional {
-                        for &(ref sample, _) in &additional_samples {
-                            film.expose(position, sample.clone());
-                        }
-                    }
+    fn is_dispersed(&self) -> bool {
+        self.vertices.iter().any(|vertex| vertex.bounce.dispersed)
+    }
+}
+
+/// Starts a light path on `lamp` and follows it through the world.
+fn trace_light_path<'a>(
+    path: &mut Subpath<'a>,
+    lamp: &'a Lamp<'a>,
+    lamp_probability: f32,
+    time: f32,
+    bounces: u32,
+    wavelengths: &[f32],
+    world: &'a World,
+    rng: &mut impl Rng,
+    exe: &mut ExecutionContext<'a>,
+) {
+    // Directional lights have no position to start from.
+    let Some(RaySample {
+        ray,
+        surface,
+        weight,
+    }) = lamp.sample_ray(rng)
+    else {
+        return;
+    };
+
+    // The lamps send light in every direction.
+    let direction = if rng.gen() {
+        ray.direction
+    } else {
+        -ray.direction
+    };
+
+    let (color, material_probability, dispersed, normal, texture, emitter) = match surface {
+        Surface::Physical {
+            normal,
+            material,
+            texture,
+        } => {
+            let component = material.choose_emissive(rng);
+            let input = ProbabilityInput {
+                wavelength: wavelengths[0],
+                wavelength_used: Cell::new(false),
+                normal,
+                incident: -direction,
+                texture_coordinate: texture,
+            };
+
+            let probability = component.get_probability(exe, &input);
+
+            (
+                component.bsdf.color,
+                probability,
+                input.wavelength_used.get(),
+                normal,
+                texture,
+                Emitter::Area(weight),
+            )
+        }
+        Surface::Color(color) => (
+            color,
+            1.0,
+            false,
+            direction,
+            Point2::origin(),
+            Emitter::Point,
+        ),
+    };
+
+    // The weight is the area of the lamp, or the solid angle of a point
+    // light.
+    let forward_density = match emitter {
+        Emitter::Area(area) => lamp_probability / area,
+        _ => lamp_probability,
+    };
+
+    let start = Vertex {
+        bounce: Bounce {
+            ty: BounceType::Emission,
+            dispersed,
+            color,
+            incident: -direction,
+            position: ray.origin,
+            normal,
+            texture,
+            probability: material_probability * weight / lamp_probability,
+            direct_light: vec![],
+            object_id: 0,
+            distant: false,
+            time,
+        },
+        emitter: Some(emitter),
+        forward_density,
+        reverse_density: 0.0,
+        scattering: 0.0,
+    };
+    let scattering = start.cos(direction) / EMISSION_DENSITY;
+    path.push(
+        Vertex {
+            scattering,
+            ..start
+        },
+        wavelengths,
+        exe,
+    );
+
+    trace_subpath(
+        path,
+        Ray3::new(ray.origin, direction),
+        time,
+        EMISSION_DENSITY,
+        bounces,
+        false,
+        wavelengths,
+        world,
+        rng,
+        exe,
+    );
+}
+
+/// Follows `ray` from the end of `path` and adds the vertices that it hits.
+/// The `density` is the density of the ray's direction, per solid angle.
+/// Light sources end the path, and they are only added to camera paths.
+fn trace_subpath<'a>(
+    path: &mut Subpath<'a>,
+    mut ray: Ray3<f32>,
+    time: f32,
+    mut density: f32,
+    bounces: u32,
+    is_camera_path: bool,
+    wavelengths: &[f32],
+    world: &'a World,
+    rng: &mut impl Rng,
+    exe: &mut ExecutionContext<'a>,
+) {
+    let wavelength = wavelengths[0];
+
+    for _ in 0..bounces {
+        let Some(intersection) = world.intersect(ray) else {
+            if is_camera_path {
+                // Directional lights are sampled directly from diffuse
+                // surfaces, so they are only found this way from the camera
+                // or through specular bounces.
+                let sees_directional = path.vertices.last().map_or(true, Vertex::is_delta);
+                let directional = if sees_directional {
+                    trace_directional(ray.direction, world)
+                } else {
+                    None
+                };
+
+                let sky = Vertex {
+                    bounce: Bounce {
+                        ty: BounceType::Emission,
+                        dispersed: false,
+                        color: directional.unwrap_or(world.sky),
+                        incident: ray.direction,
+                        position: ray.origin,
+                        normal: -ray.direction,
+                        texture: Point2::origin(),
+                        probability: 1.0,
+                        direct_light: vec![],
+                        object_id: 0,
+                        distant: true,
+                        time,
+                    },
+                    emitter: Some(Emitter::Sky),
+                    forward_density: 0.0,
+                    reverse_density: 0.0,
+                    scattering: 0.0,
+                };
+                path.push(sky, wavelengths, exe);
+            }
+
+            break;
+        };
+
+        let material = intersection.surface_point.get_material();
+        let surface_data = intersection.surface_point.get_surface_data();
+
+        let normal_input = NormalInput {
+            incident: ray.direction,
+            normal: surface_data.normal.vector(),
+            texture: surface_data.texture,
+        };
+        let normal = material.apply_normal_map(surface_data.normal, normal_input, exe);
+        let position = intersection.surface_point.position;
+
+        let component = material.choose_component(rng);
+
+        let probability_input = ProbabilityInput {
+            wavelength,
+            wavelength_used: Cell::new(false),
+            normal,
+            incident: ray.direction,
+            texture_coordinate: surface_data.texture,
+        };
+        let component_probability = component.get_probability(exe, &probability_input);
+        let normal_dispersed = probability_input.wavelength_used.get();
+
+        let sq_distance = intersection.distance * intersection.distance;
+        let forward_density = density * normal.dot(ray.direction).abs() / sq_distance;
+
+        match component
+            .bsdf
+            .scatter(ray.direction, normal, wavelength, rng)
+        {
+            Scattering::Reflected {
+                out_direction,
+                probability,
+                dispersed,
+                brdf,
+                pdf,
+            } => {
+                let (ty, scattering) = match (brdf, pdf) {
+                    (Some(brdf), Some(pdf)) => (
+                        BounceType::Diffuse(brdf, pdf, out_direction),
+                        brdf(ray.direction, normal, out_direction),
+                    ),
+                    _ => (BounceType::Specular, 1.0),
+                };
+
+                // Specular bounces have no density, and they are skipped
+                // when weighting the strategies.
+                if let Some(previous) = path.vertices.last_mut() {
+                    previous.reverse_density = pdf.map_or(0.0, |pdf| {
+                        pdf(-out_direction, -ray.direction, normal) * previous.cos(ray.direction)
+                            / sq_distance
+                    });
+                }
+
+                let vertex = Vertex {
+                    bounce: Bounce {
+                        ty,
+                        dispersed: dispersed || normal_dispersed,
+                        color: component.bsdf.color,
+                        incident: ray.direction,
+                        position,
+                        normal,
+                        texture: surface_data.texture,
+                        probability: probability * component_probability,
+                        direct_light: vec![],
+                        object_id: material.object_id(),
+                        distant: false,
+                        time,
+                    },
+                    emitter: None,
+                    forward_density,
+                    reverse_density: 0.0,
+                    scattering,
+                };
+                path.push(vertex, wavelengths, exe);
+
+                density = pdf.map_or(0.0, |pdf| pdf(ray.direction, out_direction, normal));
+                ray = Ray3::new(position, out_direction);
+            }
+            Scattering::Emitted => {
+                if is_camera_path {
+                    let emitter = match lamp::emitter_area(&intersection.surface_point) {
+                        Some(area) => Emitter::Area(area),
+                        None => Emitter::Unsampled,
+                    };
+
+                    let vertex = Vertex {
+                        bounce: Bounce {
+                            ty: BounceType::Emission,
+                            dispersed: normal_dispersed,
+                            color: component.bsdf.color,
+                            incident: ray.direction,
+                            position,
+                            normal,
+                            texture: surface_data.texture,
+                            probability: component_probability,
+                            direct_light: vec![],
+                            object_id: material.object_id(),
+                            distant: false,
+                            time,
+                        },
+                        emitter: Some(emitter),
+                        forward_density,
+                        reverse_density: 0.0,
+                        scattering: 0.0,
+                    };
+                    path.push(vertex, wavelengths, exe);
                 }
+
+                break;
             }
         }
     }
 }
 
-fn connect_paths<'a>(
-    bounce: &Bounce<'a>,
-    main: &(Sample, f32),
-    additional: &[(Sample, f32)],
-    path: &[Bounce<'a>],
-    world: &World,
-    use_additional: bool,
-    exe: &mut ExecutionContext<'a>,
-) -> Vec<Sample> {
-    let mut contributions = vec![];
-    let bounce_brdf = match bounce.ty {
-        BounceType::Emission | BounceType::Specular => return contributions,
-        BounceType::Diffuse(brdf, _) => brdf,
-    };
+/// The light that a camera sample gets from the connection strategies.
+#[derive(Default)]
+struct Contributions {
+    wavelengths: usize,
+    total: Vec<f32>,
+    /// The light from each strategy that has its own image, for each
+    /// wavelength.
+    strategies: Vec<f32>,
+}
 
-    for (i, lamp_bounce) in path.iter().enumerate() {
-        if let BounceType::Specular = lamp_bounce.ty {
-            continue;
+impl Contributions {
+    fn clear(&mut self, wavelengths: usize, strategies: usize) {
+        self.wavelengths = wavelengths;
+        self.total.clear();
+        self.total.resize(wavelengths, 0.0);
+        self.strategies.clear();
+        self.strategies.resize(wavelengths * strategies, 0.0);
+    }
+
+    fn add(&mut self, strategy: Option<usize>, wavelength: usize, value: f32) {
+        self.total[wavelength] += value;
+
+        if let Some(strategy) = strategy {
+            self.strategies[strategy * self.wavelengths + wavelength] += value;
+        }
+    }
+}
+
+/// Connects the camera paths and the light paths in every possible way,
+/// and weights the strategies with the power heuristic.
+struct Connections<'a> {
+    world: &'a World<'a>,
+    camera: &'a Camera,
+    aovs: &'a Aovs,
+    limits: Limits,
+    /// The area of the view, for the density of the camera rays.
+    view_area: f32,
+}
+
+impl<'a> Connections<'a> {
+    /// Adds the light from a light source that the camera path hit, which
+    /// is the strategy without light path vertices.
+    fn add_emission(
+        &self,
+        camera_path: &Subpath<'_>,
+        index: usize,
+        contributions: &mut Contributions,
+    ) {
+        let vertex = &camera_path.vertices[index];
+
+        // Only the lamps can be found by the other strategies.
+        let mis_weight = if let Some(Emitter::Area(area)) = vertex.emitter {
+            let lamp_probability = 1.0 / self.world.lights.len() as f32;
+            let previous_density = index.checked_sub(1).map_or(0.0, |previous| {
+                vertex.density_back(-vertex.bounce.incident, &camera_path.vertices[previous])
+            });
+
+            self.mis_weight(
+                &camera_path.vertices[..=index],
+                &[],
+                [lamp_probability / area, previous_density],
+                [0.0; 2],
+            )
+        } else {
+            1.0
+        };
+
+        let strategy = self.strategy_image(0, index + 2);
+        let throughput = camera_path.throughput(index);
+        let color = camera_path.color(index);
+
+        for wavelength in 0..contributions.wavelengths {
+            let value = throughput[wavelength] * color[wavelength] * mis_weight;
+            contributions.add(strategy, wavelength, value);
+        }
+    }
+
+    /// Connects a camera path vertex to a new sample on a lamp, which is the
+    /// strategy with a single light path vertex.
+    fn sample_lamp<'w>(
+        &self,
+        camera_path: &Subpath<'w>,
+        index: usize,
+        wavelengths: &[f32],
+        contributions: &mut Contributions,
+        rng: &mut impl Rng,
+        exe: &mut ExecutionContext<'w>,
+    ) where
+        'a: 'w,
+    {
+        let Some((lamp, lamp_probability)) = pick_lamp(self.world, rng) else {
+            return;
+        };
+
+        let vertex = &camera_path.vertices[index];
+        let lamp::Sample {
+            direction,
+            sq_distance,
+            surface,
+            weight,
+        } = lamp.sample(rng, vertex.bounce.position);
+
+        let Some((scattering, density)) = vertex.connect(direction) else {
+            return;
+        };
+
+        let hit_distance = self
+            .world
+            .intersect(Ray3::new(vertex.bounce.position, direction))
+            .map(|hit| hit.distance * hit.distance);
+        let blocked = match (hit_distance, sq_distance) {
+            (Some(hit), Some(lamp)) if hit >= lamp - DIST_EPSILON => false,
+            (None, _) => false,
+            _ => true,
+        };
+        if blocked {
+            return;
+        }
+
+        let (color, material_probability, dispersed, normal, texture) = match surface {
+            Surface::Physical {
+                normal,
+                material,
+                texture,
+            } => {
+                let component = material.choose_emissive(rng);
+                let input = ProbabilityInput {
+                    wavelength: wavelengths[0],
+                    wavelength_used: Cell::new(false),
+                    normal,
+                    incident: direction,
+                    texture_coordinate: texture,
+                };
+
+                let probability = component.get_probability(exe, &input);
+
+                (
+                    component.bsdf.color,
+                    probability,
+                    input.wavelength_used.get(),
+                    normal,
+                    texture,
+                )
+            }
+            Surface::Color(color) => (color, 1.0, false, -direction, Point2::origin()),
+        };
+
+        // Directional lights can't be hit by light paths or by diffusely
+        // scattered camera paths, so this is the only way to find them.
+        let mis_weight = if let Some(sq_distance) = sq_distance {
+            let emitter = match *lamp {
+                Lamp::Shape(shape) => Emitter::Area(shape.surface_area()),
+                _ => Emitter::Point,
+            };
+            let forward_density = match emitter {
+                Emitter::Area(area) => lamp_probability / area,
+                _ => lamp_probability,
+            };
+
+            let light_vertex = Vertex {
+                bounce: Bounce {
+                    ty: BounceType::Emission,
+                    dispersed,
+                    color,
+                    incident: direction,
+                    position: vertex.bounce.position + direction * sq_distance.sqrt(),
+                    normal,
+                    texture,
+                    probability: material_probability,
+                    direct_light: vec![],
+                    object_id: 0,
+                    distant: false,
+                    time: vertex.bounce.time,
+                },
+                emitter: Some(emitter),
+                forward_density,
+                reverse_density: 0.0,
+                scattering: 0.0,
+            };
+
+            let previous_density = index.checked_sub(1).map_or(0.0, |previous| {
+                vertex.density_back(-direction, &camera_path.vertices[previous])
+            });
+
+            self.mis_weight(
+                &camera_path.vertices[..=index],
+                std::slice::from_ref(&light_vertex),
+                [
+                    EMISSION_DENSITY * vertex.cos(direction) / sq_distance,
+                    previous_density,
+                ],
+                [density * light_vertex.cos(direction) / sq_distance, 0.0],
+            )
+        } else {
+            1.0
+        };
+
+        let scale = scattering * weight * material_probability / lamp_probability * mis_weight;
+        let strategy = self.strategy_image(1, index + 2);
+        let throughput = camera_path.throughput(index);
+        let vertex_color = camera_path.color(index);
+
+        let initial_input = RenderContext {
+            wavelength: wavelengths[0],
+            incident: direction,
+            normal,
+            texture,
+        };
+        let mut exe = color.memoize(initial_input, exe);
+
+        // The lamp's probability may depend on the wavelength.
+        let active_wavelengths = if dispersed {
+            1
+        } else {
+            contributions.wavelengths
+        };
+
+        for (channel, &wavelength) in wavelengths[..active_wavelengths].iter().enumerate() {
+            if channel > 0 {
+                exe.update_input().set_wavelength(wavelength);
+            }
+
+            let value = throughput[channel] * vertex_color[channel] * exe.run() * scale;
+            contributions.add(strategy, channel, value);
         }
+    }
 
-        let from = bounce.position;
-        let to = lamp_bounce.position;
+    /// Connects a camera path vertex and a light path vertex, where both are
+    /// surfaces that scatter light.
+    fn connect(
+        &self,
+        camera_path: &Subpath<'_>,
+        camera_index: usize,
+        light_path: &Subpath<'_>,
+        light_index: usize,
+        contributions: &mut Contributions,
+    ) {
+        let camera_vertex = &camera_path.vertices[camera_index];
+        let light_vertex = &light_path.vertices[light_index];
 
-        let direction = to - from;
-        let sq_distance = direction.magnitude2();
+        let offset = light_vertex.bounce.position - camera_vertex.bounce.position;
+        let sq_distance = offset.magnitude2();
         let distance = sq_distance.sqrt();
-        let ray = Ray3::new(from, direction / distance);
+        let direction = offset / distance;
 
-        if bounce.normal.dot(ray.direction) <= 0.0 {
-            continue;
+        let Some((camera_scattering, camera_density)) = camera_vertex.connect(direction) else {
+            return;
+        };
+        let Some((light_scattering, light_density)) = light_vertex.connect(-direction) else {
+            return;
+        };
+
+        let hit = self
+            .world
+            .intersect(Ray3::new(camera_vertex.bounce.position, direction));
+        if matches!(hit, Some(hit) if hit.distance < distance - DIST_EPSILON) {
+            return;
         }
 
-        if lamp_bounce.normal.dot(-ray.direction) <= 0.0 {
-            continue;
+        let camera_previous_density = camera_index.checked_sub(1).map_or(0.0, |previous| {
+            camera_vertex.density_back(-direction, &camera_path.vertices[previous])
+        });
+        let light_previous_density = light_index.checked_sub(1).map_or(0.0, |previous| {
+            light_vertex.density_back(direction, &light_path.vertices[previous])
+        });
+
+        let mis_weight = self.mis_weight(
+            &camera_path.vertices[..=camera_index],
+            &light_path.vertices[..=light_index],
+            [
+                light_density * camera_vertex.cos(direction) / sq_distance,
+                camera_previous_density,
+            ],
+            [
+                camera_density * light_vertex.cos(direction) / sq_distance,
+                light_previous_density,
+            ],
+        );
+
+        let scale = camera_scattering * light_scattering / sq_distance * mis_weight;
+        let strategy = self.strategy_image(light_index + 1, camera_index + 2);
+        let camera_throughput = camera_path.throughput(camera_index);
+        let camera_color = camera_path.color(camera_index);
+        let light_throughput = light_path.throughput(light_index);
+        let light_color = light_path.color(light_index);
+
+        for wavelength in 0..contributions.wavelengths {
+            let value = camera_throughput[wavelength]
+                * camera_color[wavelength]
+                * light_throughput[wavelength]
+                * light_color[wavelength]
+                * scale;
+            contributions.add(strategy, wavelength, value);
+        }
+    }
+
+    /// Connects a light path vertex directly to the camera, which is the
+    /// strategy with only the camera from the camera path. The light lands
+    /// somewhere else in the view than the camera sample, so it's added to
+    /// the film right away.
+    fn connect_to_camera(
+        &self,
+        film: &Film,
+        density: &SampleDensity,
+        light_path: &Subpath<'_>,
+        index: usize,
+        origin: Point2<f32>,
+        time: f32,
+        wavelengths: &[f32],
+        rng: &mut impl Rng,
+    ) {
+        let vertex = &light_path.vertices[index];
+
+        let Some((position, ray)) =
+            self.camera
+                .is_visible(vertex.bounce.position, time, self.world, rng)
+        else {
+            return;
+        };
+
+        if position.x <= -1.0 || position.x >= 1.0 || position.y <= -1.0 || position.y >= 1.0 {
+            return;
+        }
+
+        let Some((scattering, _)) = vertex.connect(-ray.direction) else {
+            return;
+        };
+
+        let sq_distance = (ray.origin - vertex.bounce.position).magnitude2();
+        let camera_scale = self.camera.connection_scale(position, sq_distance);
+
+        let previous_density = index.checked_sub(1).map_or(0.0, |previous| {
+            vertex.density_back(ray.direction, &light_path.vertices[previous])
+        });
+        let mis_weight = self.mis_weight(
+            &[],
+            &light_path.vertices[..=index],
+            [0.0; 2],
+            [
+                camera_scale * vertex.cos(ray.direction) / self.view_area,
+                previous_density,
+            ],
+        );
+
+        let scale = scattering * camera_scale * mis_weight * density.at(position);
+        let strategy_film = self
+            .strategy_image(index + 1, 1)
+            .map(|strategy| &self.aovs.strategies()[strategy].1);
+        let throughput = light_path.throughput(index);
+        let color = light_path.color(index);
+
+        for (channel, &wavelength) in wavelengths.iter().enumerate() {
+            let sample = Sample {
+                wavelength,
+                brightness: throughput[channel] * color[channel] * scale,
+                weight: 0.0,
+            };
+
+            if let Some(strategy_film) = strategy_film {
+                strategy_film.expose_light(position, origin, sample.clone());
+            }
+            film.expose_light(position, origin, sample);
         }
+    }
+
+    /// Weights a strategy against the other strategies that could have made
+    /// the same path, using the power heuristic. The reverse densities of the
+    /// last two vertices on each side depend on the connection, so they are
+    /// passed separately, starting with the last vertex.
+    fn mis_weight(
+        &self,
+        camera_vertices: &[Vertex<'_>],
+        light_vertices: &[Vertex<'_>],
+        camera_reverse: [f32; 2],
+        light_reverse: [f32; 2],
+    ) -> f32 {
+        // Specular bounces have no density, but they are also never used for
+        // connecting paths.
+        let remap = |density: f32| if density == 0.0 { 1.0 } else { density };
+        let mut sum = 0.0;
+
+        // The ratios for the strategies that take more vertices from the
+        // light path, one vertex at a time.
+        let mut ratio = 1.0;
+        for (index, vertex) in camera_vertices.iter().enumerate().rev() {
+            let from_end = camera_vertices.len() - 1 - index;
+            let reverse = camera_reverse
+                .get(from_end)
+                .copied()
+                .unwrap_or(vertex.reverse_density);
+            ratio *= remap(reverse) / remap(vertex.forward_density);
 
-        let hit = world.intersect(ray).map(|hit| hit.distance);
-        if let Some(dist) = hit {
-            if dist < distance - DIST_EPSILON {
-                continue;
+            let previous_is_delta = index
+                .checked_sub(1)
+                .map_or(!self.limits.camera_connectable, |previous| {
+                    camera_vertices[previous].is_delta()
+                });
+            let light_path_vertices = light_vertices.len() + from_end + 1;
+
+            if !vertex.is_delta()
+                && !previous_is_delta
+                && light_path_vertices <= self.limits.light_vertices
+            {
+                sum += ratio * ratio;
             }
         }
 
-        let cos_out = bounce.normal.dot(ray.direction).abs();
-        let cos_in = lamp_bounce.normal.dot(-ray.direction).abs();
-        let brdf_out = bounce_brdf(bounce.incident, bounce.normal, ray.direction)
-            / bounce.ty.brdf(bounce.incident, bounce.normal);
+        // The same for taking more vertices from the camera path.
+        let mut ratio = 1.0;
+        for (index, vertex) in light_vertices.iter().enumerate().rev() {
+            let from_end = light_vertices.len() - 1 - index;
+            let reverse = light_reverse
+                .get(from_end)
+                .copied()
+                .unwrap_or(vertex.reverse_density);
+            ratio *= remap(reverse) / remap(vertex.forward_density);
+
+            // Point lights can't be hit by the camera paths.
+            let previous_is_delta = index
+                .checked_sub(1)
+                .map_or(matches!(vertex.emitter, Some(Emitter::Point)), |previous| {
+                    light_vertices[previous].is_delta()
+                });
+            let camera_path_vertices = camera_vertices.len() + 1 + from_end + 1;
 
-        let scale = cos_in * cos_out * brdf_out / (2.0 * std::f32::consts::PI * sq_distance);
-        let brdf_in = lamp_bounce.ty.brdf(-ray.direction, lamp_bounce.normal)
-            / lamp_bounce
-                .ty
-                .brdf(lamp_bounce.incident, lamp_bounce.normal);
+            if !vertex.is_delta()
+                && !previous_is_delta
+                && camera_path_vertices <= self.limits.camera_vertices
+            {
+                sum += ratio * ratio;
+            }
+        }
+
+        1.0 / (1.0 + sum)
+    }
 
-        let mut use_additional = use_additional;
-        let mut additional: Vec<_> = additional
+    /// Finds the image for a strategy, if it has one.
+    fn strategy_image(&self, light_vertices: usize, camera_vertices: usize) -> Option<usize> {
+        let strategy = Strategy {
+            light_vertices,
+            camera_vertices,
+        };
+
+        self.aovs
+            .strategies()
             .iter()
-            .cloned()
-            .map(|(s, r)| (s, r * scale))
-            .collect();
-        let mut main = main.clone();
-        main.1 *= scale;
-
-        for (i, bounce) in path[i..].iter().enumerate() {
-            use_additional = !bounce.dispersed && use_additional;
-            let additional_samples = if use_additional {
-                &mut *additional
-            } else {
-                &mut []
-            };
+            .position(|&(other, _)| other == strategy)
+    }
 
-            contribute(bounce, &mut main, additional_samples, exe);
+    /// Adds the light that reached a camera sample to the film, and to the
+    /// strategy images.
+    fn expose(
+        &self,
+        film: &Film,
+        position: Point2<f32>,
+        wavelengths: &[f32],
+        contributions: &Contributions,
+    ) {
+        for (index, &wavelength) in wavelengths[..contributions.wavelengths].iter().enumerate() {
+            film.expose(
+                position,
+                Sample {
+                    wavelength,
+                    brightness: contributions.total[index],
+                    weight: 1.0,
+                },
+            );
 
-            if i == 0 {
-                main.1 *= brdf_in;
-                for (_, reflectance) in additional_samples {
-                    *reflectance *= brdf_in;
-                }
+            for (strategy, (_, strategy_film)) in self.aovs.strategies().iter().enumerate() {
+                strategy_film.expose(
+                    position,
+                    Sample {
+                        wavelength,
+                        brightness: contributions.strategies
+                            [strategy * contributions.wavelengths + index],
+                        weight: 1.0,
+                    },
+                );
             }
         }
+    }
+}
 
-        contributions.push(main.0);
-        if use_additional {
-            contributions.extend(additional.into_iter().map(|(s, _)| s));
+/// How densely the camera samples each part of the film during a pass. The
+/// light that is traced from the lamps to the camera is scaled by it, to
+/// match the camera samples that each pixel gets.
+struct SampleDensity {
+    aspect_ratio: AspectRatio,
+    tile_size: usize,
+    tiles_x: usize,
+    /// The samples per pixel in each tile.
+    tile_samples: Vec<u32>,
+    /// Converts samples per pixel to samples per view area, divided by the
+    /// number of light paths in the pass.
+    scale: f32,
+}
+
+impl SampleDensity {
+    fn new(film: &Film, tile_size: usize, tiles: &[(Tile, u32)]) -> Self {
+        let tiles_x = (film.width() + tile_size - 1) / tile_size;
+        let tiles_y = (film.height() + tile_size - 1) / tile_size;
+        let mut tile_samples = vec![0; tiles_x * tiles_y];
+
+        // Each camera sample traces one light path.
+        let mut light_paths = 0.0;
+        for (tile, samples) in tiles {
+            let from = tile.pixels.from;
+            tile_samples[from.x / tile_size + from.y / tile_size * tiles_x] += samples;
+            light_paths += tile.area() as f32 * *samples as f32;
+        }
+
+        let pixel_size = 2.0 / film.width().max(film.height()) as f32;
+
+        SampleDensity {
+            aspect_ratio: AspectRatio::new(film.width(), film.height()),
+            tile_size,
+            tiles_x,
+            tile_samples,
+            scale: 1.0 / (pixel_size * pixel_size * light_paths.max(1.0)),
         }
     }
 
-    contributions
+    fn at(&self, position: Point2<f32>) -> f32 {
+        let Some(pixel) = self.aspect_ratio.to_film(position) else {
+            return 0.0;
+        };
+
+        let x = (pixel.x as usize / self.tile_size).min(self.tiles_x - 1);
+        let y = pixel.y as usize / self.tile_size;
+
+        self.tile_samples
+            .get(x + y * self.tiles_x)
+            .map_or(0.0, |&samples| samples as f32 * self.scale)
+    }
 }
@@ -1,16 +1,28 @@
-use std::borrow::Cow;
+use std::{
+    borrow::Cow,
+    error::Error,
+    io::{self, Read, Write},
+    time::Duration,
+};
 
 use num_cpus;
 
 use crate::cameras;
 use crate::world;
 
-use crate::{film::Film, program::Resources};
+use crate::{
+    aovs::{Aovs, Strategy},
+    film::{CheckpointSettings, Film},
+    program::Resources,
+};
 use indicatif::ProgressBar;
 
+use self::progressive::{Passes, Progressive};
+
 mod algorithm;
 mod bidirectional;
 mod photon_mapping;
+mod progressive;
 mod simple;
 
 static DEFAULT_SPECTRUM_SPAN: (f32, f32) = (380.0, 780.0);
@@ -24,6 +36,8 @@ pub struct Renderer {
     pub spectrum_bins: usize,
     pub spectrum_span: (f32, f32),
     pub tile_size: usize,
+    progressive: Option<Progressive>,
+    adaptive_sampling: f32,
     algorithm: Algorithm,
 }
 
@@ -36,10 +50,12 @@ impl Renderer {
             crate::project::Renderer::Bidirectional {
                 shared,
                 light_bounces,
+                strategy_bounces,
             } => Self::from_shared(
                 shared,
                 Algorithm::Bidirectional(bidirectional::BidirParams {
                     bounces: light_bounces.unwrap_or(8),
+                    strategy_bounces: strategy_bounces.unwrap_or(3),
                 }),
             ),
             crate::project::Renderer::PhotonMapping {
@@ -48,6 +64,8 @@ impl Renderer {
                 photons,
                 photon_bounces,
                 photon_passes,
+                progressive,
+                alpha,
             } => Self::from_shared(
                 shared,
                 Algorithm::PhotonMapping(photon_mapping::Config {
@@ -55,6 +73,9 @@ impl Renderer {
                     photon_bounces: photon_bounces.unwrap_or(8),
                     photons: photons.unwrap_or(10000),
                     photon_passes: photon_passes.unwrap_or(1),
+                    radius_reduction: progressive.unwrap_or(false).then(|| {
+                        photon_mapping::RadiusReduction::new(alpha.unwrap_or(0.7).clamp(0.0, 1.0))
+                    }),
                 }),
             ),
         }
@@ -70,28 +91,175 @@ impl Renderer {
             spectrum_bins: shared.spectrum_resolution.unwrap_or(64),
             spectrum_span: DEFAULT_SPECTRUM_SPAN,
             tile_size: shared.tile_size.unwrap_or(32),
+            progressive: if shared.time_limit.is_some() || shared.noise_limit.is_some() {
+                Some(Progressive {
+                    time_limit: shared.time_limit.map(Duration::from_secs_f32),
+                    noise_limit: shared.noise_limit,
+                })
+            } else {
+                None
+            },
+            adaptive_sampling: shared.adaptive_sampling.unwrap_or(0.0).clamp(0.0, 1.0),
             algorithm,
         }
     }
 
-    pub(crate) fn render<F: FnMut(Progress<'_>)>(
+    /// Splits the pixel samples into samples that are spread evenly over the
+    /// image, and samples that are distributed by the estimated noise. At
+    /// least one sample per pixel is spread evenly.
+    fn split_adaptive_samples(&self, pixel_samples: u32) -> (u32, u32) {
+        let adaptive = (pixel_samples as f32 * self.adaptive_sampling).round() as u32;
+        let uniform = pixel_samples.saturating_sub(adaptive).max(1);
+
+        (uniform, pixel_samples.saturating_sub(uniform))
+    }
+
+    /// The connection strategies that can be saved as separate images. Only
+    /// the bidirectional renderer has them.
+    pub(crate) fn connection_strategies(&self) -> Vec<Strategy> {
+        match self.algorithm {
+            Algorithm::Bidirectional(ref config) => bidirectional::strategies(self, config),
+            Algorithm::Simple | Algorithm::PhotonMapping(_) => Vec::new(),
+        }
+    }
+
+    /// The settings that decide if a checkpoint can be resumed.
+    pub(crate) fn checkpoint_settings(&self) -> CheckpointSettings {
+        let renderer = match self.algorithm {
+            Algorithm::Simple => "simple",
+            Algorithm::Bidirectional(_) => "bidirectional",
+            Algorithm::PhotonMapping(photon_mapping::Config {
+                radius_reduction: Some(_),
+                ..
+            }) => "progressive photon mapping",
+            Algorithm::PhotonMapping(_) => "photon mapping",
+        };
+
+        CheckpointSettings {
+            renderer: renderer.into(),
+            pixel_samples: self.pixel_samples,
+            spectrum_samples: self.spectrum_samples,
+        }
+    }
+
+    /// Writes the state that the renderer keeps between passes, if it has
+    /// any, so it can be restored together with the films.
+    pub(crate) fn write_checkpoint(&self, writer: impl Write) -> io::Result<()> {
+        match self.algorithm {
+            Algorithm::PhotonMapping(photon_mapping::Config {
+                radius_reduction: Some(ref reduction),
+                ..
+            }) => reduction.write_checkpoint(self.tile_size, writer),
+            _ => Ok(()),
+        }
+    }
+
+    /// Restores the state from `write_checkpoint`.
+    pub(crate) fn read_checkpoint(&self, reader: impl Read) -> Result<(), Box<dyn Error>> {
+        match self.algorithm {
+            Algorithm::PhotonMapping(photon_mapping::Config {
+                radius_reduction: Some(ref reduction),
+                ..
+            }) => reduction.read_checkpoint(self.tile_size, reader),
+            _ => Ok(()),
+        }
+    }
+
+    /// Renders the image, continuing after `finished_samples` samples per
+    /// pixel. `on_status` gets the number of finished samples per pixel with
+    /// each update, and the total is returned at the end.
+    pub(crate) fn render<F: FnMut(Progress<'_>, u32)>(
         &self,
         film: &Film,
+        aovs: &Aovs,
         task_runner: TaskRunner,
+        mut on_status: F,
+        finished_samples: u32,
+        camera: &cameras::Camera,
+        world: &world::World,
+        resources: &Resources,
+    ) -> u32 {
+        let mut passes = Passes::new(
+            self.progressive.as_ref(),
+            self.pixel_samples,
+            finished_samples,
+        );
+
+        while let Some(pixel_samples) = passes.next_pass() {
+            let pass_message = passes.message();
+            let finished_samples = passes.samples();
+            let on_pass_status = |status: Progress<'_>| {
+                if passes.is_progressive() {
+                    on_status(
+                        Progress {
+                            message: &format!("{}: {}", pass_message, status.message),
+                            ..status
+                        },
+                        finished_samples,
+                    )
+                } else {
+                    on_status(status, finished_samples)
+                }
+            };
+
+            self.render_pass(
+                film,
+                aovs,
+                &task_runner,
+                on_pass_status,
+                pixel_samples,
+                camera,
+                world,
+                resources,
+            );
+
+            passes.finish_pass(pixel_samples, film);
+
+            if passes.is_progressive() {
+                on_status(
+                    Progress {
+                        progress: 100,
+                        message: &passes.finished_message(),
+                        pass_finished: true,
+                    },
+                    passes.samples(),
+                );
+            }
+        }
+
+        passes.samples()
+    }
+
+    fn render_pass<F: FnMut(Progress<'_>)>(
+        &self,
+        film: &Film,
+        aovs: &Aovs,
+        task_runner: &TaskRunner,
         on_status: F,
+        pixel_samples: u32,
         camera: &cameras::Camera,
         world: &world::World,
         resources: &Resources,
     ) {
         match self.algorithm {
-            Algorithm::Simple => {
-                simple::render(film, task_runner, on_status, self, world, camera, resources)
-            }
+            Algorithm::Simple => simple::render(
+                film,
+                aovs,
+                task_runner,
+                on_status,
+                self,
+                pixel_samples,
+                world,
+                camera,
+                resources,
+            ),
             Algorithm::Bidirectional(ref config) => bidirectional::render(
                 film,
+                aovs,
                 task_runner,
                 on_status,
                 self,
+                pixel_samples,
                 config,
                 world,
                 camera,
@@ -99,9 +267,11 @@ impl Renderer {
             ),
             Algorithm::PhotonMapping(ref config) => photon_mapping::render(
                 film,
+                aovs,
                 task_runner,
                 on_status,
                 self,
+                pixel_samples,
                 config,
                 world,
                 camera,
@@ -229,4 +399,6 @@ impl LocalProgress {
 pub(crate) struct Progress<'a> {
     pub(crate) progress: u8,
     pub(crate) message: &'a str,
+    /// Set when a full progressive pass has been added to the film.
+    pub(crate) pass_finished: bool,
 }
@@ -9,7 +9,8 @@ use super::{
     algorithm::{contribute, make_tiles},
     Progress, Renderer, TaskRunner,
 };
-use crate::cameras::Camera;
+use crate::aovs::Aovs;
+use crate::cameras::{Camera, CameraRay};
 use crate::film::{DetachedPixel, Film, Sample};
 use crate::lamp::Surface;
 use crate::spatial::kd_tree::{self, KdTree};
@@ -23,11 +24,17 @@ use crate::{
     world::World,
 };
 
+pub(crate) use self::stochastic::RadiusReduction;
+
+mod stochastic;
+
 pub(crate) fn render<F: FnMut(Progress<'_>)>(
     film: &Film,
-    task_runner: TaskRunner,
+    aovs: &Aovs,
+    task_runner: &TaskRunner,
     mut on_status: F,
     renderer: &Renderer,
+    pixel_samples: u32,
     config: &Config,
     world: &World,
     camera: &Camera,
@@ -37,17 +44,34 @@ pub(crate) fn render<F: FnMut(Progress<'_>)>(
         XorShiftRng::from_rng(rand::thread_rng()).expect("could not generate RNG")
     }
 
+    if let Some(ref reduction) = config.radius_reduction {
+        stochastic::render(
+            film,
+            aovs,
+            task_runner,
+            on_status,
+            renderer,
+            pixel_samples,
+            config,
+            reduction,
+            world,
+            camera,
+            resources,
+        );
+        return;
+    }
+
     let tiles = make_tiles(film.width(), film.height(), renderer.tile_size, camera);
 
     let num_tiles = tiles.len();
     let mut progress;
 
-    let num_passes = renderer.pixel_samples as usize * config.photon_passes;
+    let num_passes = pixel_samples as usize * config.photon_passes;
 
     let photon_probability = 1.0
         / (renderer.bounces as f32 * config.photon_bounces as f32 * config.photon_passes as f32);
 
-    for pixel_pass in 0..renderer.pixel_samples {
+    for pixel_pass in 0..pixel_samples {
         let status_message = format!(
             "(pass {}/{}): observing the scene",
             pixel_pass as usize * config.photon_passes,
@@ -57,6 +81,7 @@ pub(crate) fn render<F: FnMut(Progress<'_>)>(
         on_status(Progress {
             progress: 0,
             message: &status_message,
+            pass_finished: false,
         });
         progress = 0;
         task_runner.run_tasks(
@@ -73,7 +98,7 @@ pub(crate) fn render<F: FnMut(Progress<'_>)>(
                     additional_samples.clear();
 
                     let position = tile.sample_point(&mut rng);
-                    let ray = camera.ray_towards(&position, &mut rng);
+
                     additional_samples.extend(
                         film.sample_many_wavelengths(&mut rng, renderer.spectrum_samples as usize)
                             .map(|wavelength| {
@@ -92,10 +117,37 @@ pub(crate) fn render<F: FnMut(Progress<'_>)>(
                         additional_samples.swap_remove(rng.gen_range(0..additional_samples.len()));
                     let wavelength = main_sample.0.wavelength;
 
+                    if camera.is_dispersive() {
+                        // The camera ray only works for the main wavelength.
+                        additional_samples.clear();
+                    }
+
+                    let Some(CameraRay {
+                        ray,
+                        time,
+                        weight: camera_weight,
+                    }) = camera.ray_towards(&position, wavelength, &mut rng)
+                    else {
+                        // The camera doesn't let any light through towards
+                        // this position.
+                        film.expose(position, main_sample.0);
+                        for (sample, _) in additional_samples.drain(..) {
+                            film.expose(position, sample);
+                        }
+                        continue;
+                    };
+                    let ray_origin = ray.origin;
+
+                    main_sample.1 = camera_weight;
+                    for (_, reflectance) in &mut additional_samples {
+                        *reflectance = camera_weight;
+                    }
+
                     trace(
                         &mut bounces,
                         &mut rng,
                         ray,
+                        time,
                         wavelength,
                         world,
                         renderer.bounces,
@@ -104,9 +156,23 @@ pub(crate) fn render<F: FnMut(Progress<'_>)>(
                     );
                     let p = 1.0 / renderer.bounces as f32;
 
+                    if let Some(first_bounce) = bounces.first() {
+                        aovs.record_surface(
+                            position,
+                            ray_origin,
+                            first_bounce,
+                            std::iter::once(wavelength).chain(
+                                additional_samples
+                                    .iter()
+                                    .map(|(sample, _)| sample.wavelength),
+                            ),
+                            &mut exe,
+                        );
+                    }
+
                     let mut use_additional = true;
 
-                    let mut current = Parent::Source(position);
+                    let mut current = Parent::Source(camera_weight);
                     for bounce in bounces.drain(..) {
                         use_additional = !bounce.dispersed && use_additional;
                         let additional_samples = if use_additional {
@@ -118,7 +184,7 @@ pub(crate) fn render<F: FnMut(Progress<'_>)>(
                         contribute(&bounce, &mut main_sample, additional_samples, &mut exe);
 
                         match bounce.ty {
-                            BounceType::Diffuse(_, _) => {
+                            BounceType::Diffuse(..) => {
                                 let b = Arc::new(CameraBounce {
                                     wavelength,
                                     parent: current,
@@ -162,6 +228,7 @@ pub(crate) fn render<F: FnMut(Progress<'_>)>(
                 on_status(Progress {
                     progress: ((progress * 100) / num_tiles) as u8,
                     message: &status_message,
+                    pass_finished: false,
                 });
             },
         );
@@ -176,6 +243,7 @@ pub(crate) fn render<F: FnMut(Progress<'_>)>(
             on_status(Progress {
                 progress: 0,
                 message: &status_message,
+                pass_finished: false,
             });
             progress = 0;
             task_runner.run_tasks(
@@ -197,6 +265,7 @@ pub(crate) fn render<F: FnMut(Progress<'_>)>(
 
                         if let Some((_lamp, probability, mut ray_sample)) = res {
                             let wavelength = film.sample_wavelength(&mut rng);
+                            let time = camera.sample_time(&mut rng);
 
                             let (color, material_probability, dispersed, normal, texture) =
                                 match ray_sample.surface {
@@ -240,6 +309,7 @@ pub(crate) fn render<F: FnMut(Progress<'_>)>(
                                 &mut bounces,
                                 &mut rng,
                                 ray_sample.ray,
+                                time,
                                 wavelength,
                                 world,
                                 config.photon_bounces,
@@ -268,26 +338,29 @@ pub(crate) fn render<F: FnMut(Progress<'_>)>(
                                         * probability
                                         * material_probability,
                                     direct_light: vec![],
+                                    object_id: 0,
+                                    distant: false,
+                                    time,
                                 },
                                 probability: p,
                             });
 
                             if let Some(bounce) = bounces.get_mut(0) {
-                                if let BounceType::Diffuse(_, ref mut o) = bounce.ty {
+                                if let BounceType::Diffuse(_, _, ref mut o) = bounce.ty {
                                     *o = -incident
                                 }
                             }
 
                             pairs(&mut bounces, |to, from| {
                                 to.incident = -from.incident;
-                                if let BounceType::Diffuse(_, ref mut o) = from.ty {
+                                if let BounceType::Diffuse(_, _, ref mut o) = from.ty {
                                     *o = from.incident
                                 }
                             });
 
                             for bounce in bounces.drain(..) {
                                 match bounce.ty {
-                                    BounceType::Diffuse(_, _) => {
+                                    BounceType::Diffuse(..) => {
                                         let b = Arc::new(LightBounce {
                                             parent: Some(current),
                                             wavelength,
@@ -320,6 +393,7 @@ pub(crate) fn render<F: FnMut(Progress<'_>)>(
                     on_status(Progress {
                         progress: ((progress as f32 / config.photons as f32) * 100.0) as u8,
                         message: &status_message,
+                        pass_finished: false,
                     });
                 },
             );
@@ -334,6 +408,7 @@ pub(crate) fn render<F: FnMut(Progress<'_>)>(
             on_status(Progress {
                 progress: 0,
                 message: &status_message,
+                pass_finished: false,
             });
             progress = 0;
 
@@ -349,7 +424,7 @@ pub(crate) fn render<F: FnMut(Progress<'_>)>(
                             light_bounces.neighbors(&point, config.radius).collect();
                         let num_neighbors = neighbors.len();
                         for neighbor in neighbors {
-                            let bounce_dispersed = hit.bounce.dispersed;
+                            let bounce_dispersed = hit.bounce.dispersed || camera.is_dispersive();
                             let neighbor_dispersed = neighbor.bounce.dispersed;
 
                             if !bounce_dispersed || !neighbor_dispersed {
@@ -421,6 +496,7 @@ pub(crate) fn render<F: FnMut(Progress<'_>)>(
                     on_status(Progress {
                         progress: ((progress as f32 / camera_bounces.len() as f32) * 100.0) as u8,
                         message: &status_message,
+                        pass_finished: false,
                     });
                 },
             );
@@ -433,10 +509,13 @@ pub struct Config {
     pub photons: usize,
     pub photon_bounces: u32,
     pub photon_passes: usize,
+    /// Makes each pixel shrink its radius over the passes, starting from
+    /// `radius`, instead of keeping it fixed.
+    pub radius_reduction: Option<RadiusReduction>,
 }
 
 struct CameraBounce<'a> {
-    parent: Parent<CameraBounce<'a>, Point2<f32>>,
+    parent: Parent<CameraBounce<'a>, f32>,
     wavelength: f32,
     bounce: Bounce<'a>,
     pixel: DetachedPixel<'a>,
@@ -451,7 +530,7 @@ impl<'a> CameraBounce<'a> {
         exe: &mut ExecutionContext<'a>,
     ) {
         let mut current = Some(self);
-        let mut first_brdf = if let BounceType::Diffuse(brdf, _) = self.bounce.ty {
+        let mut first_brdf = if let BounceType::Diffuse(brdf, ..) = self.bounce.ty {
             Some((brdf, exit))
         } else {
             None
@@ -488,7 +567,12 @@ impl<'a> CameraBounce<'a> {
 
             match hit.parent {
                 Parent::Bounce(ref b) => current = Some(b),
-                Parent::Source(_) => current = None,
+                Parent::Source(camera_weight) => {
+                    for (_, reflectance) in &mut *samples {
+                        *reflectance *= camera_weight;
+                    }
+                    current = None;
+                }
             }
         }
     }
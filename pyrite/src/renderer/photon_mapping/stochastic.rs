@@ -0,0 +1,738 @@
+use std::{
+    cell::Cell,
+    error::Error,
+    f32::consts::PI,
+    io::{self, Read, Write},
+    sync::{Arc, Mutex},
+};
+
+use rand::{self, Rng, SeedableRng};
+use rand_xorshift::XorShiftRng;
+
+use cgmath::{EuclideanSpace, InnerSpace, Point2, Vector2};
+use collision::Ray3;
+
+use super::{Config, KdPoint};
+use crate::aovs::Aovs;
+use crate::cameras::{Camera, CameraRay};
+use crate::film::{read_bytes, Film, Sample};
+use crate::lamp::{RaySample, Surface};
+use crate::renderer::{
+    algorithm::{contribute, contribute_distant, make_tiles, Tile},
+    Progress, Renderer, TaskRunner,
+};
+use crate::spatial::kd_tree::{self, KdTree};
+use crate::tracer::{trace, Bounce, BounceType, RenderContext};
+use crate::utils::BatchRange;
+use crate::{
+    materials::ProbabilityInput,
+    program::{ExecutionContext, Resources},
+    world::World,
+};
+
+/// Shrinks the gathering radius of each pixel as it finds more photons, as
+/// in stochastic progressive photon mapping by Hachisuka and Jensen. The
+/// radii are kept between the render passes.
+pub struct RadiusReduction {
+    /// The fraction of the newly found photons that is kept when the radius
+    /// shrinks, between 0 and 1.
+    pub alpha: f32,
+    /// The state of each pixel, tile by tile, after the last finished pass.
+    pixels: Mutex<Vec<Vec<PixelState>>>,
+}
+
+impl RadiusReduction {
+    pub fn new(alpha: f32) -> Self {
+        RadiusReduction {
+            alpha,
+            pixels: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Writes the state of each pixel, so the radii can continue to shrink
+    /// when the render is resumed. The tiles have `tile_size` pixels on each
+    /// side.
+    pub fn write_checkpoint(&self, tile_size: usize, mut writer: impl Write) -> io::Result<()> {
+        let pixels = self
+            .pixels
+            .lock()
+            .expect("the pixel state lock was poisoned");
+
+        writer.write_all(&(tile_size as u64).to_le_bytes())?;
+        writer.write_all(&(pixels.len() as u64).to_le_bytes())?;
+        for states in pixels.iter() {
+            writer.write_all(&(states.len() as u64).to_le_bytes())?;
+            for state in states {
+                writer.write_all(&state.radius.to_le_bytes())?;
+                writer.write_all(&state.photons.to_le_bytes())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the state of each pixel with the state from a checkpoint.
+    /// The checkpoint has to have the same tile size.
+    pub fn read_checkpoint(
+        &self,
+        tile_size: usize,
+        mut reader: impl Read,
+    ) -> Result<(), Box<dyn Error>> {
+        let checkpoint_tile_size = u64::from_le_bytes(read_bytes(&mut reader)?);
+        if checkpoint_tile_size != tile_size as u64 {
+            return Err(format!(
+                "the checkpoint has {} pixel tiles, but the project has {} pixel tiles",
+                checkpoint_tile_size, tile_size
+            )
+            .into());
+        }
+
+        let num_tiles = u64::from_le_bytes(read_bytes(&mut reader)?);
+        let mut pixels = Vec::new();
+        for _ in 0..num_tiles {
+            let num_pixels = u64::from_le_bytes(read_bytes(&mut reader)?);
+            let mut states = Vec::new();
+            for _ in 0..num_pixels {
+                let state = PixelState {
+                    radius: f32::from_le_bytes(read_bytes(&mut reader)?),
+                    photons: f32::from_le_bytes(read_bytes(&mut reader)?),
+                };
+
+                if !(state.radius.is_finite() && state.photons.is_finite()) {
+                    return Err("the checkpoint contains an invalid pixel radius".into());
+                }
+
+                states.push(state);
+            }
+            pixels.push(states);
+        }
+
+        *self
+            .pixels
+            .lock()
+            .expect("the pixel state lock was poisoned") = pixels;
+
+        Ok(())
+    }
+}
+
+/// The gathering state of a pixel.
+#[derive(Clone, Copy)]
+struct PixelState {
+    radius: f32,
+    /// The accumulated number of photons, which decides how much the radius
+    /// shrinks.
+    photons: f32,
+}
+
+impl PixelState {
+    /// Shrinks the radius after finding `found` photons, so that the
+    /// accumulated photons in the new radius are a fraction `alpha` of the
+    /// new ones.
+    fn update(&mut self, found: usize, alpha: f32) {
+        if found == 0 {
+            return;
+        }
+
+        let found = found as f32;
+        let photons = self.photons + alpha * found;
+        self.radius *= (photons / (self.photons + found)).sqrt();
+        self.photons = photons;
+    }
+}
+
+// The flux that the photons have gathered in earlier passes is rescaled with
+// the area whenever the radius shrinks, so the estimate after pass `n` is the
+// sum of each pass' flux divided by its own gathering area, divided by the
+// total number of photons. That's the average of the density estimates from
+// each pass, so they can be added to the film as samples.
+pub(super) fn render<F: FnMut(Progress<'_>)>(
+    film: &Film,
+    aovs: &Aovs,
+    task_runner: &TaskRunner,
+    mut on_status: F,
+    renderer: &Renderer,
+    pixel_samples: u32,
+    config: &Config,
+    reduction: &RadiusReduction,
+    world: &World,
+    camera: &Camera,
+    resources: &Resources,
+) {
+    fn gen_rng() -> XorShiftRng {
+        XorShiftRng::from_rng(rand::thread_rng()).expect("could not generate RNG")
+    }
+
+    let tiles = make_tiles(film.width(), film.height(), renderer.tile_size, camera);
+    let num_tiles = tiles.len();
+
+    // The shared state is only updated between the passes, so checkpoints
+    // don't see it half way through a pass.
+    let mut pixels = reduction
+        .pixels
+        .lock()
+        .expect("the pixel state lock was poisoned")
+        .clone();
+    let matches_tiles = pixels.len() == num_tiles
+        && pixels
+            .iter()
+            .zip(&tiles)
+            .all(|(states, tile)| states.len() == tile.area());
+    if !matches_tiles {
+        let initial = PixelState {
+            radius: config.radius,
+            photons: 0.0,
+        };
+        pixels = tiles
+            .iter()
+            .map(|tile| vec![initial; tile.area()])
+            .collect();
+    }
+
+    for pass in 1..=pixel_samples {
+        let status_message = format!("(pass {}/{}): shooting photons", pass, pixel_samples);
+        on_status(Progress {
+            progress: 0,
+            message: &status_message,
+            pass_finished: false,
+        });
+
+        let mut photons = Vec::with_capacity(config.photons);
+        let mut progress = 0;
+        task_runner.run_tasks(
+            BatchRange::new(0..config.photons, 5000).map(|batch| (batch, gen_rng())),
+            |_index, (num_photons, mut rng), _progress| {
+                let mut exe = ExecutionContext::new(resources);
+                let photons =
+                    shoot_photons(num_photons, film, config, camera, world, &mut rng, &mut exe);
+
+                (num_photons, photons)
+            },
+            |_, (num_photons, new_photons)| {
+                photons.extend(new_photons);
+                progress += num_photons;
+                on_status(Progress {
+                    progress: ((progress as f32 / config.photons as f32) * 100.0) as u8,
+                    message: &status_message,
+                    pass_finished: false,
+                });
+            },
+        );
+        let photons = KdTree::new(photons, 100);
+
+        let status_message = format!("(pass {}/{}): gathering light", pass, pixel_samples);
+        on_status(Progress {
+            progress: 0,
+            message: &status_message,
+            pass_finished: false,
+        });
+
+        let mut tile_pixels: Vec<_> = std::iter::repeat_with(|| None).take(num_tiles).collect();
+        let mut progress = 0;
+        task_runner.run_tasks(
+            tiles
+                .iter()
+                .zip(std::mem::take(&mut pixels))
+                .map(|(tile, states)| (tile, states, gen_rng())),
+            |_index, (tile, mut states, mut rng), _progress| {
+                let mut exe = ExecutionContext::new(resources);
+                let gather = Gather {
+                    film,
+                    aovs,
+                    camera,
+                    world,
+                    renderer,
+                    photons: &photons,
+                    emitted_photons: config.photons,
+                    alpha: reduction.alpha,
+                };
+
+                for (index, state) in states.iter_mut().enumerate() {
+                    gather.render_pixel(tile, index, state, &mut rng, &mut exe);
+                }
+
+                states
+            },
+            |index, states| {
+                tile_pixels[index] = Some(states);
+                progress += 1;
+                on_status(Progress {
+                    progress: ((progress * 100) / num_tiles) as u8,
+                    message: &status_message,
+                    pass_finished: false,
+                });
+            },
+        );
+        pixels = tile_pixels
+            .into_iter()
+            .map(|states| states.expect("a tile was not rendered"))
+            .collect();
+        *reduction
+            .pixels
+            .lock()
+            .expect("the pixel state lock was poisoned") = pixels.clone();
+
+        on_status(Progress {
+            progress: 100,
+            message: &format!("(pass {}/{}): finished", pass, pixel_samples),
+            pass_finished: true,
+        });
+    }
+}
+
+/// Traces photons from the lamps, and returns where they land on diffuse
+/// surfaces. The first hit is left out, because the direct light is sampled
+/// from the camera paths. The sky and directional lights are only found by
+/// the camera paths.
+fn shoot_photons<'w>(
+    num_photons: usize,
+    film: &Film,
+    config: &Config,
+    camera: &Camera,
+    world: &'w World,
+    rng: &mut impl Rng,
+    exe: &mut ExecutionContext<'w>,
+) -> Vec<Photon<'w>> {
+    let mut photons = vec![];
+
+    if world.lights.is_empty() {
+        return photons;
+    }
+
+    for _ in 0..num_photons {
+        let Some((lamp, lamp_probability)) = world.pick_lamp(rng) else {
+            continue;
+        };
+
+        // Directional lights have no position to start from, and are found by
+        // the camera paths instead.
+        let Some(RaySample {
+            ray,
+            surface,
+            weight,
+        }) = lamp.sample_ray(rng)
+        else {
+            continue;
+        };
+
+        let wavelength = film.sample_wavelength(rng);
+        let time = camera.sample_time(rng);
+
+        // The lamps send light in every direction.
+        let direction = if rng.gen() {
+            ray.direction
+        } else {
+            -ray.direction
+        };
+
+        let (color, material_probability, dispersed, normal, texture, cos) = match surface {
+            Surface::Physical {
+                normal,
+                material,
+                texture,
+            } => {
+                let component = material.choose_emissive(rng);
+                let input = ProbabilityInput {
+                    wavelength,
+                    wavelength_used: Cell::new(false),
+                    normal,
+                    incident: -direction,
+                    texture_coordinate: texture,
+                };
+
+                let probability = component.get_probability(exe, &input);
+
+                (
+                    component.bsdf.color,
+                    probability,
+                    input.wavelength_used.get(),
+                    normal,
+                    texture,
+                    normal.dot(direction).abs(),
+                )
+            }
+            Surface::Color(color) => (color, 1.0, false, direction, Point2::origin(), 1.0),
+        };
+
+        let mut bounces = Vec::with_capacity(config.photon_bounces as usize);
+        trace(
+            &mut bounces,
+            rng,
+            Ray3::new(ray.origin, direction),
+            time,
+            wavelength,
+            world,
+            config.photon_bounces,
+            0,
+            exe,
+        );
+
+        let path = Arc::new(PhotonPath {
+            source: Bounce {
+                ty: BounceType::Emission,
+                dispersed,
+                color,
+                incident: -direction,
+                position: ray.origin,
+                normal,
+                texture,
+                // The direction is picked from the whole sphere.
+                probability: material_probability * weight * cos * 4.0 * PI / lamp_probability,
+                direct_light: vec![],
+                object_id: 0,
+                distant: false,
+                time,
+            },
+            bounces,
+        });
+
+        let mut dispersed = dispersed;
+        for (index, bounce) in path.bounces.iter().enumerate() {
+            if index > 0 {
+                if let BounceType::Diffuse(..) = bounce.ty {
+                    photons.push(Photon {
+                        path: path.clone(),
+                        index,
+                        wavelength,
+                        dispersed,
+                    });
+                }
+            }
+
+            dispersed |= bounce.dispersed;
+        }
+    }
+
+    photons
+}
+
+/// A path from a light source.
+struct PhotonPath<'a> {
+    /// The start of the path, with the emitted light.
+    source: Bounce<'a>,
+    bounces: Vec<Bounce<'a>>,
+}
+
+/// A photon that landed on a diffuse surface, at `index` in its path.
+struct Photon<'a> {
+    path: Arc<PhotonPath<'a>>,
+    index: usize,
+    /// The wavelength that the path was traced with.
+    wavelength: f32,
+    /// Set if the path on the way here only works for `wavelength`.
+    dispersed: bool,
+}
+
+impl<'a> Photon<'a> {
+    fn bounce(&self) -> &Bounce<'a> {
+        &self.path.bounces[self.index]
+    }
+
+    /// The light that the photon carries at `wavelength`.
+    fn power(&self, wavelength: f32, exe: &mut ExecutionContext<'a>) -> f32 {
+        let source = &self.path.source;
+        let context = RenderContext {
+            wavelength,
+            incident: source.incident,
+            normal: source.normal,
+            texture: source.texture,
+        };
+        let mut power = exe.run(source.color, &context) * source.probability;
+
+        for bounce in &self.path.bounces[..self.index] {
+            let context = RenderContext {
+                wavelength,
+                incident: bounce.incident,
+                normal: bounce.normal,
+                texture: bounce.texture,
+            };
+            power *= exe.run(bounce.color, &context)
+                * bounce.probability
+                * bounce.ty.brdf(bounce.incident, bounce.normal);
+        }
+
+        power
+    }
+}
+
+impl<'a> kd_tree::Element for Photon<'a> {
+    type Point = KdPoint;
+
+    fn position(&self) -> KdPoint {
+        KdPoint(self.bounce().position)
+    }
+
+    fn sq_distance(&self, point: &KdPoint) -> f32 {
+        (self.bounce().position - point.0).magnitude2()
+    }
+}
+
+/// Renders the pixel samples for a pass, with the photons from the same
+/// pass.
+struct Gather<'a> {
+    film: &'a Film,
+    aovs: &'a Aovs,
+    camera: &'a Camera,
+    world: &'a World<'a>,
+    renderer: &'a Renderer,
+    photons: &'a KdTree<Photon<'a>>,
+    emitted_photons: usize,
+    alpha: f32,
+}
+
+impl<'a> Gather<'a> {
+    /// Traces a camera path through a random point in pixel `index` of
+    /// `tile`. It adds the emitted and directly sampled light until the
+    /// first diffuse surface, the light from the photons around it, and the
+    /// light from the sky and directional lights after it.
+    fn render_pixel(
+        &self,
+        tile: &Tile,
+        index: usize,
+        state: &mut PixelState,
+        rng: &mut impl Rng,
+        exe: &mut ExecutionContext<'a>,
+    ) {
+        let film = self.film;
+        let renderer = self.renderer;
+
+        let pixel_size = Vector2::new(
+            tile.area.size.x / tile.width as f32,
+            tile.area.size.y / tile.height as f32,
+        );
+        let position = tile.area.from
+            + Vector2::new(
+                ((index % tile.width) as f32 + rng.gen::<f32>()) * pixel_size.x,
+                ((index / tile.width) as f32 + rng.gen::<f32>()) * pixel_size.y,
+            );
+
+        let spectrum_samples = renderer.spectrum_samples as usize;
+        let mut additional_samples: Vec<_> = film
+            .sample_many_wavelengths(rng, spectrum_samples)
+            .map(|wavelength| {
+                (
+                    Sample {
+                        wavelength,
+                        brightness: 0.0,
+                        weight: 1.0,
+                    },
+                    1.0,
+                )
+            })
+            .collect();
+
+        let mut main_sample =
+            additional_samples.swap_remove(rng.gen_range(0..additional_samples.len()));
+        let wavelength = main_sample.0.wavelength;
+
+        if self.camera.is_dispersive() {
+            // The camera ray only works for the main wavelength.
+            additional_samples.clear();
+        }
+
+        let Some(CameraRay {
+            ray,
+            time,
+            weight: camera_weight,
+        }) = self.camera.ray_towards(&position, wavelength, rng)
+        else {
+            // The camera doesn't let any light through towards this position.
+            film.expose(position, main_sample.0);
+            for (sample, _) in additional_samples {
+                film.expose(position, sample);
+            }
+            return;
+        };
+        let ray_origin = ray.origin;
+
+        main_sample.1 = camera_weight;
+        for (_, reflectance) in &mut additional_samples {
+            *reflectance = camera_weight;
+        }
+
+        let mut bounces = Vec::with_capacity(renderer.bounces as usize);
+        trace(
+            &mut bounces,
+            rng,
+            ray,
+            time,
+            wavelength,
+            self.world,
+            renderer.bounces,
+            renderer.light_samples,
+            exe,
+        );
+
+        if let Some(first_bounce) = bounces.first() {
+            self.aovs.record_surface(
+                position,
+                ray_origin,
+                first_bounce,
+                std::iter::once(wavelength).chain(
+                    additional_samples
+                        .iter()
+                        .map(|(sample, _)| sample.wavelength),
+                ),
+                exe,
+            );
+        }
+
+        // The photons take over the light from the lamps at the first
+        // diffuse surface. The lamps are still sampled from it, and the light
+        // that the path hits right after it is the other part of that direct
+        // light. The sky and directional lights don't send out photons, so
+        // the path continues to find their light.
+        let mut use_additional = true;
+        let mut visible_point = None;
+        let mut visible_index = None;
+        for (index, bounce) in bounces.iter().enumerate() {
+            use_additional = !bounce.dispersed && use_additional;
+            let additional = if use_additional {
+                &mut *additional_samples
+            } else {
+                &mut []
+            };
+
+            match visible_index {
+                None => {
+                    if let BounceType::Diffuse(..) = bounce.ty {
+                        visible_point =
+                            Some(VisiblePoint::new(bounce, &main_sample, additional, exe));
+                        visible_index = Some(index);
+                    }
+
+                    contribute(bounce, &mut main_sample, additional, exe);
+                }
+                Some(visible_index) if index == visible_index + 1 && bounce.ty.is_emission() => {
+                    contribute(bounce, &mut main_sample, additional, exe);
+                }
+                Some(_) => contribute_distant(bounce, &mut main_sample, additional, exe),
+            }
+        }
+
+        if let Some(visible_point) = visible_point {
+            let found = self.gather(
+                &visible_point,
+                state.radius,
+                &mut main_sample,
+                &mut additional_samples[..visible_point.additional],
+                exe,
+            );
+            state.update(found, self.alpha);
+        }
+
+        film.expose(position, main_sample.0);
+        if use_additional {
+            for (sample, _) in additional_samples {
+                film.expose(position, sample);
+            }
+        }
+    }
+
+    /// Adds the light from the photons within `radius` of the visible point
+    /// to the samples, and returns how many photons it found.
+    fn gather(
+        &self,
+        visible_point: &VisiblePoint<'_, 'a>,
+        radius: f32,
+        main_sample: &mut (Sample, f32),
+        additional_samples: &mut [(Sample, f32)],
+        exe: &mut ExecutionContext<'a>,
+    ) -> usize {
+        let bounce = visible_point.bounce;
+        let BounceType::Diffuse(brdf, pdf, _) = bounce.ty else {
+            return 0;
+        };
+
+        let (start, end) = self.film.wavelength_span();
+        let strata = self.renderer.spectrum_samples as usize;
+        let stratum = |wavelength: f32| {
+            (((wavelength - start) / (end - start) * strata as f32) as usize).min(strata - 1)
+        };
+
+        let scale = 1.0 / (self.emitted_photons as f32 * PI * radius * radius);
+        let point = KdPoint(bounce.position);
+        let mut found = 0;
+
+        for photon in self.photons.neighbors(&point, radius) {
+            let photon_bounce = photon.bounce();
+
+            // The photon has to arrive on the side that the camera sees.
+            if photon_bounce.incident.dot(bounce.normal) * bounce.incident.dot(bounce.normal) <= 0.0
+            {
+                continue;
+            }
+
+            found += 1;
+
+            let towards_light = -photon_bounce.incident;
+            let cos = bounce.normal.dot(towards_light).abs();
+            if cos == 0.0 {
+                continue;
+            }
+
+            let bsdf = brdf(bounce.incident, bounce.normal, towards_light)
+                * pdf(bounce.incident, towards_light, bounce.normal)
+                / cos;
+
+            let samples = std::iter::once(&mut *main_sample)
+                .chain(additional_samples.iter_mut())
+                .zip(&visible_point.reflectance);
+            for ((sample, _), &reflectance) in samples {
+                // A dispersed photon only has the light for its own
+                // wavelength, so it stands in for the other wavelengths
+                // in the same part of the spectrum.
+                let weight = if !photon.dispersed {
+                    1.0
+                } else if stratum(photon.wavelength) == stratum(sample.wavelength) {
+                    strata as f32
+                } else {
+                    continue;
+                };
+
+                sample.brightness +=
+                    photon.power(sample.wavelength, exe) * reflectance * bsdf * weight * scale;
+            }
+        }
+
+        found
+    }
+}
+
+/// The first diffuse surface on a camera path.
+struct VisiblePoint<'b, 'a> {
+    bounce: &'b Bounce<'a>,
+    /// The throughput from the camera, including the surface color, for
+    /// the main sample followed by the additional samples.
+    reflectance: Vec<f32>,
+    /// The number of additional samples that the path works for.
+    additional: usize,
+}
+
+impl<'b, 'a> VisiblePoint<'b, 'a> {
+    fn new(
+        bounce: &'b Bounce<'a>,
+        main_sample: &(Sample, f32),
+        additional_samples: &[(Sample, f32)],
+        exe: &mut ExecutionContext<'a>,
+    ) -> Self {
+        let reflectance = std::iter::once(main_sample)
+            .chain(additional_samples)
+            .map(|&(ref sample, reflectance)| {
+                let context = RenderContext {
+                    wavelength: sample.wavelength,
+                    incident: bounce.incident,
+                    normal: bounce.normal,
+                    texture: bounce.texture,
+                };
+                reflectance * exe.run(bounce.color, &context) * bounce.probability
+            })
+            .collect();
+
+        VisiblePoint {
+            bounce,
+            reflectance,
+            additional: additional_samples.len(),
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use std::time::{Duration, Instant};
+
+use crate::film::Film;
+
+/// Settings for rendering the image in multiple full passes, until a limit is
+/// reached. Each pass has the renderer's `pixel_samples`.
+pub struct Progressive {
+    pub time_limit: Option<Duration>,
+    pub noise_limit: Option<f32>,
+}
+
+/// Keeps track of the render passes and decides when to stop.
+pub(crate) struct Passes<'a> {
+    settings: Option<&'a Progressive>,
+    pixel_samples: u32,
+    samples: u32,
+    passes: u32,
+    started: Instant,
+    noise: Option<f32>,
+}
+
+impl<'a> Passes<'a> {
+    /// Starts after `samples` already finished samples per pixel.
+    pub fn new(settings: Option<&'a Progressive>, pixel_samples: u32, samples: u32) -> Self {
+        Passes {
+            settings,
+            pixel_samples,
+            samples,
+            passes: 0,
+            started: Instant::now(),
+            noise: None,
+        }
+    }
+
+    pub fn is_progressive(&self) -> bool {
+        self.settings.is_some()
+    }
+
+    /// The number of samples per pixel in the finished passes.
+    pub fn samples(&self) -> u32 {
+        self.samples
+    }
+
+    /// Returns the number of samples per pixel for the next pass, or `None`
+    /// if the render is done.
+    pub fn next_pass(&mut self) -> Option<u32> {
+        let Some(settings) = self.settings else {
+            let remaining = self.pixel_samples.saturating_sub(self.samples);
+            return (remaining > 0).then_some(remaining);
+        };
+
+        if self.passes > 0 {
+            if let Some(time_limit) = settings.time_limit {
+                // Stop early if the next pass is expected to run past the limit.
+                let elapsed = self.started.elapsed();
+                let pass_time = elapsed / self.passes;
+                if elapsed + pass_time > time_limit {
+                    return None;
+                }
+            }
+
+            if let (Some(noise_limit), Some(noise)) = (settings.noise_limit, self.noise) {
+                if noise <= noise_limit {
+                    return None;
+                }
+            }
+        }
+
+        Some(self.pixel_samples)
+    }
+
+    pub fn finish_pass(&mut self, samples: u32, film: &Film) {
+        self.samples += samples;
+        self.passes += 1;
+
+        if self
+            .settings
+            .and_then(|settings| settings.noise_limit)
+            .is_some()
+        {
+            self.noise = estimate_noise(film);
+        }
+    }
+
+    /// Describes the current pass.
+    pub fn message(&self) -> String {
+        format!("Pass {} ({})", self.passes + 1, self.summary())
+    }
+
+    /// Describes the last finished pass.
+    pub fn finished_message(&self) -> String {
+        format!("Finished pass {} ({})", self.passes, self.summary())
+    }
+
+    fn summary(&self) -> String {
+        if let Some(noise) = self.noise {
+            format!("{} samples, {:.2}% noise", self.samples, noise * 100.0)
+        } else {
+            format!("{} samples", self.samples)
+        }
+    }
+}
+
+/// Estimates the RMS error of the image, relative to its average brightness.
+/// Returns `None` if some pixels don't have an error estimate yet. A black
+/// image has no noise.
+fn estimate_noise(film: &Film) -> Option<f32> {
+    let mut squared_error = 0.0f64;
+    let mut total = 0.0f64;
+    let mut pixels = 0;
+
+    for spectrum in film.developed_pixels() {
+        let estimate = spectrum.estimate();
+        squared_error += (estimate.error as f64).powi(2);
+        total += estimate.brightness as f64;
+        pixels += 1;
+    }
+
+    if !squared_error.is_finite() {
+        None
+    } else if total > 0.0 {
+        Some(((squared_error * pixels as f64).sqrt() / total) as f32)
+    } else {
+        Some(0.0)
+    }
+}
@@ -2,10 +2,11 @@ use rand::{Rng, SeedableRng};
 use rand_xorshift::XorShiftRng;
 
 use super::{
-    algorithm::{contribute, make_tiles, Tile},
+    algorithm::{contribute, distribute_samples, make_tiles, Tile},
     LocalProgress, Progress, Renderer, TaskRunner,
 };
-use crate::cameras::Camera;
+use crate::aovs::{direct_bounces, Aovs};
+use crate::cameras::{Camera, CameraRay};
 use crate::film::{Film, Sample};
 use crate::tracer::trace;
 use crate::{
@@ -16,9 +17,59 @@ use std::time::{Duration, Instant};
 
 pub(crate) fn render<F: FnMut(Progress<'_>)>(
     film: &Film,
-    task_runner: TaskRunner,
+    aovs: &Aovs,
+    task_runner: &TaskRunner,
     mut on_status: F,
     renderer: &Renderer,
+    pixel_samples: u32,
+    world: &World,
+    camera: &Camera,
+    resources: &Resources,
+) {
+    let (uniform_samples, adaptive_samples) = renderer.split_adaptive_samples(pixel_samples);
+
+    let tiles = make_tiles(film.width(), film.height(), renderer.tile_size, camera);
+    render_tiles(
+        "Rendering",
+        tiles
+            .into_iter()
+            .map(|tile| (tile, uniform_samples))
+            .collect(),
+        film,
+        aovs,
+        task_runner,
+        &mut on_status,
+        renderer,
+        world,
+        camera,
+        resources,
+    );
+
+    if adaptive_samples > 0 {
+        let tiles = make_tiles(film.width(), film.height(), renderer.tile_size, camera);
+        render_tiles(
+            "Adaptive sampling",
+            distribute_samples(film, tiles, adaptive_samples, uniform_samples),
+            film,
+            aovs,
+            task_runner,
+            &mut on_status,
+            renderer,
+            world,
+            camera,
+            resources,
+        );
+    }
+}
+
+fn render_tiles<F: FnMut(Progress<'_>)>(
+    status_message: &str,
+    tiles: Vec<(Tile, u32)>,
+    film: &Film,
+    aovs: &Aovs,
+    task_runner: &TaskRunner,
+    on_status: &mut F,
+    renderer: &Renderer,
     world: &World,
     camera: &Camera,
     resources: &Resources,
@@ -27,29 +78,40 @@ pub(crate) fn render<F: FnMut(Progress<'_>)>(
         XorShiftRng::from_rng(rand::thread_rng()).expect("could not generate RNG")
     }
 
-    let status_message = "Rendering";
     on_status(Progress {
         progress: 0,
-        message: &status_message,
+        message: status_message,
+        pass_finished: false,
     });
 
-    let tiles = make_tiles(film.width(), film.height(), renderer.tile_size, camera);
-
     let mut progress: usize = 0;
     let num_tiles = tiles.len();
 
     task_runner.run_tasks(
-        tiles.into_iter().map(|f| (f, gen_rng())),
-        |index, (tile, rng), progress| {
+        tiles
+            .into_iter()
+            .map(|(tile, pixel_samples)| (tile, pixel_samples, gen_rng())),
+        |index, (tile, pixel_samples, rng), progress| {
             render_tile(
-                index, rng, tile, film, camera, world, resources, renderer, progress,
+                index,
+                rng,
+                tile,
+                film,
+                aovs,
+                camera,
+                world,
+                resources,
+                renderer,
+                pixel_samples,
+                progress,
             );
         },
         |_, _| {
             progress += 1;
             on_status(Progress {
                 progress: ((progress * 100) / num_tiles) as u8,
-                message: &status_message,
+                message: status_message,
+                pass_finished: false,
             });
         },
     );
@@ -60,17 +122,20 @@ fn render_tile<R: Rng>(
     mut rng: R,
     tile: Tile,
     film: &Film,
+    aovs: &Aovs,
     camera: &Camera,
     world: &World,
     resources: &Resources,
     renderer: &Renderer,
+    pixel_samples: u32,
     progress: LocalProgress,
 ) {
     let mut additional_samples = Vec::with_capacity(renderer.spectrum_samples as usize - 1);
     let mut path = Vec::with_capacity(renderer.bounces as usize);
+    let mut direct_brightness = Vec::with_capacity(renderer.spectrum_samples as usize);
     let mut exe = ExecutionContext::new(resources);
 
-    let iterations = tile.area() as u64 * renderer.pixel_samples as u64;
+    let iterations = tile.area() as u64 * pixel_samples as u64;
     let message = format!("Tile {}", index + 1);
     let mut last_progress = Instant::now();
     progress.show(message, iterations);
@@ -83,11 +148,10 @@ fn render_tile<R: Rng>(
 
         additional_samples.clear();
         path.clear();
+        direct_brightness.clear();
 
         let position = tile.sample_point(&mut rng);
 
-        let ray = camera.ray_towards(&position, &mut rng);
-
         additional_samples.extend(
             film.sample_many_wavelengths(&mut rng, renderer.spectrum_samples as usize)
                 .map(|wavelength| {
@@ -106,10 +170,36 @@ fn render_tile<R: Rng>(
             additional_samples.swap_remove(rng.gen_range(0..additional_samples.len()));
         let wavelength = main_sample.0.wavelength;
 
+        if camera.is_dispersive() {
+            // The camera ray only works for the main wavelength.
+            additional_samples.clear();
+        }
+
+        let Some(CameraRay {
+            ray,
+            time,
+            weight: camera_weight,
+        }) = camera.ray_towards(&position, wavelength, &mut rng)
+        else {
+            // The camera doesn't let any light through towards this position.
+            film.expose(position, main_sample.0);
+            for (sample, _) in additional_samples.drain(..) {
+                film.expose(position, sample);
+            }
+            continue;
+        };
+        let ray_origin = ray.origin;
+
+        main_sample.1 = camera_weight;
+        for (_, reflectance) in &mut additional_samples {
+            *reflectance = camera_weight;
+        }
+
         trace(
             &mut path,
             &mut rng,
             ray,
+            time,
             wavelength,
             world,
             renderer.bounces,
@@ -117,23 +207,53 @@ fn render_tile<R: Rng>(
             &mut exe,
         );
 
+        if let Some(first_bounce) = path.first() {
+            aovs.record_surface(
+                position,
+                ray_origin,
+                first_bounce,
+                std::iter::once(wavelength).chain(
+                    additional_samples
+                        .iter()
+                        .map(|(sample, _)| sample.wavelength),
+                ),
+                &mut exe,
+            );
+        }
+
         let mut use_additional = true;
+        let direct_bounces = direct_bounces(&path);
 
-        for bounce in &path {
+        for (index, bounce) in path.iter().enumerate() {
             use_additional = !bounce.dispersed && use_additional;
-            let additional_samples = if use_additional {
+            let samples = if use_additional {
                 &mut *additional_samples
             } else {
                 &mut []
             };
 
-            contribute(bounce, &mut main_sample, additional_samples, &mut exe);
+            contribute(bounce, &mut main_sample, samples, &mut exe);
+
+            if index + 1 == direct_bounces && aovs.has_lighting() {
+                direct_brightness.push(main_sample.0.brightness);
+                direct_brightness.extend(samples.iter().map(|(sample, _)| sample.brightness));
+            }
+        }
+
+        if aovs.has_lighting() {
+            let direct = direct_brightness.get(0).copied().unwrap_or(0.0);
+            aovs.record_lighting(position, &main_sample.0, direct);
         }
 
         film.expose(position, main_sample.0);
 
         if use_additional {
-            for (sample, _) in additional_samples.drain(..) {
+            for (index, (sample, _)) in additional_samples.drain(..).enumerate() {
+                if aovs.has_lighting() {
+                    let direct = direct_brightness.get(index + 1).copied().unwrap_or(0.0);
+                    aovs.record_lighting(position, &sample, direct);
+                }
+
                 film.expose(position, sample);
             }
         }
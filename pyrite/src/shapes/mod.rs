@@ -315,7 +315,9 @@ impl<'p> Shape<'p> {
         }
     }
 
-    pub fn transform(&mut self, transform: Matrix4<f32>) {
+    /// Transforms the shape with `transform`, and its normals with
+    /// `normal_transform`, which is the inverse transpose of its linear part.
+    pub fn transform(&mut self, transform: Matrix4<f32>, normal_transform: Matrix3<f32>) {
         match *self {
             Sphere {
                 ref mut position, ..
@@ -330,9 +332,9 @@ impl<'p> Shape<'p> {
                 ref mut edge2,
                 material: _,
             } => {
-                v1.normal = v1.normal.transform(transform);
-                v2.normal = v2.normal.transform(transform);
-                v3.normal = v3.normal.transform(transform);
+                v1.normal = v1.normal.transform(transform, normal_transform);
+                v2.normal = v2.normal.transform(transform, normal_transform);
+                v3.normal = v3.normal.transform(transform, normal_transform);
                 v1.position = transform.transform_point(v1.position);
                 v2.position = transform.transform_point(v2.position);
                 v3.position = transform.transform_point(v3.position);
@@ -569,14 +571,13 @@ impl Normal {
         self.from_space.conjugate() * vector
     }
 
-    pub fn transform(&self, transform: Matrix4<f32>) -> Self {
-        let vector = transform.transform_vector(self.vector).normalize();
-        let x = transform
-            .transform_vector(self.from_space(Vector3::unit_x()))
-            .normalize();
-        let y = transform
-            .transform_vector(self.from_space(Vector3::unit_y()))
-            .normalize();
+    /// The tangent follows `transform`, but is kept perpendicular to the
+    /// normal after a non-uniform scale.
+    pub fn transform(&self, transform: Matrix4<f32>, normal_transform: Matrix3<f32>) -> Self {
+        let vector = (normal_transform * self.vector).normalize();
+        let tangent = transform.transform_vector(self.from_space(Vector3::unit_x()));
+        let x = (tangent - vector * tangent.dot(vector)).normalize();
+        let y = vector.cross(x);
         let from_space = Matrix3::from_cols(x, y, vector).into();
 
         Normal { vector, from_space }
@@ -85,6 +85,14 @@ impl<T> Texture<T> {
         })
     }
 
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
     pub fn get_color(&self, position: Point2<f32>) -> T
     where
         T: Copy + Add<Output = T> + Sub<Output = T> + Mul<Output = T> + Mul<f32, Output = T>,
@@ -148,7 +156,7 @@ impl<T> Texture<T> {
     }
 
     #[inline(always)]
-    fn color_at(&self, x: usize, y: usize) -> T
+    pub fn color_at(&self, x: usize, y: usize) -> T
     where
         T: Copy,
     {
@@ -0,0 +1,199 @@
+use cgmath::{Matrix3, SquareMatrix, Vector3};
+use palette::{convert::FromColorUnclamped, LinSrgb, Xyz};
+
+use crate::{color_space::RgbSpace, project::ColorSpace};
+
+/// The reference camera settings, that give an exposure of 0 EV.
+const REFERENCE_ISO: f32 = 100.0;
+const REFERENCE_SHUTTER_SPEED: f32 = 1.0;
+const REFERENCE_F_NUMBER: f32 = 1.0;
+
+/// Turns the developed scene colors into colors for the output image, by
+/// applying the exposure and then the tone mapping operator. The colors are
+/// only clipped in the output color space, to keep wide gamut colors.
+pub(crate) struct ToneMapping {
+    exposure: f32,
+    operator: Operator,
+    output_space: Option<OutputSpace>,
+}
+
+impl ToneMapping {
+    pub fn from_project(
+        exposure: Option<f32>,
+        iso: Option<f32>,
+        shutter_speed: Option<f32>,
+        f_number: Option<f32>,
+        operator: Option<crate::project::ToneMapping>,
+        white_luminance: Option<f32>,
+        color_space: ColorSpace,
+    ) -> Self {
+        // The sensor gets more light with a higher ISO, a longer shutter
+        // time and a wider aperture.
+        let iso = iso.unwrap_or(REFERENCE_ISO) / REFERENCE_ISO;
+        let shutter_speed =
+            shutter_speed.unwrap_or(REFERENCE_SHUTTER_SPEED) / REFERENCE_SHUTTER_SPEED;
+        let f_number = f_number.unwrap_or(REFERENCE_F_NUMBER) / REFERENCE_F_NUMBER;
+        let exposure_value =
+            exposure.unwrap_or(0.0) + (iso * shutter_speed / (f_number * f_number)).log2();
+
+        ToneMapping {
+            exposure: exposure_value.exp2(),
+            operator: match operator {
+                None => Operator::Linear,
+                Some(crate::project::ToneMapping::Clip) => Operator::Clip,
+                Some(crate::project::ToneMapping::Reinhard) => Operator::Reinhard {
+                    white: white_luminance.unwrap_or(f32::INFINITY),
+                },
+                Some(crate::project::ToneMapping::Aces) => Operator::Aces,
+                Some(crate::project::ToneMapping::Agx) => Operator::Agx,
+            },
+            output_space: RgbSpace::from_color_space(color_space).map(OutputSpace::new),
+        }
+    }
+
+    /// The linear brightness factor from the exposure settings.
+    pub fn exposure(&self) -> f32 {
+        self.exposure
+    }
+
+    pub fn apply(&self, pixels: &mut [Xyz]) {
+        for pixel in pixels {
+            let color = *pixel * self.exposure;
+
+            *pixel = self.operator.apply(color, self.output_space.as_ref());
+        }
+    }
+}
+
+/// Converts the XYZ colors from the film to and from linear RGB in the
+/// output color space.
+struct OutputSpace {
+    to_rgb: Matrix3<f32>,
+    to_xyz: Matrix3<f32>,
+}
+
+impl OutputSpace {
+    fn new(space: RgbSpace) -> Self {
+        let to_rgb = space.film_to_rgb_matrix();
+
+        OutputSpace {
+            to_rgb,
+            to_xyz: to_rgb
+                .invert()
+                .expect("the film to RGB matrix should be invertible"),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Operator {
+    /// Keeps the values as they are. Integer formats are still clipped when
+    /// they are saved.
+    Linear,
+    Clip,
+    Reinhard {
+        white: f32,
+    },
+    Aces,
+    Agx,
+}
+
+impl Operator {
+    /// None of the operators clip the colors, except for `Clip`. That's left
+    /// to the output file, to not limit the colors to a smaller gamut.
+    fn apply(self, color: Xyz, output_space: Option<&OutputSpace>) -> Xyz {
+        match self {
+            Operator::Linear => color,
+            Operator::Clip => clip(color, output_space),
+            Operator::Reinhard { white } => reinhard(color, white),
+            // The fitted curves are made for linear sRGB values, which may
+            // be negative outside of its gamut.
+            Operator::Aces => Xyz::from_color_unclamped(aces(LinSrgb::from_color_unclamped(color))),
+            Operator::Agx => Xyz::from_color_unclamped(agx(LinSrgb::from_color_unclamped(color))),
+        }
+    }
+}
+
+/// Clamps the color to 0..1 in the output color space, or clamps each
+/// component if the output is XYZ.
+fn clip(color: Xyz, output_space: Option<&OutputSpace>) -> Xyz {
+    let color = Vector3::new(color.x, color.y, color.z);
+    let clamp = |color: Vector3<f32>| color.map(|value| value.clamp(0.0, 1.0));
+
+    let color = match output_space {
+        Some(space) => space.to_xyz * clamp(space.to_rgb * color),
+        None => clamp(color),
+    };
+
+    Xyz::new(color.x, color.y, color.z)
+}
+
+/// The extended Reinhard operator, applied to the luminance to preserve the
+/// hue. Luminances at or above `white` become white.
+fn reinhard(color: Xyz, white: f32) -> Xyz {
+    let luminance = color.y;
+    if luminance <= 0.0 {
+        return Xyz::new(0.0, 0.0, 0.0);
+    }
+
+    let mapped = luminance * (1.0 + luminance / (white * white)) / (1.0 + luminance);
+    color * (mapped.min(1.0) / luminance)
+}
+
+/// The ACES reference rendering and output transforms, as fitted by Stephen
+/// Hill.
+fn aces(color: LinSrgb) -> LinSrgb {
+    const INPUT: [[f32; 3]; 3] = [
+        [0.59719, 0.35458, 0.04823],
+        [0.07600, 0.90834, 0.01566],
+        [0.02840, 0.13383, 0.83777],
+    ];
+    const OUTPUT: [[f32; 3]; 3] = [
+        [1.60475, -0.53108, -0.07367],
+        [-0.10208, 1.10813, -0.00605],
+        [-0.00327, -0.07276, 1.07602],
+    ];
+
+    let color = multiply(&INPUT, color.into()).map(|value| {
+        (value * (value + 0.0245786) - 0.000090537)
+            / (value * (0.983729 * value + 0.432951) + 0.238081)
+    });
+
+    multiply(&OUTPUT, color).into()
+}
+
+/// A minimal AgX style operator, after Troy Sobotka's AgX and Benjamin
+/// Wrensch's polynomial fit of its default contrast curve.
+fn agx(color: LinSrgb) -> LinSrgb {
+    const INSET: [[f32; 3]; 3] = [
+        [0.84247905, 0.0784336, 0.079223745],
+        [0.042328242, 0.87846863, 0.07916613],
+        [0.042375654, 0.0784336, 0.879143],
+    ];
+    const OUTSET: [[f32; 3]; 3] = [
+        [1.196879, -0.09802088, -0.09902974],
+        [-0.052896854, 1.1519032, -0.098961174],
+        [-0.052971635, -0.09804345, 1.1510737],
+    ];
+    const MIN_EV: f32 = -12.47393;
+    const MAX_EV: f32 = 4.026069;
+
+    let color = multiply(&INSET, color.into()).map(|value| {
+        let x = (value.max(1e-10).log2().clamp(MIN_EV, MAX_EV) - MIN_EV) / (MAX_EV - MIN_EV);
+        let x2 = x * x;
+        let x4 = x2 * x2;
+
+        15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x
+            - 0.00232
+    });
+
+    // The curve produces display encoded values, so they are linearized
+    // again, to be encoded by the output file.
+    multiply(&OUTSET, color)
+        .map(|value| value.max(0.0).powf(2.2))
+        .into()
+}
+
+fn multiply(matrix: &[[f32; 3]; 3], vector: [f32; 3]) -> [f32; 3] {
+    matrix.map(|row| row[0] * vector[0] + row[1] * vector[1] + row[2] * vector[2])
+}
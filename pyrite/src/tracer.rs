@@ -17,6 +17,8 @@ use crate::{
 use std::{borrow::Cow, cell::Cell, convert::TryFrom};
 
 pub type Brdf = fn(ray_in: Vector3<f32>, ray_out: Vector3<f32>, normal: Vector3<f32>) -> f32;
+/// The probability density of scattering towards `ray_out`, per solid angle.
+pub type Pdf = fn(ray_in: Vector3<f32>, ray_out: Vector3<f32>, normal: Vector3<f32>) -> f32;
 pub(crate) type LightProgram<'p> = ProgramFor<'p, RenderContext, f32>;
 
 pub trait ParametricValue<From, To>: Send + Sync {
@@ -164,17 +166,25 @@ pub(crate) struct Bounce<'a> {
     pub texture: Point2<f32>,
     pub probability: f32,
     pub direct_light: Vec<DirectLight<'a>>,
+    /// The object that was hit, or 0 for the sky and the start of light paths.
+    pub object_id: u32,
+    /// Set if the path left the scene and found the sky or a directional
+    /// light.
+    pub distant: bool,
+    /// When the path was traced, while the camera shutter was open.
+    pub time: f32,
 }
 
 pub enum BounceType {
-    Diffuse(Brdf, Vector3<f32>),
+    /// Scattered with a BSDF, its sampling density, and the outgoing direction.
+    Diffuse(Brdf, Pdf, Vector3<f32>),
     Specular,
     Emission,
 }
 
 impl BounceType {
     pub fn brdf(&self, incident: Vector3<f32>, normal: Vector3<f32>) -> f32 {
-        if let BounceType::Diffuse(brdf, out) = *self {
+        if let BounceType::Diffuse(brdf, _, out) = *self {
             brdf(incident, normal, out)
         } else {
             1.0
@@ -191,6 +201,8 @@ impl BounceType {
 }
 
 pub(crate) struct DirectLight<'a> {
+    /// Set if the light comes from a directional light.
+    pub directional: bool,
     pub dispersed: bool,
     pub color: LightProgram<'a>,
     pub incident: Vector3<f32>,
@@ -199,6 +211,16 @@ pub(crate) struct DirectLight<'a> {
     pub probability: f32,
 }
 
+/// The light sampling at the previous bounce, for weighting the emission
+/// from lamps that the path hits after it.
+struct LightSampling {
+    position: Point3<f32>,
+    /// The probability density of the path's direction, from the BSDF.
+    bsdf_density: f32,
+    /// The probability of picking each lamp, times the number of samples.
+    lamp_density: f32,
+}
+
 #[derive(Clone)]
 pub struct Light {
     wavelength: f32,
@@ -209,16 +231,17 @@ pub(crate) fn trace<'w, R: Rng>(
     path: &mut Vec<Bounce<'w>>,
     rng: &mut R,
     mut ray: Ray3<f32>,
+    time: f32,
     wavelength: f32,
     world: &'w World,
     bounces: u32,
     light_samples: usize,
     exe: &mut ExecutionContext<'w>,
 ) {
-    let mut sample_light = true;
+    let mut light_sampling: Option<LightSampling> = None;
     let mut light_sample_events = 0;
 
-    for _ in 0..bounces {
+    for bounce_index in 0..bounces {
         match world.intersect(ray) {
             Some(intersection) => {
                 let material = intersection.surface_point.get_material();
@@ -253,13 +276,28 @@ pub(crate) fn trace<'w, R: Rng>(
                         probability,
                         dispersed,
                         brdf,
+                        pdf,
                     } => {
-                        let direct_light = if light_sample_events < 2 {
-                            sample_light = brdf.is_none() || light_samples == 0;
-
-                            if let Some(brdf) = brdf {
+                        // The lamps can also be found by following the path,
+                        // unless this is the last bounce.
+                        let is_last_bounce = bounce_index + 1 == bounces;
+
+                        light_sampling = None;
+                        let direct_light = match (brdf, pdf) {
+                            (Some(brdf), Some(pdf))
+                                if light_sample_events < 2 && light_samples > 0 =>
+                            {
                                 light_sample_events += 1;
 
+                                if !is_last_bounce {
+                                    light_sampling = Some(LightSampling {
+                                        position,
+                                        bsdf_density: pdf(ray.direction, out_direction, normal),
+                                        lamp_density: light_samples as f32
+                                            / world.lights.len() as f32,
+                                    });
+                                }
+
                                 trace_direct(
                                     rng,
                                     light_samples,
@@ -269,20 +307,19 @@ pub(crate) fn trace<'w, R: Rng>(
                                     normal,
                                     world,
                                     brdf,
+                                    pdf,
+                                    !is_last_bounce,
                                     exe,
                                 )
-                            } else {
-                                vec![]
                             }
-                        } else {
-                            sample_light = true;
-                            vec![]
+                            _ => vec![],
                         };
 
-                        let bounce_type = if let Some(brdf) = brdf {
-                            BounceType::Diffuse(brdf, out_direction)
-                        } else {
-                            BounceType::Specular
+                        let bounce_type = match (brdf, pdf) {
+                            (Some(brdf), Some(pdf)) => {
+                                BounceType::Diffuse(brdf, pdf, out_direction)
+                            }
+                            _ => BounceType::Specular,
                         };
 
                         let bounce = Bounce {
@@ -295,32 +332,55 @@ pub(crate) fn trace<'w, R: Rng>(
                             texture: surface_data.texture,
                             probability: probability * component_probability,
                             direct_light,
+                            object_id: material.object_id(),
+                            distant: false,
+                            time,
                         };
 
                         ray = Ray3::new(position, out_direction);
                         path.push(bounce);
                     }
                     Scattering::Emitted => {
-                        if sample_light {
-                            path.push(Bounce {
-                                ty: BounceType::Emission,
-                                dispersed: normal_dispersed,
-                                color: component.bsdf.color,
-                                incident: ray.direction,
-                                position,
-                                normal,
-                                texture: surface_data.texture,
-                                probability: component_probability,
-                                direct_light: vec![],
-                            });
-                        }
+                        // The light may also have been sampled directly at
+                        // the previous bounce, so the two ways of finding it
+                        // are weighted against each other.
+                        let mis_weight = light_sampling
+                            .as_ref()
+                            .and_then(|sampling| {
+                                let weight = lamp::emitter_weight(
+                                    &intersection.surface_point,
+                                    sampling.position,
+                                )?;
+                                Some(power_heuristic(
+                                    sampling.bsdf_density,
+                                    sampling.lamp_density / weight,
+                                ))
+                            })
+                            .unwrap_or(1.0);
+
+                        path.push(Bounce {
+                            ty: BounceType::Emission,
+                            dispersed: normal_dispersed,
+                            color: component.bsdf.color,
+                            incident: ray.direction,
+                            position,
+                            normal,
+                            texture: surface_data.texture,
+                            probability: component_probability * mis_weight,
+                            direct_light: vec![],
+                            object_id: material.object_id(),
+                            distant: false,
+                            time,
+                        });
 
                         break;
                     }
                 }
             }
             None => {
-                let directional = if sample_light {
+                // Directional lights are either sampled directly or found
+                // by following the path, but not both.
+                let directional = if light_sampling.is_none() {
                     trace_directional(ray.direction, world)
                 } else {
                     None
@@ -336,6 +396,9 @@ pub(crate) fn trace<'w, R: Rng>(
                     texture: Point2::origin(),
                     probability: 1.0,
                     direct_light: vec![],
+                    object_id: 0,
+                    distant: true,
+                    time,
                 });
 
                 break;
@@ -353,6 +416,8 @@ fn trace_direct<'w, R: Rng>(
     normal: Vector3<f32>,
     world: &'w World,
     brdf: Brdf,
+    pdf: Pdf,
+    weighted: bool,
     exe: &mut ExecutionContext<'w>,
 ) -> Vec<DirectLight<'w>> {
     if let Some((lamp, probability)) = world.pick_lamp(rng) {
@@ -362,7 +427,7 @@ fn trace_direct<'w, R: Rng>(
             -normal
         };
 
-        let probability = 1.0 / (samples as f32 * 2.0 * std::f32::consts::PI * probability);
+        let lamp_density = samples as f32 * probability;
 
         (0..samples)
             .filter_map(|_| {
@@ -389,6 +454,16 @@ fn trace_direct<'w, R: Rng>(
                     };
 
                     if !blocked {
+                        // Lamps with a surface can also be found by
+                        // following the path, when it's `weighted`.
+                        let bsdf_density = pdf(ray_in, ray_out.direction, normal);
+                        let mis_weight = match surface {
+                            lamp::Surface::Physical { .. } if weighted => {
+                                power_heuristic(lamp_density / weight, bsdf_density)
+                            }
+                            _ => 1.0,
+                        };
+
                         let (color, material_probability, dispersed, target_normal, texture) =
                             match surface {
                                 lamp::Surface::Physical {
@@ -420,9 +495,12 @@ fn trace_direct<'w, R: Rng>(
                                     (color, 1.0, false, target_normal, Point2::origin())
                                 }
                             };
-                        let scale = weight * probability * brdf(ray_in, normal, ray_out.direction);
+                        let scale = weight * bsdf_density * brdf(ray_in, normal, ray_out.direction)
+                            / lamp_density
+                            * mis_weight;
 
                         return Some(DirectLight {
+                            directional: matches!(lamp, Lamp::Directional { .. }),
                             dispersed,
                             color,
                             incident: ray_out.direction,
@@ -441,7 +519,23 @@ fn trace_direct<'w, R: Rng>(
     }
 }
 
-fn trace_directional<'w>(ray: Vector3<f32>, world: &'w World) -> Option<LightProgram<'w>> {
+/// Weights a sample by the power heuristic, for multiple importance
+/// sampling. The densities include the number of samples for each strategy.
+fn power_heuristic(density: f32, other_density: f32) -> f32 {
+    let density = density * density;
+    let sum = density + other_density * other_density;
+
+    if sum > 0.0 {
+        density / sum
+    } else {
+        0.0
+    }
+}
+
+pub(crate) fn trace_directional<'w>(
+    ray: Vector3<f32>,
+    world: &'w World,
+) -> Option<LightProgram<'w>> {
     for light in &world.lights {
         if let &Lamp::Directional {
             direction,
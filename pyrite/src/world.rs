@@ -5,7 +5,7 @@ use rand::Rng;
 use obj;
 
 use cgmath::{
-    EuclideanSpace, InnerSpace, Matrix3, Matrix4, Point2, SquareMatrix, Vector2, Vector3,
+    EuclideanSpace, InnerSpace, Matrix, Matrix3, Matrix4, Point2, SquareMatrix, Vector2, Vector3,
 };
 use collision::Ray3;
 
@@ -49,6 +49,12 @@ impl<'p> World<'p> {
         let mut planes = Vec::new();
         let mut lights = Vec::new();
 
+        let mut object_count = 0;
+        let mut next_object_id = || {
+            object_count += 1;
+            object_count
+        };
+
         for (i, object) in project.objects.into_iter().enumerate() {
             match object {
                 WorldObject::Sphere {
@@ -57,7 +63,13 @@ impl<'p> World<'p> {
                     texture_scale,
                     material,
                 } => {
-                    let material = Material::from_project(material, programs, nodes, allocator)?;
+                    let material = Material::from_project(
+                        material,
+                        next_object_id(),
+                        programs,
+                        nodes,
+                        allocator,
+                    )?;
                     let emissive = material.is_emissive();
                     let eval_context = EvalContext { nodes };
                     let texture_scale: Option<_> = texture_scale.evaluate(eval_context)?;
@@ -80,7 +92,13 @@ impl<'p> World<'p> {
                     texture_scale,
                     material,
                 } => {
-                    let material = Material::from_project(material, programs, nodes, allocator)?;
+                    let material = Material::from_project(
+                        material,
+                        next_object_id(),
+                        programs,
+                        nodes,
+                        allocator,
+                    )?;
                     let emissive = material.is_emissive();
 
                     let eval_context = EvalContext { nodes };
@@ -115,7 +133,13 @@ impl<'p> World<'p> {
                     bounds,
                     material,
                 } => {
-                    let material = Material::from_project(material, programs, nodes, allocator)?;
+                    let material = Material::from_project(
+                        material,
+                        next_object_id(),
+                        programs,
+                        nodes,
+                        allocator,
+                    )?;
                     let emissive = material.is_emissive();
 
                     let eval_context = EvalContext { nodes };
@@ -194,8 +218,13 @@ impl<'p> World<'p> {
                         let (object_material, emissive) = match mesh_materials.remove(&object.name)
                         {
                             Some(material) => {
-                                let material =
-                                    Material::from_project(material, programs, nodes, allocator)?;
+                                let material = Material::from_project(
+                                    material,
+                                    next_object_id(),
+                                    programs,
+                                    nodes,
+                                    allocator,
+                                )?;
                                 let emissive = material.is_emissive();
                                 (material, emissive)
                             }
@@ -212,6 +241,19 @@ impl<'p> World<'p> {
                         let transform =
                             transform.evaluate_or_else(eval_context, || Matrix4::identity())?;
                         let scale = scale.evaluate_or(eval_context, 1.0)?;
+                        let normal_transform = Matrix3::from_cols(
+                            transform.x.truncate(),
+                            transform.y.truncate(),
+                            transform.z.truncate(),
+                        )
+                        .invert()
+                        .ok_or_else(|| {
+                            format!(
+                                "objects[{}]: the transform of '{}' can't be inverted",
+                                i, object.name
+                            )
+                        })?
+                        .transpose();
 
                         for group in &object.groups {
                             for shape in &group.polys {
@@ -220,7 +262,7 @@ impl<'p> World<'p> {
                                         let mut triangle =
                                             make_triangle(obj, x, y, z, object_material.clone());
                                         triangle.scale(scale);
-                                        triangle.transform(transform);
+                                        triangle.transform(transform, normal_transform);
                                         let triangle = allocator.alloc(triangle);
                                         if emissive {
                                             lights.push(Lamp::Shape(triangle));